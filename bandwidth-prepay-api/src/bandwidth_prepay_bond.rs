@@ -0,0 +1,88 @@
+use crate::bandwidth_prepay_state::BandwidthPrepayError;
+use crate::id;
+use bincode::{deserialize, serialize_into};
+use serde_derive::{Deserialize, Serialize};
+use solana_sdk::hash::hashv;
+use solana_sdk::pubkey::Pubkey;
+
+/// Tag stored in the first byte of every bond account, distinct from both
+/// contract state versions and `REGISTRATION_TAG`
+pub const BOND_TAG: u8 = 0x81;
+
+/// Space allocated for every bond account
+const BOND_SIZE: usize = 64;
+
+/// Lamports a gatekeeper locks up as collateral against overcharging. The
+/// bond account's balance is the bond; a successful `Dispute` slashes it to
+/// repay the initiator
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct GatekeeperBond {
+    pub gatekeeper_id: Pubkey,
+    /// Lamports paid out of the bond by disputes so far
+    pub total_slashed: u64,
+}
+
+impl GatekeeperBond {
+    pub fn deserialize(input: &[u8]) -> Result<Self, BandwidthPrepayError> {
+        match input.first() {
+            Some(&BOND_TAG) => deserialize(&input[1..])
+                .map_err(|_| BandwidthPrepayError::UserdataDeserializeFailure),
+            _ => Err(BandwidthPrepayError::UserdataDeserializeFailure),
+        }
+    }
+
+    pub fn serialize(&self, output: &mut [u8]) -> Result<(), BandwidthPrepayError> {
+        if output.is_empty() {
+            Err(BandwidthPrepayError::UserdataTooSmall)?
+        }
+        output[0] = BOND_TAG;
+        serialize_into(&mut output[1..], self).map_err(|_| BandwidthPrepayError::UserdataTooSmall)
+    }
+
+    pub fn max_size() -> usize {
+        BOND_SIZE
+    }
+}
+
+/// The one address a gatekeeper's bond can live at
+pub fn bond_address(gatekeeper_id: &Pubkey) -> Pubkey {
+    let hash = hashv(&[b"bond", gatekeeper_id.as_ref(), id().as_ref()]);
+    Pubkey::new(hash.as_ref())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bandwidth_prepay_registry::GatekeeperRegistration;
+    use solana_sdk::account::Account;
+
+    #[test]
+    fn test_serializer() {
+        let mut a = Account::new(0, GatekeeperBond::max_size(), &id());
+        let b = GatekeeperBond {
+            gatekeeper_id: Pubkey::new_rand(),
+            total_slashed: u64::max_value(),
+        };
+        b.serialize(&mut a.data).unwrap();
+        assert_eq!(a.data[0], BOND_TAG);
+        assert_eq!(GatekeeperBond::deserialize(&a.data), Ok(b.clone()));
+        assert_eq!(
+            GatekeeperRegistration::deserialize(&a.data),
+            Err(BandwidthPrepayError::UserdataDeserializeFailure)
+        );
+        assert_eq!(
+            b.serialize(&mut a.data[..8]),
+            Err(BandwidthPrepayError::UserdataTooSmall)
+        );
+    }
+
+    #[test]
+    fn test_bond_address() {
+        let gatekeeper_id = Pubkey::new_rand();
+        assert_eq!(bond_address(&gatekeeper_id), bond_address(&gatekeeper_id));
+        assert_ne!(
+            bond_address(&gatekeeper_id),
+            bond_address(&Pubkey::new_rand())
+        );
+    }
+}
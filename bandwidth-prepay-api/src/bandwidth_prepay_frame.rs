@@ -0,0 +1,141 @@
+use crate::bandwidth_prepay_receipt::UsageReceipt;
+use bincode::{deserialize, serialize};
+use solana_sdk::signature::Signature;
+use std::io::{self, ErrorKind, Read};
+
+/// Largest payload a frame may carry
+pub const MAX_FRAME_PAYLOAD: usize = 64 * 1024;
+
+/// A kind byte, then the payload's length as a little-endian u32
+const HEADER_LEN: usize = 5;
+const DATA: u8 = 0;
+const RECEIPT: u8 = 1;
+
+/// What a framed data connection carries. The initiator hands its receipts to
+/// the gatekeeper in between its traffic, and the gatekeeper frames the
+/// destination's replies as `Data`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Frame {
+    Data(Vec<u8>),
+    Receipt(UsageReceipt, Signature),
+}
+
+impl Frame {
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let (kind, payload) = match self {
+            Frame::Data(data) => (DATA, data.clone()),
+            Frame::Receipt(receipt, signature) => (
+                RECEIPT,
+                serialize(&(receipt, signature)).map_err(invalid_data)?,
+            ),
+        };
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.push(kind);
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        Ok(bytes)
+    }
+
+    /// Take the first frame off the front of `bytes`, once all of it has
+    /// arrived. Bytes that don't make a valid frame are left in place
+    pub fn decode(bytes: &mut Vec<u8>) -> io::Result<Option<Self>> {
+        if bytes.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let len = payload_len(&bytes[..HEADER_LEN])?;
+        if bytes.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+        let frame = Self::from_payload(bytes[0], bytes[HEADER_LEN..HEADER_LEN + len].to_vec())?;
+        bytes.drain(..HEADER_LEN + len);
+        Ok(Some(frame))
+    }
+
+    /// Read the next frame, waiting for all of it
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let mut payload = vec![0; payload_len(&header)?];
+        reader.read_exact(&mut payload)?;
+        Self::from_payload(header[0], payload)
+    }
+
+    fn from_payload(kind: u8, payload: Vec<u8>) -> io::Result<Self> {
+        match kind {
+            DATA => Ok(Frame::Data(payload)),
+            RECEIPT => {
+                let (receipt, signature) = deserialize(&payload).map_err(invalid_data)?;
+                Ok(Frame::Receipt(receipt, signature))
+            }
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown frame kind {}", kind),
+            )),
+        }
+    }
+}
+
+fn invalid_data(err: bincode::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err.to_string())
+}
+
+fn payload_len(header: &[u8]) -> io::Result<usize> {
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[1..HEADER_LEN]);
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_PAYLOAD {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {} bytes is too long", len),
+        ));
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use solana_sdk::pubkey::Pubkey;
+    use solana_sdk::signature::{Keypair, KeypairUtil};
+
+    #[test]
+    fn test_decode() {
+        let receipt = UsageReceipt::new(&Pubkey::new_rand(), 1, 2, 2048, 2);
+        let signature = receipt.sign(&Keypair::new());
+        let data = Frame::Data(vec![7; 100]);
+        let receipt = Frame::Receipt(receipt, signature);
+
+        let mut bytes = data.to_bytes().unwrap();
+        bytes.extend(receipt.to_bytes().unwrap());
+        let mut partial = bytes[..50].to_vec();
+        assert_eq!(Frame::decode(&mut partial).unwrap(), None);
+        assert_eq!(partial.len(), 50);
+
+        assert_eq!(Frame::decode(&mut bytes).unwrap(), Some(data.clone()));
+        assert_eq!(Frame::decode(&mut bytes).unwrap(), Some(receipt.clone()));
+        assert_eq!(Frame::decode(&mut bytes).unwrap(), None);
+        assert!(bytes.is_empty());
+
+        let mut bytes = data.to_bytes().unwrap();
+        bytes.extend(receipt.to_bytes().unwrap());
+        let mut reader = &bytes[..];
+        assert_eq!(Frame::read_from(&mut reader).unwrap(), data);
+        assert_eq!(Frame::read_from(&mut reader).unwrap(), receipt);
+        assert!(Frame::read_from(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let mut bytes = Frame::Data(vec![]).to_bytes().unwrap();
+        bytes[0] = 2;
+        assert!(Frame::decode(&mut bytes).is_err());
+
+        let mut bytes = Frame::Data(vec![]).to_bytes().unwrap();
+        bytes[1..HEADER_LEN].copy_from_slice(&(MAX_FRAME_PAYLOAD as u32 + 1).to_le_bytes());
+        assert!(Frame::decode(&mut bytes).is_err());
+
+        let mut bytes = vec![RECEIPT, 1, 0, 0, 0, 0];
+        assert!(Frame::decode(&mut bytes).is_err());
+        assert_eq!(bytes, vec![RECEIPT, 1, 0, 0, 0, 0]);
+    }
+}
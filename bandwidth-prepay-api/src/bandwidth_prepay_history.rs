@@ -0,0 +1,90 @@
+use crate::bandwidth_prepay_state::{BandwidthPrepayError, BandwidthPrepayState};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+
+/// Operations kept in a contract's history, oldest first
+pub const HISTORY_LEN: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EventKind {
+    /// Lamports put into the contract by `InitializeAccount` or `TopUp`
+    Deposit,
+    /// Lamports charged by `Spend`, `SpendBatch` or `EndSession`, including
+    /// the gatekeeper fee
+    Spend,
+    Settle,
+    /// Lamports returned to the initiator by `Refund` or `Close`
+    Refund,
+    Reclaim,
+    /// Lamports repaid to the initiator out of the gatekeeper's bond
+    Slash,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            EventKind::Deposit => "deposit",
+            EventKind::Spend => "spend",
+            EventKind::Settle => "settle",
+            EventKind::Refund => "refund",
+            EventKind::Reclaim => "reclaim",
+            EventKind::Slash => "slash",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ContractEvent {
+    pub kind: EventKind,
+    pub amount: u64,
+    /// Slot the operation was processed in, when the instruction carried the
+    /// clock sysvar
+    pub slot: Option<u64>,
+}
+
+impl fmt::Display for ContractEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.slot {
+            Some(slot) => write!(f, "slot {:>10}: ", slot)?,
+            None => write!(f, "slot {:>10}: ", "?")?,
+        }
+        write!(f, "{:<8} {} lamports", self.kind, self.amount)
+    }
+}
+
+/// Decode the recent operations recorded in a contract account's data
+pub fn contract_history(data: &[u8]) -> Result<Vec<ContractEvent>, BandwidthPrepayError> {
+    BandwidthPrepayState::deserialize(data).map(|state| state.history)
+}
+
+/// One line per recent operation on the contract, oldest first
+pub fn statement(data: &[u8]) -> Result<String, BandwidthPrepayError> {
+    let lines: Vec<_> = contract_history(data)?
+        .iter()
+        .map(ContractEvent::to_string)
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::id;
+    use solana_sdk::account::Account;
+
+    #[test]
+    fn test_statement() {
+        let mut a = Account::new(0, BandwidthPrepayState::max_size(), &id());
+        let mut state = BandwidthPrepayState::default();
+        state.record(EventKind::Deposit, 500, Some(3));
+        state.record(EventKind::Spend, 100, None);
+        state.serialize(&mut a.data).unwrap();
+
+        assert_eq!(contract_history(&a.data).unwrap(), state.history);
+        assert_eq!(
+            statement(&a.data).unwrap(),
+            "slot          3: deposit  500 lamports\nslot          ?: spend    100 lamports"
+        );
+    }
+}
@@ -1,15 +1,153 @@
+use crate::bandwidth_prepay_bond::{bond_address, GatekeeperBond};
+use crate::bandwidth_prepay_receipt::UsageReceipt;
+use crate::bandwidth_prepay_registry::{
+    registration_address, GatekeeperInfo, GatekeeperRegistration,
+};
 use crate::bandwidth_prepay_state::BandwidthPrepayState;
 use crate::id;
 use serde_derive::{Deserialize, Serialize};
+use solana_sdk::hash::{hashv, Hash};
 use solana_sdk::instruction::{AccountMeta, Instruction};
 use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
 use solana_sdk::system_instruction;
+use solana_sdk::sysvar::clock;
+
+/// Price charged when the initiator doesn't negotiate one, matching the
+/// original hard-coded gatekeeper pricing of one lamport per KiB
+pub const DEFAULT_LAMPORTS_PER_KIB: u64 = 1;
+
+/// Terms agreed to by the initiator when a contract is initialized
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ContractTerms {
+    pub lamports_per_kib: u64,
+    /// Bytes the contract may carry over all of its sessions
+    pub max_bytes: Option<u64>,
+    /// Slot from which the initiator may reclaim the remaining balance.
+    /// `u64::max_value()` never expires
+    pub expiry_slot: u64,
+    /// Accounts sharing each payment, with their cut in basis points. The
+    /// shares must add up to `TOTAL_BASIS_POINTS`. Empty pays the provider
+    /// everything
+    pub recipients: Vec<(Pubkey, u16)>,
+    /// Lamports paid to the gatekeeper on top of every `Spend`, covering the
+    /// transaction fees it pays to submit them. Never more than
+    /// `MAX_FEE_BASIS_POINTS` of the spend it comes with
+    pub gatekeeper_fee: u64,
+    /// Whether handing the contract to another gatekeeper also needs the
+    /// initiator's signature
+    pub rotation_requires_initiator: bool,
+    /// Nonce the contract address was derived from with `contract_address`.
+    /// `None` for contracts at an arbitrary address
+    pub nonce: Option<u64>,
+    /// Value every receipt for the contract must name. Contracts at a derived
+    /// address should pick it at random: the address is reused once a drained
+    /// contract's account is removed, and a fresh instance keeps receipts for
+    /// the old contract from being settled against the new one
+    pub instance: u64,
+    /// Whether the gatekeeper, and any it hands the contract to, must have
+    /// published a registration; see `register_gatekeeper`
+    pub require_registered_gatekeeper: bool,
+    /// `destination_hash`es of the only destinations the gatekeeper may
+    /// connect to, at most `MAX_DESTINATIONS`. Empty allows any destination
+    pub allowed_destinations: Vec<Hash>,
+    /// Lamports the gatekeeper may charge for each slot a session lasts, on
+    /// top of `lamports_per_kib`. A session's clock runs from its first
+    /// charge to its `EndSession`, so no slots count between sessions
+    pub lamports_per_slot: u64,
+}
+
+impl Default for ContractTerms {
+    fn default() -> Self {
+        Self {
+            lamports_per_kib: DEFAULT_LAMPORTS_PER_KIB,
+            max_bytes: None,
+            expiry_slot: u64::max_value(),
+            recipients: vec![],
+            gatekeeper_fee: 0,
+            rotation_requires_initiator: false,
+            nonce: None,
+            instance: 0,
+            require_registered_gatekeeper: false,
+            allowed_destinations: vec![],
+            lamports_per_slot: 0,
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub enum BandwidthPrepayInstruction {
-    InitializeAccount,
-    Spend(u64),
+    InitializeAccount(ContractTerms),
+    /// Pay `amount` lamports to the contract's payees, plus the agreed fee to
+    /// the gatekeeper; `total_bytes` is the cumulative number of bytes the
+    /// gatekeeper has forwarded in the current session
+    Spend {
+        amount: u64,
+        total_bytes: u64,
+    },
     Refund,
+    /// Pay the contract's payees up to the lamports acknowledged by an
+    /// initiator-signed usage receipt. A receipt for no more than was already
+    /// spent is still recorded, backing those spends against a `Dispute`
+    Settle(UsageReceipt, Signature),
+    /// Return the remaining balance to the initiator once the contract expires
+    Reclaim,
+    /// Record a deposit of `lamports` into an open contract by its initiator
+    /// or its gatekeeper. Always preceded by the system transfer that moves
+    /// the lamports, which must have raised the balance by at least that
+    /// much; see `top_up`
+    TopUp(u64),
+    /// Refund the remaining balance to the initiator and mark the contract
+    /// closed. A closed contract rejects every further instruction
+    Close,
+    /// Move a contract written by an older program into a new account of
+    /// `BandwidthPrepayState::max_size()` bytes, owned by this program, that
+    /// the caller has created. The state is rewritten in the current layout
+    /// and the old account's lamports follow it. Signed by either the
+    /// initiator or the gatekeeper, who address the contract at the new
+    /// account from then on
+    Migrate,
+    /// Hand the contract to another gatekeeper. Signed by the current
+    /// gatekeeper, and by the initiator if the terms require it. A contract
+    /// requiring a registered gatekeeper also needs the new one's
+    /// registration. The current session must have been ended with
+    /// `EndSession`, which charges what the outgoing gatekeeper is owed. The
+    /// outgoing gatekeeper's bond stays answerable for the sessions it
+    /// served, so a contract handed over before can't be handed over again
+    /// until the sessions of the gatekeeper before can no longer be disputed
+    ChangeGatekeeper,
+    /// Pay the last `final_spend` lamports of a session the way `Spend` does,
+    /// then start a new session, keeping the remaining balance in the
+    /// contract. A `final_spend` of zero pays no fee. Needs the clock, which
+    /// starts the ended session's dispute window, and fails while the
+    /// contract already keeps `DISPUTABLE_SESSIONS` sessions open to disputes
+    EndSession {
+        final_spend: u64,
+        total_bytes: u64,
+    },
+    /// `Spend` from many contracts paying the same single provider. Each entry
+    /// is the index of a contract account followed by the `amount` and
+    /// `total_bytes` of its spend; see `spend_batch`
+    SpendBatch(Vec<(u8, u64, u64)>),
+    /// Publish or update the signing gatekeeper's registration, stored at
+    /// `registration_address(gatekeeper_id)`
+    RegisterGatekeeper(GatekeeperInfo),
+    /// Remove the signing gatekeeper's registration, returning its lamports
+    /// to the gatekeeper
+    DeregisterGatekeeper,
+    /// Record lamports added to the signing gatekeeper's bond, stored at
+    /// `bond_address(gatekeeper_id)`. Always preceded by the instruction that
+    /// moves the lamports; see `create_bond` and `add_to_bond`. A bond is
+    /// never refunded: no instruction takes lamports out of it other than
+    /// `Dispute`, as the program can't tell when none of the gatekeeper's
+    /// sessions can be disputed any more
+    PostBond,
+    /// Slash the bond of the gatekeeper that served an ended session by what
+    /// it charged beyond what the initiator acknowledged, repaying the
+    /// initiator. The initiator's own receipt for the session is compared
+    /// with the highest one the gatekeeper settled. Signed by the initiator
+    /// within `DISPUTE_WINDOW_SLOTS` of the session's end
+    Dispute(UsageReceipt, Signature),
 }
 
 pub fn initialize(
@@ -18,47 +156,146 @@ pub fn initialize(
     gatekeeper_id: &Pubkey,
     provider_id: &Pubkey,
     lamports: u64,
+    terms: &ContractTerms,
 ) -> Vec<Instruction> {
     let space = BandwidthPrepayState::max_size() as u64;
     vec![
         system_instruction::create_account(&initiator_id, contract_id, lamports, space, &id()),
-        initialize_account(initiator_id, contract_id, gatekeeper_id, provider_id),
+        initialize_account(initiator_id, contract_id, gatekeeper_id, provider_id, terms),
     ]
 }
 
+/// Deterministic address for the `nonce`th contract between the same three
+/// parties, letting an initiator find its contracts again without keeping
+/// their keys
+pub fn contract_address(
+    initiator_id: &Pubkey,
+    gatekeeper_id: &Pubkey,
+    provider_id: &Pubkey,
+    nonce: u64,
+) -> Pubkey {
+    let hash = hashv(&[
+        initiator_id.as_ref(),
+        gatekeeper_id.as_ref(),
+        provider_id.as_ref(),
+        &nonce.to_le_bytes(),
+        id().as_ref(),
+    ]);
+    Pubkey::new(hash.as_ref())
+}
+
+/// Like `initialize`, but creates the contract at
+/// `contract_address(initiator_id, gatekeeper_id, provider_id, nonce)`,
+/// which is returned along with the instructions
+pub fn initialize_derived(
+    initiator_id: &Pubkey,
+    gatekeeper_id: &Pubkey,
+    provider_id: &Pubkey,
+    nonce: u64,
+    lamports: u64,
+    terms: &ContractTerms,
+) -> (Pubkey, Vec<Instruction>) {
+    let contract_id = contract_address(initiator_id, gatekeeper_id, provider_id, nonce);
+    let terms = ContractTerms {
+        nonce: Some(nonce),
+        ..terms.clone()
+    };
+    let instructions = initialize(
+        initiator_id,
+        &contract_id,
+        gatekeeper_id,
+        provider_id,
+        lamports,
+        &terms,
+    );
+    (contract_id, instructions)
+}
+
 fn initialize_account(
     initiator_id: &Pubkey,
     contract_id: &Pubkey,
     gatekeeper_id: &Pubkey,
     provider_id: &Pubkey,
+    terms: &ContractTerms,
 ) -> Instruction {
-    let account_metas = vec![
+    let mut account_metas = vec![
         AccountMeta::new(*initiator_id, true),
         AccountMeta::new(*contract_id, false),
         AccountMeta::new(*gatekeeper_id, false),
         AccountMeta::new(*provider_id, false),
     ];
+    if terms.require_registered_gatekeeper {
+        account_metas.push(AccountMeta::new(registration_address(gatekeeper_id), false));
+    }
+    account_metas.push(AccountMeta::new_credit_only(clock::id(), false));
     Instruction::new(
         id(),
-        &BandwidthPrepayInstruction::InitializeAccount,
+        &BandwidthPrepayInstruction::InitializeAccount(terms.clone()),
         account_metas,
     )
 }
 
+fn payment_account_metas(
+    gatekeeper_id: &Pubkey,
+    contract_id: &Pubkey,
+    payees: &[Pubkey],
+) -> Vec<AccountMeta> {
+    let mut account_metas = vec![
+        AccountMeta::new(*gatekeeper_id, true),
+        AccountMeta::new(*contract_id, false),
+    ];
+    account_metas.extend(payees.iter().map(|payee| AccountMeta::new(*payee, false)));
+    account_metas.push(AccountMeta::new_credit_only(clock::id(), false));
+    account_metas
+}
+
+/// `payees` must be the contract's `BandwidthPrepayState::payees()`, in order
 pub fn spend(
     gatekeeper_id: &Pubkey,
     contract_id: &Pubkey,
-    provider_id: &Pubkey,
+    payees: &[Pubkey],
     amount: u64,
+    total_bytes: u64,
 ) -> Instruction {
-    let account_metas = vec![
+    let account_metas = payment_account_metas(gatekeeper_id, contract_id, payees);
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::Spend {
+            amount,
+            total_bytes,
+        },
+        account_metas,
+    )
+}
+
+/// Charge every `(contract_id, amount, total_bytes)` in `spends` in one
+/// instruction. Every contract must pay `provider_id` alone
+pub fn spend_batch(
+    gatekeeper_id: &Pubkey,
+    provider_id: &Pubkey,
+    spends: &[(Pubkey, u64, u64)],
+) -> Instruction {
+    let mut account_metas = vec![
         AccountMeta::new(*gatekeeper_id, true),
-        AccountMeta::new(*contract_id, false),
         AccountMeta::new(*provider_id, false),
     ];
+    let spends = spends
+        .iter()
+        .map(|(contract_id, amount, total_bytes)| {
+            let index = account_metas
+                .iter()
+                .position(|meta| &meta.pubkey == contract_id)
+                .unwrap_or_else(|| {
+                    account_metas.push(AccountMeta::new(*contract_id, false));
+                    account_metas.len() - 1
+                });
+            (index as u8, *amount, *total_bytes)
+        })
+        .collect();
+    account_metas.push(AccountMeta::new_credit_only(clock::id(), false));
     Instruction::new(
         id(),
-        &BandwidthPrepayInstruction::Spend(amount),
+        &BandwidthPrepayInstruction::SpendBatch(spends),
         account_metas,
     )
 }
@@ -68,6 +305,222 @@ pub fn refund(gatekeeper_id: &Pubkey, contract_id: &Pubkey, initiator_id: &Pubke
         AccountMeta::new(*gatekeeper_id, true),
         AccountMeta::new(*contract_id, false),
         AccountMeta::new(*initiator_id, false),
+        AccountMeta::new_credit_only(clock::id(), false),
     ];
     Instruction::new(id(), &BandwidthPrepayInstruction::Refund, account_metas)
 }
+
+/// `payees` must be the contract's `BandwidthPrepayState::payees()`, in order
+pub fn settle(
+    gatekeeper_id: &Pubkey,
+    contract_id: &Pubkey,
+    payees: &[Pubkey],
+    receipt: &UsageReceipt,
+    signature: &Signature,
+) -> Instruction {
+    let account_metas = payment_account_metas(gatekeeper_id, contract_id, payees);
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::Settle(receipt.clone(), *signature),
+        account_metas,
+    )
+}
+
+pub fn reclaim(initiator_id: &Pubkey, contract_id: &Pubkey) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*initiator_id, true),
+        AccountMeta::new(*contract_id, false),
+        AccountMeta::new_credit_only(clock::id(), false),
+    ];
+    Instruction::new(id(), &BandwidthPrepayInstruction::Reclaim, account_metas)
+}
+
+pub fn top_up(funder_id: &Pubkey, contract_id: &Pubkey, lamports: u64) -> Vec<Instruction> {
+    let account_metas = vec![
+        AccountMeta::new(*funder_id, true),
+        AccountMeta::new(*contract_id, false),
+        AccountMeta::new_credit_only(clock::id(), false),
+    ];
+    vec![
+        system_instruction::transfer(funder_id, contract_id, lamports),
+        Instruction::new(
+            id(),
+            &BandwidthPrepayInstruction::TopUp(lamports),
+            account_metas,
+        ),
+    ]
+}
+
+pub fn close(gatekeeper_id: &Pubkey, contract_id: &Pubkey, initiator_id: &Pubkey) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*gatekeeper_id, true),
+        AccountMeta::new(*contract_id, false),
+        AccountMeta::new(*initiator_id, false),
+        AccountMeta::new_credit_only(clock::id(), false),
+    ];
+    Instruction::new(id(), &BandwidthPrepayInstruction::Close, account_metas)
+}
+
+/// Create `contract_id`, funded with `lamports` by the signer, and move the
+/// legacy contract at `legacy_id` into it
+pub fn migrate(
+    signer_id: &Pubkey,
+    legacy_id: &Pubkey,
+    contract_id: &Pubkey,
+    lamports: u64,
+) -> Vec<Instruction> {
+    let space = BandwidthPrepayState::max_size() as u64;
+    vec![
+        system_instruction::create_account(signer_id, contract_id, lamports, space, &id()),
+        migrate_account(signer_id, legacy_id, contract_id),
+    ]
+}
+
+pub fn migrate_account(
+    signer_id: &Pubkey,
+    legacy_id: &Pubkey,
+    contract_id: &Pubkey,
+) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*signer_id, true),
+        AccountMeta::new(*legacy_id, false),
+        AccountMeta::new(*contract_id, false),
+    ];
+    Instruction::new(id(), &BandwidthPrepayInstruction::Migrate, account_metas)
+}
+
+/// Pass `initiator_id` to have the initiator co-sign the handover
+pub fn change_gatekeeper(
+    gatekeeper_id: &Pubkey,
+    contract_id: &Pubkey,
+    new_gatekeeper_id: &Pubkey,
+    initiator_id: Option<&Pubkey>,
+) -> Instruction {
+    let mut account_metas = vec![
+        AccountMeta::new(*gatekeeper_id, true),
+        AccountMeta::new(*contract_id, false),
+        AccountMeta::new(*new_gatekeeper_id, false),
+    ];
+    if let Some(initiator_id) = initiator_id {
+        account_metas.push(AccountMeta::new(*initiator_id, true));
+    }
+    // Checked when the contract requires registered gatekeepers
+    account_metas.push(AccountMeta::new_credit_only(
+        registration_address(new_gatekeeper_id),
+        false,
+    ));
+    account_metas.push(AccountMeta::new_credit_only(clock::id(), false));
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::ChangeGatekeeper,
+        account_metas,
+    )
+}
+
+/// `payees` must be the contract's `BandwidthPrepayState::payees()`, in order
+pub fn end_session(
+    gatekeeper_id: &Pubkey,
+    contract_id: &Pubkey,
+    payees: &[Pubkey],
+    final_spend: u64,
+    total_bytes: u64,
+) -> Instruction {
+    let account_metas = payment_account_metas(gatekeeper_id, contract_id, payees);
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::EndSession {
+            final_spend,
+            total_bytes,
+        },
+        account_metas,
+    )
+}
+
+/// Create the gatekeeper's registration account, funded with `lamports`, and
+/// publish `info` in it
+pub fn register_gatekeeper(
+    gatekeeper_id: &Pubkey,
+    lamports: u64,
+    info: &GatekeeperInfo,
+) -> Vec<Instruction> {
+    let registration_id = registration_address(gatekeeper_id);
+    let space = GatekeeperRegistration::max_size() as u64;
+    vec![
+        system_instruction::create_account(gatekeeper_id, &registration_id, lamports, space, &id()),
+        update_registration(gatekeeper_id, info),
+    ]
+}
+
+/// Replace what an already registered gatekeeper publishes
+pub fn update_registration(gatekeeper_id: &Pubkey, info: &GatekeeperInfo) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*gatekeeper_id, true),
+        AccountMeta::new(registration_address(gatekeeper_id), false),
+    ];
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::RegisterGatekeeper(info.clone()),
+        account_metas,
+    )
+}
+
+pub fn deregister_gatekeeper(gatekeeper_id: &Pubkey) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*gatekeeper_id, true),
+        AccountMeta::new(registration_address(gatekeeper_id), false),
+    ];
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::DeregisterGatekeeper,
+        account_metas,
+    )
+}
+
+/// Create the gatekeeper's bond account holding `lamports`
+pub fn create_bond(gatekeeper_id: &Pubkey, lamports: u64) -> Vec<Instruction> {
+    let bond_id = bond_address(gatekeeper_id);
+    let space = GatekeeperBond::max_size() as u64;
+    vec![
+        system_instruction::create_account(gatekeeper_id, &bond_id, lamports, space, &id()),
+        post_bond(gatekeeper_id),
+    ]
+}
+
+/// Add `lamports` to an existing bond
+pub fn add_to_bond(gatekeeper_id: &Pubkey, lamports: u64) -> Vec<Instruction> {
+    vec![
+        system_instruction::transfer(gatekeeper_id, &bond_address(gatekeeper_id), lamports),
+        post_bond(gatekeeper_id),
+    ]
+}
+
+fn post_bond(gatekeeper_id: &Pubkey) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*gatekeeper_id, true),
+        AccountMeta::new(bond_address(gatekeeper_id), false),
+    ];
+    Instruction::new(id(), &BandwidthPrepayInstruction::PostBond, account_metas)
+}
+
+/// `signature` is the initiator's signature of `receipt`. `gatekeeper_id` is
+/// the gatekeeper that served the session; see
+/// `BandwidthPrepayState::session_gatekeeper`
+pub fn dispute(
+    initiator_id: &Pubkey,
+    contract_id: &Pubkey,
+    gatekeeper_id: &Pubkey,
+    receipt: &UsageReceipt,
+    signature: &Signature,
+) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*initiator_id, true),
+        AccountMeta::new(*contract_id, false),
+        AccountMeta::new(bond_address(gatekeeper_id), false),
+        AccountMeta::new_credit_only(clock::id(), false),
+    ];
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::Dispute(receipt.clone(), *signature),
+        account_metas,
+    )
+}
@@ -1,30 +1,375 @@
-use crate::bandwidth_prepay_instruction::BandwidthPrepayInstruction;
-use crate::bandwidth_prepay_state::{BandwidthPrepayError, BandwidthPrepayState};
+use crate::bandwidth_prepay_bond::{bond_address, GatekeeperBond, BOND_TAG};
+use crate::bandwidth_prepay_history::EventKind;
+use crate::bandwidth_prepay_instruction::{
+    contract_address, BandwidthPrepayInstruction, ContractTerms,
+};
+use crate::bandwidth_prepay_receipt::UsageReceipt;
+use crate::bandwidth_prepay_registry::{
+    registration_address, GatekeeperInfo, GatekeeperRegistration, REGISTRATION_TAG,
+};
+use crate::bandwidth_prepay_state::{
+    BandwidthPrepayError, BandwidthPrepayState, ContractStatus, MAX_DESTINATIONS,
+};
 use bincode::deserialize;
 use solana_sdk::account::KeyedAccount;
 use solana_sdk::instruction::InstructionError;
 use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use solana_sdk::sysvar::clock;
 
-fn initialize_account(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+fn initialize_account(
+    program_id: &Pubkey,
+    keyed_accounts: &mut [KeyedAccount],
+    terms: ContractTerms,
+    slot: Option<u64>,
+) -> Result<(), BandwidthPrepayError> {
     if let Ok(state) = BandwidthPrepayState::deserialize(&keyed_accounts[1].account.data) {
-        if state != BandwidthPrepayState::default() {
-            Err(BandwidthPrepayError::AlreadyInitialized)?
+        match state.status {
+            ContractStatus::Uninitialized => {}
+            ContractStatus::Initialized => Err(BandwidthPrepayError::AlreadyInitialized)?,
+            ContractStatus::Closed => Err(BandwidthPrepayError::ContractClosed)?,
+        }
+    }
+    if !BandwidthPrepayState::is_valid_split(&terms.recipients) {
+        Err(BandwidthPrepayError::InvalidRevenueSplit)?
+    }
+    if terms.allowed_destinations.len() > MAX_DESTINATIONS {
+        Err(BandwidthPrepayError::TooManyDestinations)?
+    }
+    let contract_pubkey = keyed_accounts[1].unsigned_key();
+    if terms
+        .recipients
+        .iter()
+        .any(|(recipient, _)| recipient == contract_pubkey)
+    {
+        Err(BandwidthPrepayError::AliasedAccounts)?
+    }
+    let initiator_id = *keyed_accounts[0]
+        .signer_key()
+        .ok_or(BandwidthPrepayError::NotSignedByInitiator)?;
+    let gatekeeper_id = *keyed_accounts[2].unsigned_key();
+    let provider_id = *keyed_accounts[3].unsigned_key();
+    if let Some(nonce) = terms.nonce {
+        let derived_id = contract_address(&initiator_id, &gatekeeper_id, &provider_id, nonce);
+        if keyed_accounts[1].unsigned_key() != &derived_id {
+            Err(BandwidthPrepayError::InvalidContractAddress)?
         }
     }
-    let state = BandwidthPrepayState {
-        initiator_id: *keyed_accounts[0].signer_key().unwrap(),
-        gatekeeper_id: *keyed_accounts[2].unsigned_key(),
-        provider_id: *keyed_accounts[3].unsigned_key(),
+    if terms.require_registered_gatekeeper {
+        check_registered(program_id, keyed_accounts.get(4), &gatekeeper_id)?;
+    }
+    let mut state = BandwidthPrepayState {
+        initiator_id,
+        gatekeeper_id,
+        provider_id,
+        lamports_per_kib: terms.lamports_per_kib,
+        max_bytes: terms.max_bytes,
+        total_spent: 0,
+        total_bytes: 0,
+        expiry_slot: terms.expiry_slot,
+        status: ContractStatus::Initialized,
+        deposit_count: 0,
+        recorded_balance: 0,
+        recipients: terms.recipients,
+        gatekeeper_fee: terms.gatekeeper_fee,
+        rotation_requires_initiator: terms.rotation_requires_initiator,
+        session_count: 0,
+        lifetime_bytes: 0,
+        nonce: terms.nonce,
+        instance: terms.instance,
+        require_registered_gatekeeper: terms.require_registered_gatekeeper,
+        ended_sessions: vec![],
+        receipt_bytes: 0,
+        receipt_lamports: 0,
+        previous_gatekeeper: None,
+        history: vec![],
+        allowed_destinations: terms.allowed_destinations,
+        lamports_per_slot: terms.lamports_per_slot,
+        session_start_slot: None,
     };
-    state.serialize(&mut keyed_accounts[1].account.data)
+    if !state.has_distinct_payees() {
+        Err(BandwidthPrepayError::AliasedAccounts)?
+    }
+    state.record(EventKind::Deposit, keyed_accounts[1].account.lamports, slot);
+    store(&mut keyed_accounts[1], &mut state)
+}
+
+/// Write `state` to `contract_account`, remembering the balance it leaves
+/// the contract with so `TopUp` can tell what has been deposited since
+fn store(
+    contract_account: &mut KeyedAccount,
+    state: &mut BandwidthPrepayState,
+) -> Result<(), BandwidthPrepayError> {
+    state.recorded_balance = contract_account.account.lamports;
+    state.serialize(&mut contract_account.account.data)
+}
+
+/// Check that `registration` is the program's record of `gatekeeper_id`
+fn check_registered(
+    program_id: &Pubkey,
+    registration: Option<&KeyedAccount>,
+    gatekeeper_id: &Pubkey,
+) -> Result<(), BandwidthPrepayError> {
+    let registration = registration.ok_or(BandwidthPrepayError::GatekeeperNotRegistered)?;
+    if registration.unsigned_key() != &registration_address(gatekeeper_id)
+        || registration.account.owner != *program_id
+    {
+        Err(BandwidthPrepayError::GatekeeperNotRegistered)?
+    }
+    match GatekeeperRegistration::deserialize(&registration.account.data) {
+        Ok(registration) if &registration.gatekeeper_id == gatekeeper_id => Ok(()),
+        _ => Err(BandwidthPrepayError::GatekeeperNotRegistered),
+    }
+}
+
+/// Check that the accounts following the contract are its payees, in order
+fn check_payees(
+    payee_accounts: &[KeyedAccount],
+    state: &BandwidthPrepayState,
+) -> Result<(), BandwidthPrepayError> {
+    if !state.has_distinct_payees() {
+        Err(BandwidthPrepayError::AliasedAccounts)?
+    }
+    let payees = state.payees();
+    if payee_accounts.len() != payees.len()
+        || payee_accounts
+            .iter()
+            .zip(payees.iter())
+            .any(|(account, payee)| account.unsigned_key() != payee)
+    {
+        Err(BandwidthPrepayError::NoProviderAccount)?
+    }
+    Ok(())
+}
+
+fn debit(account: &mut KeyedAccount, lamports: u64) -> Result<(), BandwidthPrepayError> {
+    account.account.lamports = account
+        .account
+        .lamports
+        .checked_sub(lamports)
+        .ok_or(BandwidthPrepayError::BalanceTooLow)?;
+    Ok(())
+}
+
+fn credit(account: &mut KeyedAccount, lamports: u64) -> Result<(), BandwidthPrepayError> {
+    account.account.lamports = account
+        .account
+        .lamports
+        .checked_add(lamports)
+        .ok_or(BandwidthPrepayError::Overflow)?;
+    Ok(())
+}
+
+/// Move `amount` lamports from the contract to its payees according to the
+/// contract's revenue split
+fn pay(
+    contract_account: &mut KeyedAccount,
+    payee_accounts: &mut [KeyedAccount],
+    state: &BandwidthPrepayState,
+    amount: u64,
+) -> Result<(), BandwidthPrepayError> {
+    debit(contract_account, amount)?;
+    for (payee_account, share) in payee_accounts.iter_mut().zip(state.split(amount)) {
+        credit(payee_account, share)?;
+    }
+    Ok(())
+}
+
+/// Move the whole contract balance to `recipient_account`
+fn drain(
+    contract_account: &mut KeyedAccount,
+    recipient_account: &mut KeyedAccount,
+) -> Result<(), BandwidthPrepayError> {
+    let lamports = contract_account.account.lamports;
+    credit(recipient_account, lamports)?;
+    contract_account.account.lamports = 0;
+    Ok(())
+}
+
+/// Split the accounts of a `Spend` or `EndSession` into the gatekeeper, the
+/// contract and the payees
+fn split_payment_accounts<'a, 'b>(
+    keyed_accounts: &'a mut [KeyedAccount<'b>],
+) -> (
+    &'a mut KeyedAccount<'b>,
+    &'a mut KeyedAccount<'b>,
+    &'a mut [KeyedAccount<'b>],
+) {
+    let (gatekeeper_accounts, keyed_accounts) = keyed_accounts.split_at_mut(1);
+    let (contract_accounts, payee_accounts) = keyed_accounts.split_at_mut(1);
+    (
+        &mut gatekeeper_accounts[0],
+        &mut contract_accounts[0],
+        payee_accounts,
+    )
+}
+
+/// Pay `amount` and the gatekeeper fee out of the contract, checking it
+/// against the agreed terms for the `total_bytes` forwarded this session
+fn charge(
+    gatekeeper_account: &mut KeyedAccount,
+    contract_account: &mut KeyedAccount,
+    payee_accounts: &mut [KeyedAccount],
+    state: &mut BandwidthPrepayState,
+    amount: u64,
+    total_bytes: u64,
+    slot: Option<u64>,
+) -> Result<(), BandwidthPrepayError> {
+    if let Some(gatekeeper_pubkey) = gatekeeper_account.signer_key() {
+        if gatekeeper_pubkey != &state.gatekeeper_id {
+            Err(BandwidthPrepayError::NoGatekeeperAccount)?
+        }
+    } else {
+        Err(BandwidthPrepayError::NotSignedByGatekeeper)?
+    }
+    check_payees(payee_accounts, state)?;
+    let fee = state.fee(amount);
+    let debit_amount = amount
+        .checked_add(fee)
+        .ok_or(BandwidthPrepayError::Overflow)?;
+    if contract_account.account.lamports < debit_amount {
+        Err(BandwidthPrepayError::BalanceTooLow)?
+    }
+    if total_bytes < state.total_bytes {
+        Err(BandwidthPrepayError::ByteCounterDecreased)?
+    }
+    if !state.within_data_cap(total_bytes) {
+        Err(BandwidthPrepayError::DataCapExceeded)?
+    }
+    // A session's clock starts with its first charge
+    if state.session_start_slot.is_none() {
+        state.session_start_slot = slot;
+    }
+    let total_spent = state
+        .total_spent
+        .checked_add(amount)
+        .ok_or(BandwidthPrepayError::Overflow)?;
+    if total_spent > state.allowance(total_bytes, state.session_slots(slot)) {
+        Err(BandwidthPrepayError::SpendExceedsRate)?
+    }
+
+    pay(contract_account, payee_accounts, state, amount)?;
+    debit(contract_account, fee)?;
+    credit(gatekeeper_account, fee)?;
+
+    state.lifetime_bytes = state.lifetime_bytes_at(total_bytes);
+    state.total_spent = total_spent;
+    state.total_bytes = total_bytes;
+    state.record(EventKind::Spend, debit_amount, slot);
+    Ok(())
+}
+
+fn spend(
+    keyed_accounts: &mut [KeyedAccount],
+    amount: u64,
+    total_bytes: u64,
+    slot: Option<u64>,
+) -> Result<(), BandwidthPrepayError> {
+    let contract_account_index = 1;
+    if amount == 0 {
+        Err(BandwidthPrepayError::ZeroAmount)?
+    }
+    let mut state =
+        BandwidthPrepayState::deserialize(&keyed_accounts[contract_account_index].account.data)?;
+    if state.status == ContractStatus::Closed {
+        Err(BandwidthPrepayError::ContractClosed)?
+    }
+
+    let (gatekeeper_account, contract_account, payee_accounts) =
+        split_payment_accounts(keyed_accounts);
+    charge(
+        gatekeeper_account,
+        contract_account,
+        payee_accounts,
+        &mut state,
+        amount,
+        total_bytes,
+        slot,
+    )?;
+    store(&mut keyed_accounts[contract_account_index], &mut state)
+}
+
+fn spend_batch(
+    keyed_accounts: &mut [KeyedAccount],
+    spends: &[(u8, u64, u64)],
+    slot: Option<u64>,
+) -> Result<(), BandwidthPrepayError> {
+    let first_contract_account_index = 2;
+    if spends.is_empty() {
+        Err(BandwidthPrepayError::ZeroAmount)?
+    }
+    let (payment_accounts, contract_accounts) =
+        keyed_accounts.split_at_mut(first_contract_account_index);
+    let (gatekeeper_accounts, provider_accounts) = payment_accounts.split_at_mut(1);
+    for &(index, amount, total_bytes) in spends {
+        if amount == 0 {
+            Err(BandwidthPrepayError::ZeroAmount)?
+        }
+        let contract_account = usize::from(index)
+            .checked_sub(first_contract_account_index)
+            .and_then(|i| contract_accounts.get_mut(i))
+            .ok_or(BandwidthPrepayError::NotEnoughAccounts)?;
+        let mut state = BandwidthPrepayState::deserialize(&contract_account.account.data)?;
+        if state.status == ContractStatus::Closed {
+            Err(BandwidthPrepayError::ContractClosed)?
+        }
+
+        charge(
+            &mut gatekeeper_accounts[0],
+            contract_account,
+            provider_accounts,
+            &mut state,
+            amount,
+            total_bytes,
+            slot,
+        )?;
+        store(contract_account, &mut state)?;
+    }
+    Ok(())
+}
+
+fn end_session(
+    keyed_accounts: &mut [KeyedAccount],
+    final_spend: u64,
+    total_bytes: u64,
+    slot: Option<u64>,
+) -> Result<(), BandwidthPrepayError> {
+    let contract_account_index = 1;
+    let mut state =
+        BandwidthPrepayState::deserialize(&keyed_accounts[contract_account_index].account.data)?;
+    if state.status == ContractStatus::Closed {
+        Err(BandwidthPrepayError::ContractClosed)?
+    }
+    // The dispute window runs from the session's end
+    let end_slot = slot.ok_or(BandwidthPrepayError::InvalidClockAccount)?;
+
+    let (gatekeeper_account, contract_account, payee_accounts) =
+        split_payment_accounts(keyed_accounts);
+    charge(
+        gatekeeper_account,
+        contract_account,
+        payee_accounts,
+        &mut state,
+        final_spend,
+        total_bytes,
+        slot,
+    )?;
+    state.end_session(end_slot)?;
+    store(&mut keyed_accounts[contract_account_index], &mut state)
 }
 
-fn spend(keyed_accounts: &mut [KeyedAccount], amount: u64) -> Result<(), BandwidthPrepayError> {
+fn refund(
+    keyed_accounts: &mut [KeyedAccount],
+    slot: Option<u64>,
+) -> Result<(), BandwidthPrepayError> {
     let gatekeeper_account_index = 0;
     let contract_account_index = 1;
-    let provider_account_index = 2;
-    let state =
+    let initiator_account_index = 2;
+    let mut state =
         BandwidthPrepayState::deserialize(&keyed_accounts[contract_account_index].account.data)?;
+    if state.status == ContractStatus::Closed {
+        Err(BandwidthPrepayError::ContractClosed)?
+    }
 
     if let Some(gatekeeper_pubkey) = keyed_accounts[gatekeeper_account_index].signer_key() {
         if gatekeeper_pubkey != &state.gatekeeper_id {
@@ -33,25 +378,232 @@ fn spend(keyed_accounts: &mut [KeyedAccount], amount: u64) -> Result<(), Bandwid
     } else {
         Err(BandwidthPrepayError::NotSignedByGatekeeper)?
     }
-    if keyed_accounts[provider_account_index].unsigned_key() != &state.provider_id {
-        Err(BandwidthPrepayError::NoProviderAccount)?
+    if keyed_accounts[initiator_account_index].unsigned_key() != &state.initiator_id {
+        Err(BandwidthPrepayError::NoInitiatorAccount)?
+    }
+
+    let lamports = keyed_accounts[contract_account_index].account.lamports;
+    let (contract_accounts, initiator_accounts) =
+        keyed_accounts.split_at_mut(initiator_account_index);
+    drain(
+        &mut contract_accounts[contract_account_index],
+        &mut initiator_accounts[0],
+    )?;
+
+    state.record(EventKind::Refund, lamports, slot);
+    store(&mut keyed_accounts[contract_account_index], &mut state)
+}
+
+fn close(
+    keyed_accounts: &mut [KeyedAccount],
+    slot: Option<u64>,
+) -> Result<(), BandwidthPrepayError> {
+    let contract_account_index = 1;
+    refund(keyed_accounts, slot)?;
+
+    let mut state =
+        BandwidthPrepayState::deserialize(&keyed_accounts[contract_account_index].account.data)?;
+    state.status = ContractStatus::Closed;
+    store(&mut keyed_accounts[contract_account_index], &mut state)
+}
+
+fn settle(
+    keyed_accounts: &mut [KeyedAccount],
+    receipt: &UsageReceipt,
+    signature: &Signature,
+    slot: Option<u64>,
+) -> Result<(), BandwidthPrepayError> {
+    let gatekeeper_account_index = 0;
+    let contract_account_index = 1;
+    let first_payee_account_index = 2;
+    let mut state =
+        BandwidthPrepayState::deserialize(&keyed_accounts[contract_account_index].account.data)?;
+    if state.status == ContractStatus::Closed {
+        Err(BandwidthPrepayError::ContractClosed)?
+    }
+
+    if let Some(gatekeeper_pubkey) = keyed_accounts[gatekeeper_account_index].signer_key() {
+        if gatekeeper_pubkey != &state.gatekeeper_id {
+            Err(BandwidthPrepayError::NoGatekeeperAccount)?
+        }
+    } else {
+        Err(BandwidthPrepayError::NotSignedByGatekeeper)?
+    }
+    check_payees(&keyed_accounts[first_payee_account_index..], &state)?;
+    if keyed_accounts[contract_account_index].unsigned_key() != &receipt.contract_id
+        || receipt.instance != state.instance
+    {
+        Err(BandwidthPrepayError::ReceiptContractMismatch)?
+    }
+    if !receipt.verify(signature, &state.initiator_id) {
+        Err(BandwidthPrepayError::InvalidReceiptSignature)?
+    }
+    if receipt.session != state.session_count || receipt.total_lamports <= state.receipt_lamports {
+        Err(BandwidthPrepayError::StaleReceipt)?
     }
+    let total_bytes = state.total_bytes.max(receipt.total_bytes);
+    if !state.within_data_cap(total_bytes) {
+        Err(BandwidthPrepayError::DataCapExceeded)?
+    }
+    // A receipt for no more than was already spent only backs those spends
+    // against a dispute
+    let amount = receipt.total_lamports.saturating_sub(state.total_spent);
     if keyed_accounts[contract_account_index].account.lamports < amount {
         Err(BandwidthPrepayError::BalanceTooLow)?
     }
 
-    keyed_accounts[contract_account_index].account.lamports -= amount;
-    keyed_accounts[provider_account_index].account.lamports += amount;
+    if amount > 0 {
+        let (contract_accounts, payee_accounts) =
+            keyed_accounts.split_at_mut(first_payee_account_index);
+        pay(
+            &mut contract_accounts[contract_account_index],
+            payee_accounts,
+            &state,
+            amount,
+        )?;
+    }
 
-    Ok(())
+    state.lifetime_bytes = state.lifetime_bytes_at(total_bytes);
+    state.total_spent = state.total_spent.max(receipt.total_lamports);
+    state.total_bytes = total_bytes;
+    state.receipt_bytes = state.receipt_bytes.max(receipt.total_bytes);
+    state.receipt_lamports = receipt.total_lamports;
+    state.record(EventKind::Settle, amount, slot);
+    store(&mut keyed_accounts[contract_account_index], &mut state)
+}
+
+fn reclaim(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+    let initiator_account_index = 0;
+    let contract_account_index = 1;
+    let clock_account_index = 2;
+    let mut state =
+        BandwidthPrepayState::deserialize(&keyed_accounts[contract_account_index].account.data)?;
+    if state.status == ContractStatus::Closed {
+        Err(BandwidthPrepayError::ContractClosed)?
+    }
+
+    if let Some(initiator_pubkey) = keyed_accounts[initiator_account_index].signer_key() {
+        if initiator_pubkey != &state.initiator_id {
+            Err(BandwidthPrepayError::NoInitiatorAccount)?
+        }
+    } else {
+        Err(BandwidthPrepayError::NotSignedByInitiator)?
+    }
+    let clock = clock::from_keyed_account(&keyed_accounts[clock_account_index])
+        .map_err(|_| BandwidthPrepayError::InvalidClockAccount)?;
+    if clock.slot < state.expiry_slot {
+        Err(BandwidthPrepayError::NotExpired)?
+    }
+
+    let lamports = keyed_accounts[contract_account_index].account.lamports;
+    let (initiator_accounts, contract_accounts) =
+        keyed_accounts.split_at_mut(contract_account_index);
+    drain(
+        &mut contract_accounts[0],
+        &mut initiator_accounts[initiator_account_index],
+    )?;
+
+    state.record(EventKind::Reclaim, lamports, Some(clock.slot));
+    state.status = ContractStatus::Closed;
+    store(&mut keyed_accounts[contract_account_index], &mut state)
+}
+
+fn top_up(
+    keyed_accounts: &mut [KeyedAccount],
+    lamports: u64,
+    slot: Option<u64>,
+) -> Result<(), BandwidthPrepayError> {
+    let funder_account_index = 0;
+    let contract_account_index = 1;
+    let funder_id = *keyed_accounts[funder_account_index]
+        .signer_key()
+        .ok_or(BandwidthPrepayError::NotSignedByFunder)?;
+    let mut state =
+        BandwidthPrepayState::deserialize(&keyed_accounts[contract_account_index].account.data)?;
+    if state.status == ContractStatus::Closed {
+        Err(BandwidthPrepayError::ContractClosed)?
+    }
+
+    if state.status != ContractStatus::Initialized {
+        Err(BandwidthPrepayError::ContractNotInitialized)?
+    }
+    // Only the parties may add to the contract history
+    if funder_id != state.initiator_id && funder_id != state.gatekeeper_id {
+        Err(BandwidthPrepayError::NotSignedByContractParty)?
+    }
+    if lamports == 0 {
+        Err(BandwidthPrepayError::ZeroAmount)?
+    }
+    // Only what actually reached the contract since its last instruction
+    // counts as a deposit
+    let deposited = keyed_accounts[contract_account_index]
+        .account
+        .lamports
+        .saturating_sub(state.recorded_balance);
+    if deposited < lamports {
+        Err(BandwidthPrepayError::BalanceTooLow)?
+    }
+
+    state.deposit_count = state
+        .deposit_count
+        .checked_add(1)
+        .ok_or(BandwidthPrepayError::Overflow)?;
+    state.record(EventKind::Deposit, deposited, slot);
+    store(&mut keyed_accounts[contract_account_index], &mut state)
+}
+
+fn migrate(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+    let signer_account_index = 0;
+    let legacy_account_index = 1;
+    let contract_account_index = 2;
+    if !BandwidthPrepayState::is_legacy(&keyed_accounts[legacy_account_index].account.data) {
+        Err(BandwidthPrepayError::AlreadyMigrated)?
+    }
+    let mut state =
+        BandwidthPrepayState::deserialize(&keyed_accounts[legacy_account_index].account.data)?;
+
+    match keyed_accounts[signer_account_index].signer_key() {
+        Some(signer_pubkey)
+            if signer_pubkey == &state.initiator_id || signer_pubkey == &state.gatekeeper_id => {}
+        _ => Err(BandwidthPrepayError::NotSignedByContractParty)?,
+    }
+
+    // An account can't be resized in place, so the state moves into a new
+    // one the caller has allocated at full size
+    let contract_data = &keyed_accounts[contract_account_index].account.data;
+    if contract_data.len() < BandwidthPrepayState::max_size() {
+        Err(BandwidthPrepayError::UserdataTooSmall)?
+    }
+    if BandwidthPrepayState::deserialize(contract_data)?.status != ContractStatus::Uninitialized {
+        Err(BandwidthPrepayError::AlreadyInitialized)?
+    }
+
+    let lamports = keyed_accounts[legacy_account_index].account.lamports;
+    debit(&mut keyed_accounts[legacy_account_index], lamports)?;
+    credit(&mut keyed_accounts[contract_account_index], lamports)?;
+    // Leave nothing behind that could be migrated a second time
+    keyed_accounts[legacy_account_index]
+        .account
+        .data
+        .iter_mut()
+        .for_each(|byte| *byte = 0);
+    store(&mut keyed_accounts[contract_account_index], &mut state)
 }
 
-fn refund(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+fn change_gatekeeper(
+    program_id: &Pubkey,
+    keyed_accounts: &mut [KeyedAccount],
+    slot: Option<u64>,
+) -> Result<(), BandwidthPrepayError> {
     let gatekeeper_account_index = 0;
     let contract_account_index = 1;
-    let initiator_account_index = 2;
-    let state =
+    let new_gatekeeper_account_index = 2;
+    let initiator_account_index = 3;
+    let mut state =
         BandwidthPrepayState::deserialize(&keyed_accounts[contract_account_index].account.data)?;
+    if state.status == ContractStatus::Closed {
+        Err(BandwidthPrepayError::ContractClosed)?
+    }
 
     if let Some(gatekeeper_pubkey) = keyed_accounts[gatekeeper_account_index].signer_key() {
         if gatekeeper_pubkey != &state.gatekeeper_id {
@@ -60,44 +612,354 @@ fn refund(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayErro
     } else {
         Err(BandwidthPrepayError::NotSignedByGatekeeper)?
     }
-    if keyed_accounts[initiator_account_index].unsigned_key() != &state.initiator_id {
-        Err(BandwidthPrepayError::NoInitiatorAccount)?
+    match keyed_accounts
+        .get(initiator_account_index)
+        .and_then(|account| account.signer_key())
+    {
+        Some(initiator_pubkey) if initiator_pubkey != &state.initiator_id => {
+            Err(BandwidthPrepayError::NoInitiatorAccount)?
+        }
+        None if state.rotation_requires_initiator => {
+            Err(BandwidthPrepayError::NotSignedByInitiator)?
+        }
+        _ => {}
+    }
+    // The outgoing gatekeeper ends its session first, charging what it is
+    // owed, as it can't charge the contract once it is handed over
+    if state.total_spent > 0 || state.total_bytes > 0 {
+        Err(BandwidthPrepayError::SessionOpen)?
+    }
+    // Disputes slash the bond of the gatekeeper that served the session, and
+    // the contract only remembers the one it was last handed over from
+    if state.previous_gatekeeper.is_some() {
+        let slot = slot.ok_or(BandwidthPrepayError::InvalidClockAccount)?;
+        if state.previous_gatekeeper_disputable(slot) {
+            Err(BandwidthPrepayError::DisputeWindowOpen)?
+        }
+    }
+
+    let new_gatekeeper_id = *keyed_accounts[new_gatekeeper_account_index].unsigned_key();
+    if state.require_registered_gatekeeper {
+        let registration_id = registration_address(&new_gatekeeper_id);
+        let registration = keyed_accounts
+            .iter()
+            .find(|account| account.unsigned_key() == &registration_id);
+        check_registered(program_id, registration, &new_gatekeeper_id)?;
+    }
+
+    state.previous_gatekeeper = Some((state.gatekeeper_id, state.session_count));
+    state.gatekeeper_id = new_gatekeeper_id;
+    if !state.has_distinct_payees() {
+        Err(BandwidthPrepayError::AliasedAccounts)?
+    }
+    store(&mut keyed_accounts[contract_account_index], &mut state)
+}
+
+fn register_gatekeeper(
+    keyed_accounts: &mut [KeyedAccount],
+    info: GatekeeperInfo,
+) -> Result<(), BandwidthPrepayError> {
+    let gatekeeper_account_index = 0;
+    let registration_account_index = 1;
+    let gatekeeper_id = *keyed_accounts[gatekeeper_account_index]
+        .signer_key()
+        .ok_or(BandwidthPrepayError::NotSignedByGatekeeper)?;
+    let registration_account = &mut keyed_accounts[registration_account_index];
+    if registration_account.unsigned_key() != &registration_address(&gatekeeper_id) {
+        Err(BandwidthPrepayError::InvalidRegistrationAccount)?
+    }
+    // Only a fresh account or an earlier registration may be written
+    match registration_account.account.data.first() {
+        Some(&0) | Some(&REGISTRATION_TAG) => {}
+        _ => Err(BandwidthPrepayError::InvalidRegistrationAccount)?,
+    }
+    let registration = GatekeeperRegistration {
+        gatekeeper_id,
+        info,
+    };
+    registration_account
+        .account
+        .data
+        .iter_mut()
+        .for_each(|byte| *byte = 0);
+    registration.serialize(&mut registration_account.account.data)
+}
+
+fn deregister_gatekeeper(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+    let gatekeeper_account_index = 0;
+    let registration_account_index = 1;
+    let gatekeeper_id = *keyed_accounts[gatekeeper_account_index]
+        .signer_key()
+        .ok_or(BandwidthPrepayError::NotSignedByGatekeeper)?;
+    if keyed_accounts[registration_account_index].unsigned_key()
+        != &registration_address(&gatekeeper_id)
+    {
+        Err(BandwidthPrepayError::InvalidRegistrationAccount)?
+    }
+    GatekeeperRegistration::deserialize(&keyed_accounts[registration_account_index].account.data)
+        .map_err(|_| BandwidthPrepayError::InvalidRegistrationAccount)?;
+
+    keyed_accounts[registration_account_index]
+        .account
+        .data
+        .iter_mut()
+        .for_each(|byte| *byte = 0);
+    let (gatekeeper_account, registration_account) = keyed_accounts.split_at_mut(1);
+    drain(&mut registration_account[0], &mut gatekeeper_account[0])
+}
+
+fn post_bond(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+    let gatekeeper_account_index = 0;
+    let bond_account_index = 1;
+    let gatekeeper_id = *keyed_accounts[gatekeeper_account_index]
+        .signer_key()
+        .ok_or(BandwidthPrepayError::NotSignedByGatekeeper)?;
+    let bond_account = &mut keyed_accounts[bond_account_index];
+    if bond_account.unsigned_key() != &bond_address(&gatekeeper_id) {
+        Err(BandwidthPrepayError::InvalidBondAccount)?
+    }
+    match bond_account.account.data.first() {
+        Some(&0) => GatekeeperBond {
+            gatekeeper_id,
+            total_slashed: 0,
+        }
+        .serialize(&mut bond_account.account.data),
+        Some(&BOND_TAG) => Ok(()),
+        _ => Err(BandwidthPrepayError::InvalidBondAccount),
+    }
+}
+
+fn dispute(
+    program_id: &Pubkey,
+    keyed_accounts: &mut [KeyedAccount],
+    receipt: &UsageReceipt,
+    signature: &Signature,
+    slot: Option<u64>,
+) -> Result<(), BandwidthPrepayError> {
+    let initiator_account_index = 0;
+    let contract_account_index = 1;
+    let bond_account_index = 2;
+    let mut state =
+        BandwidthPrepayState::deserialize(&keyed_accounts[contract_account_index].account.data)?;
+    if state.status == ContractStatus::Uninitialized {
+        Err(BandwidthPrepayError::ContractNotInitialized)?
+    }
+
+    if let Some(initiator_pubkey) = keyed_accounts[initiator_account_index].signer_key() {
+        if initiator_pubkey != &state.initiator_id {
+            Err(BandwidthPrepayError::NoInitiatorAccount)?
+        }
+    } else {
+        Err(BandwidthPrepayError::NotSignedByInitiator)?
+    }
+    let bond_account = &keyed_accounts[bond_account_index];
+    if bond_account.unsigned_key() != &bond_address(state.session_gatekeeper(receipt.session))
+        || bond_account.account.owner != *program_id
+    {
+        Err(BandwidthPrepayError::InvalidBondAccount)?
+    }
+    let mut bond = GatekeeperBond::deserialize(&bond_account.account.data)
+        .map_err(|_| BandwidthPrepayError::InvalidBondAccount)?;
+    if keyed_accounts[contract_account_index].unsigned_key() != &receipt.contract_id
+        || receipt.instance != state.instance
+    {
+        Err(BandwidthPrepayError::ReceiptContractMismatch)?
+    }
+    if !receipt.verify(signature, &state.initiator_id) {
+        Err(BandwidthPrepayError::InvalidReceiptSignature)?
+    }
+    let slot = slot.ok_or(BandwidthPrepayError::InvalidClockAccount)?;
+    // Only a finished session has final totals to compare the receipt with
+    let ended_index = state
+        .ended_sessions
+        .iter()
+        .position(|ended| ended.session == receipt.session)
+        .ok_or(BandwidthPrepayError::StaleReceipt)?;
+    let ended = &state.ended_sessions[ended_index];
+    if ended.disputed {
+        Err(BandwidthPrepayError::AlreadyDisputed)?
+    }
+    if !ended.in_dispute_window(slot) {
+        Err(BandwidthPrepayError::DisputeWindowClosed)?
     }
+    // The initiator acknowledged the larger of its own receipt and the one
+    // the gatekeeper settled, plus the time the session lasted
+    let acknowledged = state
+        .allowance(receipt.total_bytes.max(ended.receipt_bytes), ended.slots)
+        .max(receipt.total_lamports)
+        .max(ended.receipt_lamports);
+    let overcharge = ended.spent.saturating_sub(acknowledged);
+    if overcharge == 0 {
+        Err(BandwidthPrepayError::NoOvercharge)?
+    }
+    state.ended_sessions[ended_index].disputed = true;
+
+    // Repay as much of the overcharge as the bond still covers
+    let slashed = overcharge.min(keyed_accounts[bond_account_index].account.lamports);
+    debit(&mut keyed_accounts[bond_account_index], slashed)?;
+    credit(&mut keyed_accounts[initiator_account_index], slashed)?;
+    bond.total_slashed = bond
+        .total_slashed
+        .checked_add(slashed)
+        .ok_or(BandwidthPrepayError::Overflow)?;
+    bond.serialize(&mut keyed_accounts[bond_account_index].account.data)?;
+
+    state.record(EventKind::Slash, slashed, Some(slot));
+    store(&mut keyed_accounts[contract_account_index], &mut state)
+}
 
-    keyed_accounts[initiator_account_index].account.lamports +=
-        keyed_accounts[contract_account_index].account.lamports;
-    keyed_accounts[contract_account_index].account.lamports = 0;
+/// Split off the clock sysvar account that builders append to date each
+/// operation in the contract history. The clock is optional, so instructions
+/// built without it still work, save for those that need a slot; their
+/// operations are recorded without one
+fn split_clock<'a, 'b>(
+    keyed_accounts: &'a mut [KeyedAccount<'b>],
+) -> (&'a mut [KeyedAccount<'b>], Option<u64>) {
+    let has_clock = keyed_accounts
+        .last()
+        .map_or(false, |account| account.unsigned_key() == &clock::id());
+    if !has_clock {
+        return (keyed_accounts, None);
+    }
+    let (clock_account, keyed_accounts) = keyed_accounts.split_last_mut().unwrap();
+    let slot = clock::from_keyed_account(clock_account)
+        .ok()
+        .map(|clock| clock.slot);
+    (keyed_accounts, slot)
+}
 
+/// Check that `instruction` was given enough accounts and that the program's
+/// accounts, the second one unless the instruction says otherwise, belong to
+/// this program and appear only once
+fn check_accounts(
+    program_id: &Pubkey,
+    keyed_accounts: &[KeyedAccount],
+    instruction: &BandwidthPrepayInstruction,
+) -> Result<(), BandwidthPrepayError> {
+    let program_account_indices: Vec<usize> = match instruction {
+        BandwidthPrepayInstruction::SpendBatch(spends) => spends
+            .iter()
+            .map(|(index, _, _)| usize::from(*index))
+            .collect(),
+        BandwidthPrepayInstruction::Migrate => vec![1, 2],
+        _ => vec![1],
+    };
+    let required_accounts = match instruction {
+        BandwidthPrepayInstruction::InitializeAccount(_) => 4,
+        BandwidthPrepayInstruction::Spend { .. } => 3,
+        BandwidthPrepayInstruction::Refund => 3,
+        BandwidthPrepayInstruction::Settle(_, _) => 3,
+        BandwidthPrepayInstruction::Reclaim => 3,
+        BandwidthPrepayInstruction::TopUp(_) => 2,
+        BandwidthPrepayInstruction::Close => 3,
+        BandwidthPrepayInstruction::Migrate => 3,
+        BandwidthPrepayInstruction::ChangeGatekeeper => 3,
+        BandwidthPrepayInstruction::EndSession { .. } => 3,
+        BandwidthPrepayInstruction::RegisterGatekeeper(_) => 2,
+        BandwidthPrepayInstruction::DeregisterGatekeeper => 2,
+        BandwidthPrepayInstruction::PostBond => 2,
+        BandwidthPrepayInstruction::Dispute(_, _) => 3,
+        BandwidthPrepayInstruction::SpendBatch(_) => 3,
+    };
+    let required_accounts = program_account_indices
+        .iter()
+        .map(|index| index + 1)
+        .fold(required_accounts, usize::max);
+    if keyed_accounts.len() < required_accounts {
+        Err(BandwidthPrepayError::NotEnoughAccounts)?
+    }
+    for &index in &program_account_indices {
+        if keyed_accounts[index].account.owner != *program_id {
+            Err(BandwidthPrepayError::InvalidAccountOwner)?
+        }
+        let program_pubkey = keyed_accounts[index].unsigned_key();
+        if keyed_accounts
+            .iter()
+            .enumerate()
+            .any(|(i, account)| i != index && account.unsigned_key() == program_pubkey)
+        {
+            Err(BandwidthPrepayError::AliasedAccounts)?
+        }
+    }
     Ok(())
 }
 
 pub fn process_instruction(
-    _program_id: &Pubkey,
+    program_id: &Pubkey,
     keyed_accounts: &mut [KeyedAccount],
     data: &[u8],
 ) -> Result<(), InstructionError> {
     let instruction = deserialize(data).map_err(|_| InstructionError::InvalidInstructionData)?;
 
-    match instruction {
-        BandwidthPrepayInstruction::InitializeAccount => initialize_account(keyed_accounts),
-        BandwidthPrepayInstruction::Spend(amount) => spend(keyed_accounts, amount),
-        BandwidthPrepayInstruction::Refund => refund(keyed_accounts),
-    }
-    .map_err(|e| InstructionError::CustomError(e as u32))
+    // Reclaim needs the clock itself, at a fixed position
+    let (keyed_accounts, slot) = match instruction {
+        BandwidthPrepayInstruction::Reclaim => (keyed_accounts, None),
+        _ => split_clock(keyed_accounts),
+    };
+    check_accounts(program_id, keyed_accounts, &instruction)
+        .and_then(|()| match instruction {
+            BandwidthPrepayInstruction::InitializeAccount(terms) => {
+                initialize_account(program_id, keyed_accounts, terms, slot)
+            }
+            BandwidthPrepayInstruction::Spend {
+                amount,
+                total_bytes,
+            } => spend(keyed_accounts, amount, total_bytes, slot),
+            BandwidthPrepayInstruction::Refund => refund(keyed_accounts, slot),
+            BandwidthPrepayInstruction::Settle(receipt, signature) => {
+                settle(keyed_accounts, &receipt, &signature, slot)
+            }
+            BandwidthPrepayInstruction::Reclaim => reclaim(keyed_accounts),
+            BandwidthPrepayInstruction::TopUp(lamports) => top_up(keyed_accounts, lamports, slot),
+            BandwidthPrepayInstruction::Close => close(keyed_accounts, slot),
+            BandwidthPrepayInstruction::Migrate => migrate(keyed_accounts),
+            BandwidthPrepayInstruction::ChangeGatekeeper => {
+                change_gatekeeper(program_id, keyed_accounts, slot)
+            }
+            BandwidthPrepayInstruction::EndSession {
+                final_spend,
+                total_bytes,
+            } => end_session(keyed_accounts, final_spend, total_bytes, slot),
+            BandwidthPrepayInstruction::RegisterGatekeeper(info) => {
+                register_gatekeeper(keyed_accounts, info)
+            }
+            BandwidthPrepayInstruction::DeregisterGatekeeper => {
+                deregister_gatekeeper(keyed_accounts)
+            }
+            BandwidthPrepayInstruction::SpendBatch(spends) => {
+                spend_batch(keyed_accounts, &spends, slot)
+            }
+            BandwidthPrepayInstruction::PostBond => post_bond(keyed_accounts),
+            BandwidthPrepayInstruction::Dispute(receipt, signature) => {
+                dispute(program_id, keyed_accounts, &receipt, &signature, slot)
+            }
+        })
+        .map_err(|e| InstructionError::CustomError(e as u32))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bandwidth_prepay_history::{contract_history, ContractEvent};
     use crate::bandwidth_prepay_instruction;
+    use crate::bandwidth_prepay_state::{
+        destination_hash, DISPUTABLE_SESSIONS, DISPUTE_WINDOW_SLOTS, LEGACY_STATE_SIZE,
+    };
     use crate::id;
+    use bincode::serialize;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
     use solana_runtime::bank::Bank;
     use solana_runtime::bank_client::BankClient;
+    use solana_sdk::account::Account;
     use solana_sdk::client::SyncClient;
     use solana_sdk::genesis_block::create_genesis_block;
+    use solana_sdk::instruction::{AccountMeta, Instruction};
     use solana_sdk::message::Message;
     use solana_sdk::signature::{Keypair, KeypairUtil};
     use solana_sdk::system_instruction;
+    use solana_sdk::system_program;
+    use solana_sdk::transport::TransportError;
 
     fn create_bank(lamports: u64) -> (Bank, Keypair) {
         let (genesis_block, mint_keypair) = create_genesis_block(lamports);
@@ -106,6 +968,27 @@ mod tests {
         (bank, mint_keypair)
     }
 
+    fn process(
+        keyed_accounts: &mut [KeyedAccount],
+        instruction: &BandwidthPrepayInstruction,
+    ) -> Result<(), InstructionError> {
+        process_instruction(&id(), keyed_accounts, &serialize(instruction).unwrap())
+    }
+
+    /// Send `instructions` expecting them to fail, returning the program error
+    fn send_error(
+        bank_client: &BankClient,
+        keypairs: &[&Keypair],
+        instructions: Vec<Instruction>,
+    ) -> Option<BandwidthPrepayError> {
+        match bank_client.send_message(keypairs, Message::new(instructions)) {
+            Err(TransportError::TransactionError(e)) => {
+                BandwidthPrepayError::from_transaction_error(&e)
+            }
+            _ => None,
+        }
+    }
+
     #[test]
     fn test_bandwidth_prepay_initialize() {
         let (bank, alice_keypair) = create_bank(10_000);
@@ -116,12 +999,40 @@ mod tests {
         let gatekeeper = Keypair::new().pubkey();
         let provider = Keypair::new().pubkey();
 
+        // Payees must differ from the gatekeeper, the initiator and each other
+        let aliased_terms = [
+            (gatekeeper, ContractTerms::default()),
+            (alice_pubkey, ContractTerms::default()),
+            (
+                provider,
+                ContractTerms {
+                    recipients: vec![(provider, 5_000), (gatekeeper, 5_000)],
+                    ..ContractTerms::default()
+                },
+            ),
+        ];
+        for (provider, terms) in &aliased_terms {
+            let instructions = bandwidth_prepay_instruction::initialize(
+                &alice_pubkey,
+                &contract,
+                &gatekeeper,
+                provider,
+                500,
+                terms,
+            );
+            assert_eq!(
+                send_error(&bank_client, &[&alice_keypair], instructions),
+                Some(BandwidthPrepayError::AliasedAccounts)
+            );
+        }
+
         let instructions = bandwidth_prepay_instruction::initialize(
             &alice_pubkey,
             &contract,
             &gatekeeper,
             &provider,
             500,
+            &ContractTerms::default(),
         );
         let message = Message::new(instructions);
         bank_client
@@ -135,6 +1046,9 @@ mod tests {
         assert_eq!(state.gatekeeper_id, gatekeeper);
         assert_eq!(state.provider_id, provider);
         assert_eq!(state.initiator_id, alice_pubkey);
+        assert_eq!(state.lamports_per_kib, 1);
+        assert_eq!(state.max_bytes, None);
+        assert_eq!(state.status, ContractStatus::Initialized);
     }
 
     #[test]
@@ -154,6 +1068,7 @@ mod tests {
             &gatekeeper.pubkey(),
             &provider,
             500,
+            &ContractTerms::default(),
         );
         let message = Message::new(instructions);
         bank_client
@@ -168,16 +1083,25 @@ mod tests {
             .unwrap();
         assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 1);
 
-        let instruction =
-            bandwidth_prepay_instruction::spend(&gatekeeper.pubkey(), &contract, &provider, 100);
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            100,
+            100 * 1024,
+        );
         let message = Message::new(vec![instruction]);
         bank_client.send_message(&[&gatekeeper], message).unwrap();
         assert_eq!(bank_client.get_balance(&contract).unwrap(), 400);
         assert_eq!(bank_client.get_balance(&provider).unwrap(), 100);
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.total_spent, 100);
+        assert_eq!(state.total_bytes, 100 * 1024);
     }
 
     #[test]
-    fn test_bandwidth_prepay_refund() {
+    fn test_bandwidth_prepay_spend_data_cap() {
         let (bank, alice_keypair) = create_bank(10_000);
         let bank_client = BankClient::new(bank);
 
@@ -186,13 +1110,19 @@ mod tests {
         let provider = Keypair::new().pubkey();
         let gatekeeper = Keypair::new();
 
-        // Initialize contract
+        // Initialize contract allowing at most 100 KiB at 2 lamports per KiB
+        let terms = ContractTerms {
+            lamports_per_kib: 2,
+            max_bytes: Some(100 * 1024),
+            ..ContractTerms::default()
+        };
         let instructions = bandwidth_prepay_instruction::initialize(
             &alice_pubkey,
             &contract,
             &gatekeeper.pubkey(),
             &provider,
             500,
+            &terms,
         );
         let message = Message::new(instructions);
         bank_client
@@ -205,14 +1135,2906 @@ mod tests {
         bank_client
             .send_message(&[&alice_keypair], message)
             .unwrap();
-        assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 1);
 
-        let instruction =
-            bandwidth_prepay_instruction::refund(&gatekeeper.pubkey(), &contract, &alice_pubkey);
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            150,
+            75 * 1024,
+        );
         let message = Message::new(vec![instruction]);
         bank_client.send_message(&[&gatekeeper], message).unwrap();
-        assert_eq!(bank_client.get_balance(&contract).unwrap(), 0);
-        assert_eq!(bank_client.get_balance(&provider).unwrap(), 0);
-        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_999);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 150);
+
+        // Reporting more than 100 KiB exceeds the data cap
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            52,
+            101 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 350);
+
+        // 51 more lamports would pay for more than the reported 100 KiB
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            51,
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 350);
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            50,
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 300);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 200);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_spend_revenue_split() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let operator = Keypair::new().pubkey();
+        let platform = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+
+        // Shares that don't add up to the whole payment are rejected
+        let terms = ContractTerms {
+            recipients: vec![(provider, 5_000), (operator, 3_000), (platform, 1_000)],
+            ..ContractTerms::default()
+        };
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &terms,
+        );
+        let message = Message::new(instructions);
+        assert!(bank_client
+            .send_message(&[&alice_keypair], message)
+            .is_err());
+
+        // Initialize contract splitting payments 50/30/20
+        let terms = ContractTerms {
+            recipients: vec![(provider, 5_000), (operator, 3_000), (platform, 2_000)],
+            ..ContractTerms::default()
+        };
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &terms,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.recipients, terms.recipients);
+        assert_eq!(state.payees(), vec![provider, operator, platform]);
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Paying only the provider skips the other recipients
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            100,
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+
+        // Recipients must be passed in the order they were agreed
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[operator, provider, platform],
+            100,
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 500);
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &state.payees(),
+            100,
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 400);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 50);
+        assert_eq!(bank_client.get_balance(&operator).unwrap(), 30);
+        assert_eq!(bank_client.get_balance(&platform).unwrap(), 20);
+
+        // Rounding leftovers go to the first recipient
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &state.payees(),
+            7,
+            107 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 393);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 54);
+        assert_eq!(bank_client.get_balance(&operator).unwrap(), 32);
+        assert_eq!(bank_client.get_balance(&platform).unwrap(), 21);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_spend_gatekeeper_fee() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+
+        // Initialize contract paying the gatekeeper 2 lamports per spend
+        let terms = ContractTerms {
+            gatekeeper_fee: 2,
+            ..ContractTerms::default()
+        };
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &terms,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            400,
+            400 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 98);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 400);
+        assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 3);
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.total_spent, 400);
+
+        // The balance has to cover the fee as well as the payment
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            97,
+            497 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 98);
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            96,
+            496 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 496);
+        assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_spend_byte_counter_decreased() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            10,
+            20 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            1,
+            19 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 10);
+
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.total_spent, 10);
+        assert_eq!(state.total_bytes, 20 * 1024);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_refund() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 1);
+
+        let instruction =
+            bandwidth_prepay_instruction::refund(&gatekeeper.pubkey(), &contract, &alice_pubkey);
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_999);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_top_up() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new().pubkey();
+
+        // Topping up an account that was never initialized fails, and the
+        // transfer preceding the TopUp is rolled back with it
+        let instructions = bandwidth_prepay_instruction::top_up(&alice_pubkey, &contract, 100);
+        let message = Message::new(instructions);
+        assert!(bank_client
+            .send_message(&[&alice_keypair], message)
+            .is_err());
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 10_000);
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper,
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instructions = bandwidth_prepay_instruction::top_up(&alice_pubkey, &contract, 200);
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 700);
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_300);
+
+        // A TopUp must be backed by lamports that reached the contract
+        let mut instructions = bandwidth_prepay_instruction::top_up(&alice_pubkey, &contract, 0);
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], instructions.clone()),
+            Some(BandwidthPrepayError::ZeroAmount)
+        );
+        let instruction = instructions.pop().unwrap();
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], vec![instruction]),
+            Some(BandwidthPrepayError::ZeroAmount)
+        );
+        let mut instructions = bandwidth_prepay_instruction::top_up(&alice_pubkey, &contract, 50);
+        let instruction = instructions.pop().unwrap();
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], vec![instruction]),
+            Some(BandwidthPrepayError::BalanceTooLow)
+        );
+
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.deposit_count, 1);
+        assert_eq!(state.recorded_balance, 700);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_reclaim() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+
+        // Initialize contract that has already expired
+        let terms = ContractTerms {
+            expiry_slot: 0,
+            ..ContractTerms::default()
+        };
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &terms,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 1);
+
+        // Only the initiator may reclaim
+        let instruction = bandwidth_prepay_instruction::reclaim(&gatekeeper.pubkey(), &contract);
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 500);
+
+        let instruction = bandwidth_prepay_instruction::reclaim(&alice_pubkey, &contract);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_999);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_reclaim_not_expired() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new().pubkey();
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper,
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction = bandwidth_prepay_instruction::reclaim(&alice_pubkey, &contract);
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client
+            .send_message(&[&alice_keypair], message)
+            .is_err());
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 500);
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_500);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_close() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction =
+            bandwidth_prepay_instruction::close(&gatekeeper.pubkey(), &contract, &alice_pubkey);
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_999);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_closed_contract_replay() {
+        let initiator = Pubkey::new_rand();
+        let contract = Pubkey::new_rand();
+        let gatekeeper = Pubkey::new_rand();
+        let provider = Pubkey::new_rand();
+        let mut initiator_account = Account::new(100, 0, &system_program::id());
+        let mut contract_account = Account::new(500, BandwidthPrepayState::max_size(), &id());
+        let mut gatekeeper_account = Account::new(1, 0, &system_program::id());
+        let mut provider_account = Account::new(0, 0, &system_program::id());
+
+        let initialize = BandwidthPrepayInstruction::InitializeAccount(ContractTerms::default());
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&gatekeeper, false, &mut gatekeeper_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+        ];
+        assert_eq!(process(&mut keyed_accounts, &initialize), Ok(()));
+
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&initiator, false, &mut initiator_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &BandwidthPrepayInstruction::Close),
+            Ok(())
+        );
+        assert_eq!(contract_account.lamports, 0);
+        assert_eq!(initiator_account.lamports, 600);
+        let state = BandwidthPrepayState::deserialize(&contract_account.data).unwrap();
+        assert_eq!(state.status, ContractStatus::Closed);
+
+        // Lamports sent to a closed contract can't be moved by any instruction
+        contract_account.lamports = 10;
+        let closed = Err(InstructionError::CustomError(
+            BandwidthPrepayError::ContractClosed as u32,
+        ));
+
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&gatekeeper, false, &mut gatekeeper_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+        ];
+        assert_eq!(process(&mut keyed_accounts, &initialize), closed);
+
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+        ];
+        let spend = BandwidthPrepayInstruction::Spend {
+            amount: 1,
+            total_bytes: 1024,
+        };
+        assert_eq!(process(&mut keyed_accounts, &spend), closed);
+
+        for instruction in &[
+            BandwidthPrepayInstruction::Refund,
+            BandwidthPrepayInstruction::Close,
+        ] {
+            let mut keyed_accounts = [
+                KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+                KeyedAccount::new(&contract, false, &mut contract_account),
+                KeyedAccount::new(&initiator, false, &mut initiator_account),
+            ];
+            assert_eq!(process(&mut keyed_accounts, instruction), closed);
+        }
+
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &BandwidthPrepayInstruction::TopUp(10)),
+            closed
+        );
+
+        assert_eq!(contract_account.lamports, 10);
+        assert_eq!(initiator_account.lamports, 600);
+        assert_eq!(provider_account.lamports, 0);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_settle() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let receipt = UsageReceipt::new(&contract, 0, 0, 300 * 1024, 300);
+        let signature = receipt.sign(&alice_keypair);
+
+        // Receipts signed by anyone other than the initiator are rejected
+        let forged_signature = receipt.sign(&gatekeeper);
+        let instruction = bandwidth_prepay_instruction::settle(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            &receipt,
+            &forged_signature,
+        );
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 500);
+
+        // So are receipts for another contract that had the same address
+        let reused = UsageReceipt::new(&contract, 1, 0, 300 * 1024, 300);
+        let instruction = bandwidth_prepay_instruction::settle(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            &reused,
+            &reused.sign(&alice_keypair),
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::ReceiptContractMismatch)
+        );
+
+        let instruction = bandwidth_prepay_instruction::settle(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            &receipt,
+            &signature,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 200);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 300);
+
+        // Only the difference from what was already paid is charged
+        let receipt = UsageReceipt::new(&contract, 0, 0, 400 * 1024, 400);
+        let signature = receipt.sign(&alice_keypair);
+        let instruction = bandwidth_prepay_instruction::settle(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            &receipt,
+            &signature,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 100);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 400);
+
+        // An older receipt can't be redeemed again
+        let receipt = UsageReceipt::new(&contract, 0, 0, 350 * 1024, 350);
+        let signature = receipt.sign(&alice_keypair);
+        let instruction = bandwidth_prepay_instruction::settle(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            &receipt,
+            &signature,
+        );
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.total_spent, 400);
+        assert_eq!(state.total_bytes, 400 * 1024);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_migrate() {
+        let initiator = Pubkey::new_rand();
+        let legacy = Pubkey::new_rand();
+        let contract = Pubkey::new_rand();
+        let gatekeeper = Pubkey::new_rand();
+        let provider = Pubkey::new_rand();
+        let mut initiator_account = Account::new(100, 0, &system_program::id());
+        let mut gatekeeper_account = Account::new(1, 0, &system_program::id());
+        let mut provider_account = Account::new(0, 0, &system_program::id());
+
+        // An account written by the unversioned program holds only three pubkeys
+        let mut legacy_account = Account::new(500, LEGACY_STATE_SIZE, &id());
+        let legacy_data = serialize(&(gatekeeper, provider, initiator)).unwrap();
+        legacy_account.data.copy_from_slice(&legacy_data);
+        let mut contract_account = Account::new(1, BandwidthPrepayState::max_size(), &id());
+
+        let spend = BandwidthPrepayInstruction::Spend {
+            amount: 10,
+            total_bytes: 10 * 1024,
+        };
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&legacy, false, &mut legacy_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &spend),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::AccountNeedsMigration as u32
+            ))
+        );
+
+        // Only the contract's initiator or gatekeeper can migrate it
+        let stranger = Pubkey::new_rand();
+        let mut stranger_account = Account::new(1, 0, &system_program::id());
+        let mut keyed_accounts = [
+            KeyedAccount::new(&stranger, true, &mut stranger_account),
+            KeyedAccount::new(&legacy, false, &mut legacy_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &BandwidthPrepayInstruction::Migrate),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::NotSignedByContractParty as u32
+            ))
+        );
+
+        // The new account must have room for the current layout
+        let small = Pubkey::new_rand();
+        let mut small_account = Account::new(1, LEGACY_STATE_SIZE, &id());
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&legacy, false, &mut legacy_account),
+            KeyedAccount::new(&small, false, &mut small_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &BandwidthPrepayInstruction::Migrate),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::UserdataTooSmall as u32
+            ))
+        );
+
+        // And must belong to the program
+        let unowned = Pubkey::new_rand();
+        let mut unowned_account =
+            Account::new(1, BandwidthPrepayState::max_size(), &system_program::id());
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&legacy, false, &mut legacy_account),
+            KeyedAccount::new(&unowned, false, &mut unowned_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &BandwidthPrepayInstruction::Migrate),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::InvalidAccountOwner as u32
+            ))
+        );
+
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&legacy, false, &mut legacy_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &BandwidthPrepayInstruction::Migrate),
+            Ok(())
+        );
+        assert_eq!(contract_account.lamports, 501);
+        assert_eq!(legacy_account.lamports, 0);
+        assert_eq!(legacy_account.data, vec![0; LEGACY_STATE_SIZE]);
+        let state = BandwidthPrepayState::deserialize(&contract_account.data).unwrap();
+        assert_eq!(state.gatekeeper_id, gatekeeper);
+        assert_eq!(state.provider_id, provider);
+        assert_eq!(state.initiator_id, initiator);
+        assert_eq!(state.status, ContractStatus::Initialized);
+        assert_eq!(state.recorded_balance, 501);
+
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&legacy, false, &mut legacy_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &BandwidthPrepayInstruction::Migrate),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::AlreadyMigrated as u32
+            ))
+        );
+
+        // The emptied legacy account can't be migrated over another contract
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&legacy, false, &mut legacy_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &BandwidthPrepayInstruction::Migrate),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::NotSignedByContractParty as u32
+            ))
+        );
+
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+        ];
+        assert_eq!(process(&mut keyed_accounts, &spend), Ok(()));
+        assert_eq!(contract_account.lamports, 491);
+        assert_eq!(provider_account.lamports, 10);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_malformed_accounts() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let bob = Keypair::new().pubkey();
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let spend = BandwidthPrepayInstruction::Spend {
+            amount: 10,
+            total_bytes: 10 * 1024,
+        };
+
+        // Short account lists
+        let instruction = Instruction::new(id(), &BandwidthPrepayInstruction::Refund, vec![]);
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], vec![instruction]),
+            Some(BandwidthPrepayError::NotEnoughAccounts)
+        );
+        let account_metas = vec![
+            AccountMeta::new(gatekeeper.pubkey(), true),
+            AccountMeta::new(contract, false),
+        ];
+        let instruction = Instruction::new(id(), &spend, account_metas);
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::NotEnoughAccounts)
+        );
+
+        // The trailing clock doesn't count towards the accounts required
+        let account_metas = vec![
+            AccountMeta::new(alice_pubkey, true),
+            AccountMeta::new(contract, false),
+            AccountMeta::new(gatekeeper.pubkey(), false),
+            AccountMeta::new_credit_only(clock::id(), false),
+        ];
+        let initialize = BandwidthPrepayInstruction::InitializeAccount(ContractTerms::default());
+        let instruction = Instruction::new(id(), &initialize, account_metas);
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], vec![instruction]),
+            Some(BandwidthPrepayError::NotEnoughAccounts)
+        );
+        let receipt = UsageReceipt::new(&contract, 0, 0, 0, 0);
+        let signature = receipt.sign(&alice_keypair);
+        let instructions = [
+            BandwidthPrepayInstruction::Refund,
+            BandwidthPrepayInstruction::Close,
+            BandwidthPrepayInstruction::ChangeGatekeeper,
+            BandwidthPrepayInstruction::Dispute(receipt.clone(), signature),
+            BandwidthPrepayInstruction::Settle(receipt, signature),
+            BandwidthPrepayInstruction::EndSession {
+                final_spend: 10,
+                total_bytes: 10 * 1024,
+            },
+            spend.clone(),
+        ];
+        for instruction in &instructions {
+            let account_metas = vec![
+                AccountMeta::new(gatekeeper.pubkey(), true),
+                AccountMeta::new(contract, false),
+                AccountMeta::new_credit_only(clock::id(), false),
+            ];
+            let instruction = Instruction::new(id(), instruction, account_metas);
+            assert_eq!(
+                send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+                Some(BandwidthPrepayError::NotEnoughAccounts)
+            );
+        }
+
+        // Reordered account lists
+        let account_metas = vec![
+            AccountMeta::new(gatekeeper.pubkey(), true),
+            AccountMeta::new(provider, false),
+            AccountMeta::new(contract, false),
+        ];
+        let instruction = Instruction::new(id(), &spend, account_metas);
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::InvalidAccountOwner)
+        );
+        let account_metas = vec![
+            AccountMeta::new(gatekeeper.pubkey(), true),
+            AccountMeta::new(alice_pubkey, false),
+            AccountMeta::new(contract, false),
+        ];
+        let instruction = Instruction::new(id(), &BandwidthPrepayInstruction::Close, account_metas);
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::InvalidAccountOwner)
+        );
+
+        // A contract-sized account owned by another program
+        let impostor = Keypair::new().pubkey();
+        let space = BandwidthPrepayState::max_size() as u64;
+        let instruction = system_instruction::create_account(
+            &alice_pubkey,
+            &impostor,
+            500,
+            space,
+            &system_program::id(),
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &impostor,
+            &[provider],
+            10,
+            10 * 1024,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::InvalidAccountOwner)
+        );
+        let instruction =
+            bandwidth_prepay_instruction::close(&gatekeeper.pubkey(), &impostor, &alice_pubkey);
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::InvalidAccountOwner)
+        );
+        assert_eq!(bank_client.get_balance(&impostor).unwrap(), 500);
+
+        // Missing signatures
+        let account_metas = vec![
+            AccountMeta::new(gatekeeper.pubkey(), false),
+            AccountMeta::new(contract, false),
+            AccountMeta::new(provider, false),
+        ];
+        let instruction = Instruction::new(id(), &spend, account_metas);
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], vec![instruction]),
+            Some(BandwidthPrepayError::NotSignedByGatekeeper)
+        );
+        let account_metas = vec![
+            AccountMeta::new(bob, false),
+            AccountMeta::new(contract, false),
+        ];
+        let instruction =
+            Instruction::new(id(), &BandwidthPrepayInstruction::TopUp(0), account_metas);
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], vec![instruction]),
+            Some(BandwidthPrepayError::NotSignedByFunder)
+        );
+        let unsigned_contract = Keypair::new().pubkey();
+        let account_metas = vec![
+            AccountMeta::new(bob, false),
+            AccountMeta::new(unsigned_contract, false),
+            AccountMeta::new(gatekeeper.pubkey(), false),
+            AccountMeta::new(provider, false),
+        ];
+        let instructions = vec![
+            system_instruction::create_account(
+                &alice_pubkey,
+                &unsigned_contract,
+                500,
+                space,
+                &id(),
+            ),
+            Instruction::new(
+                id(),
+                &BandwidthPrepayInstruction::InitializeAccount(ContractTerms::default()),
+                account_metas,
+            ),
+        ];
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], instructions),
+            Some(BandwidthPrepayError::NotSignedByInitiator)
+        );
+
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 500);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_checked_arithmetic() {
+        let initiator = Pubkey::new_rand();
+        let contract = Pubkey::new_rand();
+        let gatekeeper = Pubkey::new_rand();
+        let provider = Pubkey::new_rand();
+        let mut initiator_account = Account::new(u64::max_value(), 0, &system_program::id());
+        let mut contract_account = Account::new(500, BandwidthPrepayState::max_size(), &id());
+        let mut gatekeeper_account = Account::new(1, 0, &system_program::id());
+        let mut provider_account = Account::new(u64::max_value(), 0, &system_program::id());
+
+        // The contract can't be one of its own recipients
+        let initialize = BandwidthPrepayInstruction::InitializeAccount(ContractTerms {
+            recipients: vec![(provider, 5_000), (contract, 5_000)],
+            ..ContractTerms::default()
+        });
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&gatekeeper, false, &mut gatekeeper_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &initialize),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::AliasedAccounts as u32
+            ))
+        );
+
+        let initialize = BandwidthPrepayInstruction::InitializeAccount(ContractTerms::default());
+        assert_eq!(process(&mut keyed_accounts, &initialize), Ok(()));
+
+        let spend = |amount| BandwidthPrepayInstruction::Spend {
+            amount,
+            total_bytes: 100 * 1024,
+        };
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &spend(0)),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::ZeroAmount as u32
+            ))
+        );
+        assert_eq!(
+            process(&mut keyed_accounts, &spend(10)),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::Overflow as u32
+            ))
+        );
+
+        // Passing the contract as its own provider is rejected
+        let mut alias_account = contract_account.clone();
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&contract, false, &mut alias_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &spend(10)),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::AliasedAccounts as u32
+            ))
+        );
+
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&initiator, false, &mut initiator_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &BandwidthPrepayInstruction::Refund),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::Overflow as u32
+            ))
+        );
+    }
+
+    /// Send a random instruction sequence against a contract, checking after
+    /// every transaction that no lamports were created or destroyed
+    fn check_lamports_conserved(rng: &mut StdRng, steps: usize) {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let operator = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+
+        let recipients = if rng.gen() {
+            vec![(provider, 7_000), (operator, 3_000)]
+        } else {
+            vec![]
+        };
+        let terms = ContractTerms {
+            lamports_per_kib: rng.gen_range(1, 4),
+            gatekeeper_fee: rng.gen_range(0, 3),
+            recipients,
+            ..ContractTerms::default()
+        };
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            1_000,
+            &terms,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let payees = BandwidthPrepayState::deserialize(&account)
+            .unwrap()
+            .payees();
+        let accounts = [
+            alice_pubkey,
+            contract,
+            gatekeeper.pubkey(),
+            provider,
+            operator,
+        ];
+        let total_lamports = || -> u64 {
+            accounts
+                .iter()
+                .map(|pubkey| bank_client.get_balance(pubkey).unwrap())
+                .sum()
+        };
+        assert_eq!(total_lamports(), 10_000);
+
+        let mut total_bytes = 0;
+        for _ in 0..steps {
+            total_bytes += rng.gen_range(0, 64 * 1024);
+            let reported_bytes = total_bytes - rng.gen_range(0, 1024).min(total_bytes);
+            let (instruction, signer) = match rng.gen_range(0, 8) {
+                0..=2 => (
+                    bandwidth_prepay_instruction::spend(
+                        &gatekeeper.pubkey(),
+                        &contract,
+                        &payees,
+                        rng.gen_range(0, 100),
+                        reported_bytes,
+                    ),
+                    &gatekeeper,
+                ),
+                3 => (
+                    bandwidth_prepay_instruction::spend(
+                        &gatekeeper.pubkey(),
+                        &contract,
+                        &[contract],
+                        rng.gen_range(0, 100),
+                        reported_bytes,
+                    ),
+                    &gatekeeper,
+                ),
+                4 => {
+                    let receipt =
+                        UsageReceipt::new(&contract, 0, 0, reported_bytes, rng.gen_range(0, 1_000));
+                    let signature = receipt.sign(&alice_keypair);
+                    (
+                        bandwidth_prepay_instruction::settle(
+                            &gatekeeper.pubkey(),
+                            &contract,
+                            &payees,
+                            &receipt,
+                            &signature,
+                        ),
+                        &gatekeeper,
+                    )
+                }
+                5 | 6 => {
+                    let instructions = bandwidth_prepay_instruction::top_up(
+                        &alice_pubkey,
+                        &contract,
+                        rng.gen_range(0, 200),
+                    );
+                    let message = Message::new(instructions);
+                    let _ = bank_client.send_message(&[&alice_keypair], message);
+                    assert_eq!(total_lamports(), 10_000);
+                    continue;
+                }
+                _ => (
+                    bandwidth_prepay_instruction::refund(
+                        &gatekeeper.pubkey(),
+                        &contract,
+                        &alice_pubkey,
+                    ),
+                    &gatekeeper,
+                ),
+            };
+            let message = Message::new(vec![instruction]);
+            let _ = bank_client.send_message(&[signer], message);
+            assert_eq!(total_lamports(), 10_000);
+        }
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_lamports_conserved() {
+        for seed in 0..16 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            check_lamports_conserved(&mut rng, 64);
+        }
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_time_billing() {
+        let initiator = Pubkey::new_rand();
+        let contract = Pubkey::new_rand();
+        let gatekeeper = Pubkey::new_rand();
+        let provider = Pubkey::new_rand();
+        let mut initiator_account = Account::new(0, 0, &system_program::id());
+        let mut contract_account = Account::new(500, BandwidthPrepayState::max_size(), &id());
+        let mut gatekeeper_account = Account::new(1, 0, &system_program::id());
+        let mut provider_account = Account::new(0, 0, &system_program::id());
+        let clock_account = |slot| clock::create_account(1, slot, 0, 0, 0);
+
+        let initialize = BandwidthPrepayInstruction::InitializeAccount(ContractTerms {
+            lamports_per_slot: 2,
+            ..ContractTerms::default()
+        });
+        let mut start_clock = clock_account(10);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&gatekeeper, false, &mut gatekeeper_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+            KeyedAccount::new(&clock::id(), false, &mut start_clock),
+        ];
+        assert_eq!(process(&mut keyed_accounts, &initialize), Ok(()));
+        let state = BandwidthPrepayState::deserialize(&contract_account.data).unwrap();
+        assert_eq!(state.lamports_per_slot, 2);
+        assert_eq!(state.session_start_slot, None);
+
+        // The session's clock starts with its first charge, not before
+        let spend = |amount, total_bytes| BandwidthPrepayInstruction::Spend {
+            amount,
+            total_bytes,
+        };
+        let mut first_clock = clock_account(15);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+            KeyedAccount::new(&clock::id(), false, &mut first_clock),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &spend(2, 1024)),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::SpendExceedsRate as u32
+            ))
+        );
+        assert_eq!(process(&mut keyed_accounts, &spend(1, 1024)), Ok(()));
+        let state = BandwidthPrepayState::deserialize(&contract_account.data).unwrap();
+        assert_eq!(state.session_start_slot, Some(15));
+
+        // Then the session is charged for the slots it has been open
+        let mut spend_clock = clock_account(20);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+            KeyedAccount::new(&clock::id(), false, &mut spend_clock),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &spend(11, 1024)),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::SpendExceedsRate as u32
+            ))
+        );
+        assert_eq!(process(&mut keyed_accounts, &spend(10, 1024)), Ok(()));
+
+        // Without a clock only the bytes can be charged for
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &spend(1, 1024)),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::SpendExceedsRate as u32
+            ))
+        );
+
+        // Ending the session stops its clock
+        let end_session = BandwidthPrepayInstruction::EndSession {
+            final_spend: 4,
+            total_bytes: 1024,
+        };
+        let mut end_clock = clock_account(22);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+            KeyedAccount::new(&clock::id(), false, &mut end_clock),
+        ];
+        assert_eq!(process(&mut keyed_accounts, &end_session), Ok(()));
+        let state = BandwidthPrepayState::deserialize(&contract_account.data).unwrap();
+        assert_eq!(state.ended_sessions[0].spent, 15);
+        assert_eq!(state.ended_sessions[0].slots, 7);
+        assert_eq!(state.session_start_slot, None);
+
+        // The slots between sessions are not charged for
+        let mut next_clock = clock_account(40);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+            KeyedAccount::new(&clock::id(), false, &mut next_clock),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &spend(2, 1024)),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::SpendExceedsRate as u32
+            ))
+        );
+        assert_eq!(process(&mut keyed_accounts, &spend(1, 1024)), Ok(()));
+        let state = BandwidthPrepayState::deserialize(&contract_account.data).unwrap();
+        assert_eq!(state.session_start_slot, Some(40));
+        assert_eq!(provider_account.lamports, 16);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_change_gatekeeper() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let new_gatekeeper = Keypair::new();
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper accounts exist
+        for gatekeeper_id in &[gatekeeper.pubkey(), new_gatekeeper.pubkey()] {
+            let instruction = system_instruction::transfer(&alice_pubkey, gatekeeper_id, 1);
+            let message = Message::new(vec![instruction]);
+            bank_client
+                .send_message(&[&alice_keypair], message)
+                .unwrap();
+        }
+
+        // The contract can't be handed over to one of its payees
+        let instruction = bandwidth_prepay_instruction::change_gatekeeper(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            None,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::AliasedAccounts)
+        );
+
+        // Only the current gatekeeper can hand the contract over
+        let instruction = bandwidth_prepay_instruction::change_gatekeeper(
+            &new_gatekeeper.pubkey(),
+            &contract,
+            &new_gatekeeper.pubkey(),
+            None,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&new_gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::NoGatekeeperAccount)
+        );
+
+        let instruction = bandwidth_prepay_instruction::change_gatekeeper(
+            &gatekeeper.pubkey(),
+            &contract,
+            &new_gatekeeper.pubkey(),
+            None,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.gatekeeper_id, new_gatekeeper.pubkey());
+
+        // The old key can no longer spend, the new one can
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            10,
+            10 * 1024,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::NoGatekeeperAccount)
+        );
+        let instruction = bandwidth_prepay_instruction::spend(
+            &new_gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            10,
+            10 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&new_gatekeeper], message)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 10);
+
+        // A session the gatekeeper has charged for must end before a handover
+        let instruction = bandwidth_prepay_instruction::change_gatekeeper(
+            &new_gatekeeper.pubkey(),
+            &contract,
+            &gatekeeper.pubkey(),
+            None,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&new_gatekeeper], vec![instruction.clone()]),
+            Some(BandwidthPrepayError::SessionOpen)
+        );
+        let instructions = vec![
+            bandwidth_prepay_instruction::end_session(
+                &new_gatekeeper.pubkey(),
+                &contract,
+                &[provider],
+                5,
+                15 * 1024,
+            ),
+            instruction,
+        ];
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&new_gatekeeper], message)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 15);
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.gatekeeper_id, gatekeeper.pubkey());
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_change_gatekeeper_requires_initiator() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let new_gatekeeper = Keypair::new().pubkey();
+        let mallory = Keypair::new();
+
+        // Initialize contract that can only be handed over with the initiator's consent
+        let terms = ContractTerms {
+            rotation_requires_initiator: true,
+            ..ContractTerms::default()
+        };
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &terms,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction = bandwidth_prepay_instruction::change_gatekeeper(
+            &gatekeeper.pubkey(),
+            &contract,
+            &new_gatekeeper,
+            None,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::NotSignedByInitiator)
+        );
+
+        let instruction = bandwidth_prepay_instruction::change_gatekeeper(
+            &gatekeeper.pubkey(),
+            &contract,
+            &new_gatekeeper,
+            Some(&mallory.pubkey()),
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper, &mallory], vec![instruction]),
+            Some(BandwidthPrepayError::NoInitiatorAccount)
+        );
+
+        let instruction = bandwidth_prepay_instruction::change_gatekeeper(
+            &gatekeeper.pubkey(),
+            &contract,
+            &new_gatekeeper,
+            Some(&alice_pubkey),
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&gatekeeper, &alice_keypair], message)
+            .unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.gatekeeper_id, new_gatekeeper);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_end_session() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            100,
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+
+        // The final spend is held to the agreed rate like any other
+        let instruction = bandwidth_prepay_instruction::end_session(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            51,
+            150 * 1024,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::SpendExceedsRate)
+        );
+
+        let instruction = bandwidth_prepay_instruction::end_session(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            50,
+            150 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 350);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 150);
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.status, ContractStatus::Initialized);
+        assert_eq!(state.session_count, 1);
+        assert_eq!(state.total_spent, 0);
+        assert_eq!(state.total_bytes, 0);
+
+        // Receipts from the previous session can't be replayed
+        let receipt = UsageReceipt::new(&contract, 0, 0, 150 * 1024, 150);
+        let signature = receipt.sign(&alice_keypair);
+        let instruction = bandwidth_prepay_instruction::settle(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            &receipt,
+            &signature,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::StaleReceipt)
+        );
+
+        // The next session counts from zero and can end without a final spend
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            10,
+            10 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        let instruction = bandwidth_prepay_instruction::end_session(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            0,
+            10 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 340);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 160);
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.session_count, 2);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_end_session_keeps_data_cap() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+
+        // Initialize contract allowing 100 KiB in all, with a fee per spend
+        let terms = ContractTerms {
+            max_bytes: Some(100 * 1024),
+            gatekeeper_fee: 2,
+            ..ContractTerms::default()
+        };
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &terms,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            60,
+            60 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 3);
+
+        // Ending a session without a final spend pays no fee
+        let instruction = bandwidth_prepay_instruction::end_session(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            0,
+            60 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 3);
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 438);
+
+        // The data cap counts the bytes of earlier sessions too
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            10,
+            50 * 1024,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::DataCapExceeded)
+        );
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            40,
+            40 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.total_bytes, 40 * 1024);
+        assert_eq!(state.lifetime_bytes, 100 * 1024);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_initialize_derived() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+
+        let (contract, instructions) = bandwidth_prepay_instruction::initialize_derived(
+            &alice_pubkey,
+            &gatekeeper,
+            &provider,
+            7,
+            500,
+            &ContractTerms::default(),
+        );
+        assert_eq!(
+            contract,
+            bandwidth_prepay_instruction::contract_address(
+                &alice_pubkey,
+                &gatekeeper,
+                &provider,
+                7
+            )
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 500);
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.initiator_id, alice_pubkey);
+        assert_eq!(state.nonce, Some(7));
+
+        // Claiming a nonce the address wasn't derived from is rejected
+        let contract = bandwidth_prepay_instruction::contract_address(
+            &alice_pubkey,
+            &gatekeeper,
+            &provider,
+            9,
+        );
+        let terms = ContractTerms {
+            nonce: Some(8),
+            ..ContractTerms::default()
+        };
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper,
+            &provider,
+            500,
+            &terms,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], instructions),
+            Some(BandwidthPrepayError::InvalidContractAddress)
+        );
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_allowed_destinations() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+
+        // Committing to more destinations than the state can hold is rejected
+        let terms = ContractTerms {
+            allowed_destinations: (0..=MAX_DESTINATIONS)
+                .map(|port| destination_hash(&format!("127.0.0.1:{}", port)))
+                .collect(),
+            ..ContractTerms::default()
+        };
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper,
+            &provider,
+            500,
+            &terms,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], instructions),
+            Some(BandwidthPrepayError::TooManyDestinations)
+        );
+
+        let terms = ContractTerms {
+            allowed_destinations: vec![destination_hash("127.0.0.1:1234")],
+            ..ContractTerms::default()
+        };
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper,
+            &provider,
+            500,
+            &terms,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.allowed_destinations, terms.allowed_destinations);
+        assert!(state.allows_destination("127.0.0.1:1234"));
+        assert!(!state.allows_destination("127.0.0.1:4321"));
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_register_gatekeeper() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Keypair::new();
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 100);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let registration = registration_address(&gatekeeper.pubkey());
+
+        let info = GatekeeperInfo {
+            endpoint: "127.0.0.1:8122".to_string(),
+            lamports_per_kib: 2,
+            destinations: vec!["127.0.0.1:1234".to_string()],
+        };
+        let instructions =
+            bandwidth_prepay_instruction::register_gatekeeper(&gatekeeper.pubkey(), 10, &info);
+        let message = Message::new(instructions);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&registration).unwrap(), 10);
+        let account = bank_client
+            .get_account_data(&registration)
+            .unwrap()
+            .unwrap();
+        let state = GatekeeperRegistration::deserialize(&account).unwrap();
+        assert_eq!(state.gatekeeper_id, gatekeeper.pubkey());
+        assert_eq!(state.info, info);
+
+        // Update the published price
+        let info = GatekeeperInfo {
+            lamports_per_kib: 3,
+            ..info
+        };
+        let instruction =
+            bandwidth_prepay_instruction::update_registration(&gatekeeper.pubkey(), &info);
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        let account = bank_client
+            .get_account_data(&registration)
+            .unwrap()
+            .unwrap();
+        let state = GatekeeperRegistration::deserialize(&account).unwrap();
+        assert_eq!(state.info.lamports_per_kib, 3);
+
+        // Nobody else can write the gatekeeper's registration
+        let mallory = Keypair::new();
+        let instruction = system_instruction::transfer(&alice_pubkey, &mallory.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let instruction = Instruction::new(
+            id(),
+            &BandwidthPrepayInstruction::RegisterGatekeeper(GatekeeperInfo::default()),
+            vec![
+                AccountMeta::new(mallory.pubkey(), true),
+                AccountMeta::new(registration, false),
+            ],
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&mallory], vec![instruction]),
+            Some(BandwidthPrepayError::InvalidRegistrationAccount)
+        );
+
+        let instruction = bandwidth_prepay_instruction::deregister_gatekeeper(&gatekeeper.pubkey());
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&registration).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_initialize_registered_gatekeeper() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let terms = ContractTerms {
+            require_registered_gatekeeper: true,
+            ..ContractTerms::default()
+        };
+
+        // An unregistered gatekeeper is refused
+        let contract = Keypair::new().pubkey();
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &terms,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], instructions),
+            Some(BandwidthPrepayError::GatekeeperNotRegistered)
+        );
+
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 10);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let instructions = bandwidth_prepay_instruction::register_gatekeeper(
+            &gatekeeper.pubkey(),
+            1,
+            &GatekeeperInfo::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &terms,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.gatekeeper_id, gatekeeper.pubkey());
+        let initialized_contract = contract;
+
+        // Another gatekeeper's registration doesn't count
+        let contract = Keypair::new().pubkey();
+        let other_gatekeeper = Keypair::new().pubkey();
+        let mut instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &other_gatekeeper,
+            &provider,
+            500,
+            &terms,
+        );
+        instructions[1].accounts[4] =
+            AccountMeta::new(registration_address(&gatekeeper.pubkey()), false);
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], instructions),
+            Some(BandwidthPrepayError::GatekeeperNotRegistered)
+        );
+
+        // The contract can only be handed to another registered gatekeeper
+        let contract = initialized_contract;
+        let instruction = bandwidth_prepay_instruction::change_gatekeeper(
+            &gatekeeper.pubkey(),
+            &contract,
+            &other_gatekeeper,
+            None,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::GatekeeperNotRegistered)
+        );
+
+        let new_gatekeeper = Keypair::new();
+        let instruction = system_instruction::transfer(&alice_pubkey, &new_gatekeeper.pubkey(), 10);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let instructions = bandwidth_prepay_instruction::register_gatekeeper(
+            &new_gatekeeper.pubkey(),
+            1,
+            &GatekeeperInfo::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&new_gatekeeper], message)
+            .unwrap();
+        let instruction = bandwidth_prepay_instruction::change_gatekeeper(
+            &gatekeeper.pubkey(),
+            &contract,
+            &new_gatekeeper.pubkey(),
+            None,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.gatekeeper_id, new_gatekeeper.pubkey());
+        assert!(state.require_registered_gatekeeper);
+    }
+
+    /// Initialize a contract with a bonded gatekeeper, returning the contract
+    fn start_bonded_contract(
+        bank_client: &BankClient,
+        alice_keypair: &Keypair,
+        gatekeeper: &Keypair,
+        provider: &Pubkey,
+        bond: u64,
+    ) -> Pubkey {
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client.send_message(&[alice_keypair], message).unwrap();
+
+        let instruction =
+            system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), bond + 1);
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[alice_keypair], message).unwrap();
+        let instructions = bandwidth_prepay_instruction::create_bond(&gatekeeper.pubkey(), bond);
+        let message = Message::new(instructions);
+        bank_client.send_message(&[gatekeeper], message).unwrap();
+        contract
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_post_bond() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let gatekeeper = Keypair::new();
+        let provider = Keypair::new().pubkey();
+        start_bonded_contract(&bank_client, &alice_keypair, &gatekeeper, &provider, 50);
+        let bond = bond_address(&gatekeeper.pubkey());
+        assert_eq!(bank_client.get_balance(&bond).unwrap(), 50);
+        let account = bank_client.get_account_data(&bond).unwrap().unwrap();
+        let state = GatekeeperBond::deserialize(&account).unwrap();
+        assert_eq!(state.gatekeeper_id, gatekeeper.pubkey());
+        assert_eq!(state.total_slashed, 0);
+
+        // Add to the bond
+        let instruction =
+            system_instruction::transfer(&alice_keypair.pubkey(), &gatekeeper.pubkey(), 25);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let instructions = bandwidth_prepay_instruction::add_to_bond(&gatekeeper.pubkey(), 25);
+        let message = Message::new(instructions);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&bond).unwrap(), 75);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_dispute_honest_settlement() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Keypair::new();
+        let provider = Keypair::new().pubkey();
+        let contract =
+            start_bonded_contract(&bank_client, &alice_keypair, &gatekeeper, &provider, 50);
+
+        // Settle the initiator's receipt and end the session where it left off
+        let receipt = UsageReceipt::new(&contract, 0, 0, 100 * 1024, 100);
+        let signature = receipt.sign(&alice_keypair);
+        let instructions = vec![
+            bandwidth_prepay_instruction::settle(
+                &gatekeeper.pubkey(),
+                &contract,
+                &[provider],
+                &receipt,
+                &signature,
+            ),
+            bandwidth_prepay_instruction::end_session(
+                &gatekeeper.pubkey(),
+                &contract,
+                &[provider],
+                0,
+                100 * 1024,
+            ),
+        ];
+        let message = Message::new(instructions);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 100);
+
+        // The settled receipt outweighs an older one the initiator disputes with
+        let receipt = UsageReceipt::new(&contract, 0, 0, 40 * 1024, 40);
+        let signature = receipt.sign(&alice_keypair);
+        let instruction = bandwidth_prepay_instruction::dispute(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &receipt,
+            &signature,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], vec![instruction]),
+            Some(BandwidthPrepayError::NoOvercharge)
+        );
+        let bond = bond_address(&gatekeeper.pubkey());
+        assert_eq!(bank_client.get_balance(&bond).unwrap(), 50);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_dispute_overcharge() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Keypair::new();
+        let provider = Keypair::new().pubkey();
+        let contract =
+            start_bonded_contract(&bank_client, &alice_keypair, &gatekeeper, &provider, 100);
+
+        // The initiator signs for 40 KiB but the gatekeeper charges for
+        // 100 KiB, then ends another session to try to bury the first
+        let receipt = UsageReceipt::new(&contract, 0, 0, 40 * 1024, 40);
+        let signature = receipt.sign(&alice_keypair);
+        let instructions = vec![
+            bandwidth_prepay_instruction::end_session(
+                &gatekeeper.pubkey(),
+                &contract,
+                &[provider],
+                100,
+                100 * 1024,
+            ),
+            bandwidth_prepay_instruction::end_session(
+                &gatekeeper.pubkey(),
+                &contract,
+                &[provider],
+                0,
+                0,
+            ),
+        ];
+        let message = Message::new(instructions);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_399);
+
+        let instruction = bandwidth_prepay_instruction::dispute(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &receipt,
+            &signature,
+        );
+        let message = Message::new(vec![instruction.clone()]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let bond = bond_address(&gatekeeper.pubkey());
+        assert_eq!(bank_client.get_balance(&bond).unwrap(), 40);
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_459);
+        let account = bank_client.get_account_data(&bond).unwrap().unwrap();
+        assert_eq!(
+            GatekeeperBond::deserialize(&account).unwrap().total_slashed,
+            60
+        );
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert!(state.ended_sessions[0].disputed);
+        assert!(!state.ended_sessions[1].disputed);
+
+        // A session can only be disputed once
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], vec![instruction]),
+            Some(BandwidthPrepayError::AlreadyDisputed)
+        );
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_dispute_rejected() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Keypair::new();
+        let provider = Keypair::new().pubkey();
+        let contract =
+            start_bonded_contract(&bank_client, &alice_keypair, &gatekeeper, &provider, 100);
+
+        let instruction = bandwidth_prepay_instruction::end_session(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            100,
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+
+        // A receipt the initiator never signed proves nothing
+        let receipt = UsageReceipt::new(&contract, 0, 0, 40 * 1024, 40);
+        let signature = receipt.sign(&gatekeeper);
+        let instruction = bandwidth_prepay_instruction::dispute(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &receipt,
+            &signature,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], vec![instruction]),
+            Some(BandwidthPrepayError::InvalidReceiptSignature)
+        );
+
+        // Receipts for the session in progress aren't final
+        let receipt = UsageReceipt::new(&contract, 0, 1, 0, 0);
+        let signature = receipt.sign(&alice_keypair);
+        let instruction = bandwidth_prepay_instruction::dispute(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &receipt,
+            &signature,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&alice_keypair], vec![instruction]),
+            Some(BandwidthPrepayError::StaleReceipt)
+        );
+
+        // Only the initiator can dispute
+        let mallory = Keypair::new();
+        let instruction = system_instruction::transfer(&alice_pubkey, &mallory.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let receipt = UsageReceipt::new(&contract, 0, 0, 40 * 1024, 40);
+        let signature = receipt.sign(&alice_keypair);
+        let instruction = bandwidth_prepay_instruction::dispute(
+            &mallory.pubkey(),
+            &contract,
+            &gatekeeper.pubkey(),
+            &receipt,
+            &signature,
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&mallory], vec![instruction]),
+            Some(BandwidthPrepayError::NoInitiatorAccount)
+        );
+
+        let bond = bond_address(&gatekeeper.pubkey());
+        assert_eq!(bank_client.get_balance(&bond).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_dispute_window() {
+        let initiator_keypair = Keypair::new();
+        let initiator = initiator_keypair.pubkey();
+        let contract = Pubkey::new_rand();
+        let gatekeeper = Pubkey::new_rand();
+        let provider = Pubkey::new_rand();
+        let bond = bond_address(&gatekeeper);
+        let mut initiator_account = Account::new(0, 0, &system_program::id());
+        let mut contract_account = Account::new(500, BandwidthPrepayState::max_size(), &id());
+        let mut gatekeeper_account = Account::new(1, 0, &system_program::id());
+        let mut provider_account = Account::new(0, 0, &system_program::id());
+        let mut bond_account = Account::new(100, GatekeeperBond::max_size(), &id());
+        GatekeeperBond {
+            gatekeeper_id: gatekeeper,
+            total_slashed: 0,
+        }
+        .serialize(&mut bond_account.data)
+        .unwrap();
+        let clock_account = |slot| clock::create_account(1, slot, 0, 0, 0);
+
+        let initialize = BandwidthPrepayInstruction::InitializeAccount(ContractTerms::default());
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&gatekeeper, false, &mut gatekeeper_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+        ];
+        assert_eq!(process(&mut keyed_accounts, &initialize), Ok(()));
+
+        // A session can only end at a known slot
+        let end_session = |final_spend| BandwidthPrepayInstruction::EndSession {
+            final_spend,
+            total_bytes: 10 * 1024,
+        };
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &end_session(10)),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::InvalidClockAccount as u32
+            ))
+        );
+
+        // Overcharge every session the contract keeps open to disputes
+        for slot in 0..DISPUTABLE_SESSIONS as u64 {
+            let mut end_clock = clock_account(slot);
+            let mut keyed_accounts = [
+                KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+                KeyedAccount::new(&contract, false, &mut contract_account),
+                KeyedAccount::new(&provider, false, &mut provider_account),
+                KeyedAccount::new(&clock::id(), false, &mut end_clock),
+            ];
+            assert_eq!(process(&mut keyed_accounts, &end_session(10)), Ok(()));
+        }
+
+        // No session can be pushed out before its window closes
+        let mut end_clock = clock_account(DISPUTE_WINDOW_SLOTS);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+            KeyedAccount::new(&clock::id(), false, &mut end_clock),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &end_session(10)),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::DisputeWindowOpen as u32
+            ))
+        );
+
+        // The initiator disputes the first session just in time
+        let dispute = |session| {
+            let receipt = UsageReceipt::new(&contract, 0, session, 5 * 1024, 5);
+            let signature = receipt.sign(&initiator_keypair);
+            BandwidthPrepayInstruction::Dispute(receipt, signature)
+        };
+        let mut dispute_clock = clock_account(DISPUTE_WINDOW_SLOTS);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&bond, false, &mut bond_account),
+            KeyedAccount::new(&clock::id(), false, &mut dispute_clock),
+        ];
+        assert_eq!(process(&mut keyed_accounts, &dispute(0)), Ok(()));
+        assert_eq!(initiator_account.lamports, 5);
+
+        // Then the second session's window closes
+        let mut dispute_clock = clock_account(DISPUTE_WINDOW_SLOTS + 2);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&bond, false, &mut bond_account),
+            KeyedAccount::new(&clock::id(), false, &mut dispute_clock),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &dispute(1)),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::DisputeWindowClosed as u32
+            ))
+        );
+        assert_eq!(process(&mut keyed_accounts, &dispute(2)), Ok(()));
+        assert_eq!(initiator_account.lamports, 10);
+
+        // Sessions out of their window make room for new ones
+        let mut end_clock = clock_account(DISPUTE_WINDOW_SLOTS + 2);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+            KeyedAccount::new(&clock::id(), false, &mut end_clock),
+        ];
+        assert_eq!(process(&mut keyed_accounts, &end_session(10)), Ok(()));
+        let state = BandwidthPrepayState::deserialize(&contract_account.data).unwrap();
+        assert_eq!(state.ended_sessions.len(), DISPUTABLE_SESSIONS);
+        assert_eq!(state.ended_sessions[0].session, 1);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_dispute_after_handover() {
+        let initiator_keypair = Keypair::new();
+        let initiator = initiator_keypair.pubkey();
+        let contract = Pubkey::new_rand();
+        let gatekeeper = Pubkey::new_rand();
+        let new_gatekeeper = Pubkey::new_rand();
+        let provider = Pubkey::new_rand();
+        let mut initiator_account = Account::new(0, 0, &system_program::id());
+        let mut contract_account = Account::new(500, BandwidthPrepayState::max_size(), &id());
+        let mut gatekeeper_account = Account::new(1, 0, &system_program::id());
+        let mut new_gatekeeper_account = Account::new(1, 0, &system_program::id());
+        let mut provider_account = Account::new(0, 0, &system_program::id());
+        let bond_account = |gatekeeper_id| {
+            let mut account = Account::new(100, GatekeeperBond::max_size(), &id());
+            GatekeeperBond {
+                gatekeeper_id,
+                total_slashed: 0,
+            }
+            .serialize(&mut account.data)
+            .unwrap();
+            account
+        };
+        let bond = bond_address(&gatekeeper);
+        let new_bond = bond_address(&new_gatekeeper);
+        let mut bond_account_0 = bond_account(gatekeeper);
+        let mut new_bond_account = bond_account(new_gatekeeper);
+        let clock_account = |slot| clock::create_account(1, slot, 0, 0, 0);
+
+        let initialize = BandwidthPrepayInstruction::InitializeAccount(ContractTerms::default());
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&gatekeeper, false, &mut gatekeeper_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+        ];
+        assert_eq!(process(&mut keyed_accounts, &initialize), Ok(()));
+
+        // The first gatekeeper overcharges two sessions, then hands over
+        let end_session = BandwidthPrepayInstruction::EndSession {
+            final_spend: 10,
+            total_bytes: 10 * 1024,
+        };
+        for slot in 0..2 {
+            let mut end_clock = clock_account(slot);
+            let mut keyed_accounts = [
+                KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+                KeyedAccount::new(&contract, false, &mut contract_account),
+                KeyedAccount::new(&provider, false, &mut provider_account),
+                KeyedAccount::new(&clock::id(), false, &mut end_clock),
+            ];
+            assert_eq!(process(&mut keyed_accounts, &end_session), Ok(()));
+        }
+        let mut change_clock = clock_account(2);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&new_gatekeeper, false, &mut new_gatekeeper_account),
+            KeyedAccount::new(&clock::id(), false, &mut change_clock),
+        ];
+        assert_eq!(
+            process(
+                &mut keyed_accounts,
+                &BandwidthPrepayInstruction::ChangeGatekeeper
+            ),
+            Ok(())
+        );
+        let state = BandwidthPrepayState::deserialize(&contract_account.data).unwrap();
+        assert_eq!(state.session_gatekeeper(1), &gatekeeper);
+        assert_eq!(state.session_gatekeeper(2), &new_gatekeeper);
+
+        // A dispute of the first session slashes the bond that served it, not
+        // the new gatekeeper's
+        let dispute = |session| {
+            let receipt = UsageReceipt::new(&contract, 0, session, 5 * 1024, 5);
+            let signature = receipt.sign(&initiator_keypair);
+            BandwidthPrepayInstruction::Dispute(receipt, signature)
+        };
+        let mut dispute_clock = clock_account(3);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&new_bond, false, &mut new_bond_account),
+            KeyedAccount::new(&clock::id(), false, &mut dispute_clock),
+        ];
+        assert_eq!(
+            process(&mut keyed_accounts, &dispute(0)),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::InvalidBondAccount as u32
+            ))
+        );
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&bond, false, &mut bond_account_0),
+            KeyedAccount::new(&clock::id(), false, &mut dispute_clock),
+        ];
+        assert_eq!(process(&mut keyed_accounts, &dispute(0)), Ok(()));
+        assert_eq!(bond_account_0.lamports, 95);
+        assert_eq!(new_bond_account.lamports, 100);
+        assert_eq!(initiator_account.lamports, 5);
+
+        // The new gatekeeper serves a session of its own
+        let mut end_clock = clock_account(3);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&new_gatekeeper, true, &mut new_gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&provider, false, &mut provider_account),
+            KeyedAccount::new(&clock::id(), false, &mut end_clock),
+        ];
+        assert_eq!(process(&mut keyed_accounts, &end_session), Ok(()));
+
+        // It can't hand the contract back while the first gatekeeper's
+        // second session can still be disputed
+        let mut keyed_accounts = [
+            KeyedAccount::new(&new_gatekeeper, true, &mut new_gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&gatekeeper, false, &mut gatekeeper_account),
+        ];
+        assert_eq!(
+            process(
+                &mut keyed_accounts,
+                &BandwidthPrepayInstruction::ChangeGatekeeper
+            ),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::InvalidClockAccount as u32
+            ))
+        );
+        let mut change_clock = clock_account(4);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&new_gatekeeper, true, &mut new_gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&gatekeeper, false, &mut gatekeeper_account),
+            KeyedAccount::new(&clock::id(), false, &mut change_clock),
+        ];
+        assert_eq!(
+            process(
+                &mut keyed_accounts,
+                &BandwidthPrepayInstruction::ChangeGatekeeper
+            ),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::DisputeWindowOpen as u32
+            ))
+        );
+        let mut change_clock = clock_account(DISPUTE_WINDOW_SLOTS + 2);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&new_gatekeeper, true, &mut new_gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&gatekeeper, false, &mut gatekeeper_account),
+            KeyedAccount::new(&clock::id(), false, &mut change_clock),
+        ];
+        assert_eq!(
+            process(
+                &mut keyed_accounts,
+                &BandwidthPrepayInstruction::ChangeGatekeeper
+            ),
+            Ok(())
+        );
+
+        // The outgoing gatekeeper still answers for the session it served
+        let mut dispute_clock = clock_account(DISPUTE_WINDOW_SLOTS + 2);
+        let mut keyed_accounts = [
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&new_bond, false, &mut new_bond_account),
+            KeyedAccount::new(&clock::id(), false, &mut dispute_clock),
+        ];
+        assert_eq!(process(&mut keyed_accounts, &dispute(2)), Ok(()));
+        assert_eq!(new_bond_account.lamports, 95);
+        assert_eq!(bond_account_0.lamports, 95);
+        assert_eq!(initiator_account.lamports, 10);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_spend_batch() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let other_gatekeeper = Keypair::new().pubkey();
+
+        // Initialize contracts
+        let contracts: Vec<_> = (0..3).map(|_| Keypair::new().pubkey()).collect();
+        for contract in &contracts {
+            let instructions = bandwidth_prepay_instruction::initialize(
+                &alice_pubkey,
+                contract,
+                &gatekeeper.pubkey(),
+                &provider,
+                500,
+                &ContractTerms::default(),
+            );
+            let message = Message::new(instructions);
+            bank_client
+                .send_message(&[&alice_keypair], message)
+                .unwrap();
+        }
+        let split_contract = Keypair::new().pubkey();
+        let terms = ContractTerms {
+            recipients: vec![(provider, 5_000), (Keypair::new().pubkey(), 5_000)],
+            ..ContractTerms::default()
+        };
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &split_contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &terms,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let other_contract = Keypair::new().pubkey();
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &other_contract,
+            &other_gatekeeper,
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction = bandwidth_prepay_instruction::spend_batch(
+            &gatekeeper.pubkey(),
+            &provider,
+            &[
+                (contracts[0], 10, 10 * 1024),
+                (contracts[1], 20, 20 * 1024),
+                (contracts[2], 30, 30 * 1024),
+                (contracts[0], 5, 15 * 1024),
+            ],
+        );
+        assert_eq!(instruction.accounts.len(), 6);
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 65);
+        assert_eq!(bank_client.get_balance(&contracts[0]).unwrap(), 485);
+        assert_eq!(bank_client.get_balance(&contracts[1]).unwrap(), 480);
+        assert_eq!(bank_client.get_balance(&contracts[2]).unwrap(), 470);
+        let account = bank_client
+            .get_account_data(&contracts[0])
+            .unwrap()
+            .unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.total_spent, 15);
+        assert_eq!(state.total_bytes, 15 * 1024);
+
+        // Contracts that don't pay the provider alone can't be batched, and a
+        // failed batch charges nothing
+        let instruction = bandwidth_prepay_instruction::spend_batch(
+            &gatekeeper.pubkey(),
+            &provider,
+            &[
+                (contracts[1], 10, 30 * 1024),
+                (split_contract, 10, 10 * 1024),
+            ],
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::NoProviderAccount)
+        );
+        assert_eq!(bank_client.get_balance(&contracts[1]).unwrap(), 480);
+
+        // Every contract must belong to the signing gatekeeper
+        let instruction = bandwidth_prepay_instruction::spend_batch(
+            &gatekeeper.pubkey(),
+            &provider,
+            &[
+                (contracts[1], 10, 30 * 1024),
+                (other_contract, 10, 10 * 1024),
+            ],
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::NoGatekeeperAccount)
+        );
+
+        // Indices must name contract accounts
+        let instruction = Instruction::new(
+            id(),
+            &BandwidthPrepayInstruction::SpendBatch(vec![(1, 10, 10 * 1024)]),
+            vec![
+                AccountMeta::new(gatekeeper.pubkey(), true),
+                AccountMeta::new(contracts[1], false),
+                AccountMeta::new(provider, false),
+            ],
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::NotEnoughAccounts)
+        );
+        let instruction = Instruction::new(
+            id(),
+            &BandwidthPrepayInstruction::SpendBatch(vec![(3, 10, 10 * 1024)]),
+            vec![
+                AccountMeta::new(gatekeeper.pubkey(), true),
+                AccountMeta::new(provider, false),
+                AccountMeta::new(contracts[1], false),
+            ],
+        );
+        assert_eq!(
+            send_error(&bank_client, &[&gatekeeper], vec![instruction]),
+            Some(BandwidthPrepayError::NotEnoughAccounts)
+        );
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_history() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instructions = bandwidth_prepay_instruction::top_up(&alice_pubkey, &contract, 200);
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            100,
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+
+        // Outsiders can't deposit into the history, even with real lamports
+        let stranger = Keypair::new();
+        let instruction = system_instruction::transfer(&alice_pubkey, &stranger.pubkey(), 100);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let instructions = bandwidth_prepay_instruction::top_up(&stranger.pubkey(), &contract, 10);
+        assert_eq!(
+            send_error(&bank_client, &[&stranger], instructions),
+            Some(BandwidthPrepayError::NotSignedByContractParty)
+        );
+
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let history = contract_history(&account).unwrap();
+        let operations: Vec<_> = history
+            .iter()
+            .map(|event| (event.kind, event.amount))
+            .collect();
+        assert_eq!(
+            operations,
+            vec![
+                (EventKind::Deposit, 500),
+                (EventKind::Deposit, 200),
+                (EventKind::Spend, 100)
+            ]
+        );
+        assert!(history.iter().all(|event| event.slot == Some(0)));
+
+        // Operations that move no lamports aren't recorded
+        let instruction = bandwidth_prepay_instruction::end_session(
+            &gatekeeper.pubkey(),
+            &contract,
+            &[provider],
+            0,
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        assert_eq!(contract_history(&account).unwrap(), history);
+
+        // Operations built without the clock are still recorded
+        let instruction = Instruction::new(
+            id(),
+            &BandwidthPrepayInstruction::Spend {
+                amount: 50,
+                total_bytes: 150 * 1024,
+            },
+            vec![
+                AccountMeta::new(gatekeeper.pubkey(), true),
+                AccountMeta::new(contract, false),
+                AccountMeta::new(provider, false),
+            ],
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let history = contract_history(&account).unwrap();
+        assert_eq!(
+            history.last(),
+            Some(&ContractEvent {
+                kind: EventKind::Spend,
+                amount: 50,
+                slot: None,
+            })
+        );
     }
 }
@@ -0,0 +1,81 @@
+use bincode::serialize;
+use serde_derive::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, KeypairUtil, Signature};
+
+/// Cumulative usage within one session acknowledged by the initiator.
+/// Receipts name the contract's `instance` as well as its address, so those
+/// for an earlier contract at a reused address don't apply to a new one.
+/// Receipts are signed off-chain and redeemed by the gatekeeper with a
+/// `Settle` instruction during the session they name. Once the session ends,
+/// the initiator can `Dispute` it with the last receipt it signed.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct UsageReceipt {
+    pub contract_id: Pubkey,
+    pub instance: u64,
+    pub session: u64,
+    pub total_bytes: u64,
+    pub total_lamports: u64,
+}
+
+impl UsageReceipt {
+    pub fn new(
+        contract_id: &Pubkey,
+        instance: u64,
+        session: u64,
+        total_bytes: u64,
+        total_lamports: u64,
+    ) -> Self {
+        Self {
+            contract_id: *contract_id,
+            instance,
+            session,
+            total_bytes,
+            total_lamports,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize(self).unwrap()
+    }
+
+    pub fn sign(&self, keypair: &Keypair) -> Signature {
+        keypair.sign_message(&self.to_bytes())
+    }
+
+    pub fn verify(&self, signature: &Signature, signer_id: &Pubkey) -> bool {
+        signature.verify(signer_id.as_ref(), &self.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let initiator = Keypair::new();
+        let receipt = UsageReceipt::new(&Pubkey::new_rand(), 0, 0, 2048, 2);
+        let signature = receipt.sign(&initiator);
+        assert!(receipt.verify(&signature, &initiator.pubkey()));
+        assert!(!receipt.verify(&signature, &Pubkey::new_rand()));
+
+        let tampered = UsageReceipt {
+            total_lamports: 1,
+            ..receipt
+        };
+        assert!(!tampered.verify(&signature, &initiator.pubkey()));
+
+        let replayed = UsageReceipt {
+            session: 1,
+            ..receipt.clone()
+        };
+        assert!(!replayed.verify(&signature, &initiator.pubkey()));
+
+        let reused = UsageReceipt {
+            instance: 1,
+            ..receipt
+        };
+        assert!(!reused.verify(&signature, &initiator.pubkey()));
+    }
+}
@@ -0,0 +1,115 @@
+use crate::bandwidth_prepay_state::BandwidthPrepayError;
+use crate::id;
+use bincode::{deserialize, serialize_into};
+use serde_derive::{Deserialize, Serialize};
+use solana_sdk::hash::hashv;
+use solana_sdk::pubkey::Pubkey;
+
+/// Tag stored in the first byte of every registration account. It is never a
+/// valid contract state version, so registrations and contracts can't be
+/// mistaken for one another
+pub const REGISTRATION_TAG: u8 = 0x80;
+
+/// Space allocated for every registration account
+const REGISTRATION_SIZE: usize = 1024;
+
+/// What a gatekeeper publishes about itself so clients can find it on chain
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct GatekeeperInfo {
+    /// Address of the gatekeeper's JSON RPC listener, as HOST:PORT
+    pub endpoint: String,
+    pub lamports_per_kib: u64,
+    /// Destinations the gatekeeper forwards to. Empty forwards anywhere
+    pub destinations: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct GatekeeperRegistration {
+    pub gatekeeper_id: Pubkey,
+    pub info: GatekeeperInfo,
+}
+
+impl GatekeeperRegistration {
+    pub fn deserialize(input: &[u8]) -> Result<Self, BandwidthPrepayError> {
+        match input.first() {
+            Some(&REGISTRATION_TAG) => deserialize(&input[1..])
+                .map_err(|_| BandwidthPrepayError::UserdataDeserializeFailure),
+            _ => Err(BandwidthPrepayError::UserdataDeserializeFailure),
+        }
+    }
+
+    pub fn serialize(&self, output: &mut [u8]) -> Result<(), BandwidthPrepayError> {
+        if output.is_empty() {
+            Err(BandwidthPrepayError::UserdataTooSmall)?
+        }
+        output[0] = REGISTRATION_TAG;
+        serialize_into(&mut output[1..], self).map_err(|_| BandwidthPrepayError::UserdataTooSmall)
+    }
+
+    pub fn max_size() -> usize {
+        REGISTRATION_SIZE
+    }
+}
+
+/// The one address a gatekeeper's registration can live at
+pub fn registration_address(gatekeeper_id: &Pubkey) -> Pubkey {
+    let hash = hashv(&[b"registration", gatekeeper_id.as_ref(), id().as_ref()]);
+    Pubkey::new(hash.as_ref())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bandwidth_prepay_state::BandwidthPrepayState;
+    use solana_sdk::account::Account;
+
+    #[test]
+    fn test_serializer() {
+        let mut a = Account::new(0, GatekeeperRegistration::max_size(), &id());
+        let b = GatekeeperRegistration {
+            gatekeeper_id: Pubkey::new_rand(),
+            info: GatekeeperInfo {
+                endpoint: "127.0.0.1:8122".to_string(),
+                lamports_per_kib: 2,
+                destinations: vec!["127.0.0.1:1234".to_string()],
+            },
+        };
+        b.serialize(&mut a.data).unwrap();
+        assert_eq!(a.data[0], REGISTRATION_TAG);
+        assert_eq!(GatekeeperRegistration::deserialize(&a.data), Ok(b));
+
+        let info = GatekeeperInfo {
+            endpoint: "x".repeat(REGISTRATION_SIZE),
+            ..GatekeeperInfo::default()
+        };
+        let c = GatekeeperRegistration {
+            info,
+            ..GatekeeperRegistration::default()
+        };
+        assert_eq!(
+            c.serialize(&mut a.data),
+            Err(BandwidthPrepayError::UserdataTooSmall)
+        );
+    }
+
+    #[test]
+    fn test_registration_is_not_a_contract() {
+        let mut a = Account::new(0, GatekeeperRegistration::max_size(), &id());
+        GatekeeperRegistration::default()
+            .serialize(&mut a.data)
+            .unwrap();
+        assert_eq!(
+            BandwidthPrepayState::deserialize(&a.data),
+            Err(BandwidthPrepayError::UnsupportedStateVersion)
+        );
+
+        let mut a = Account::new(0, BandwidthPrepayState::max_size(), &id());
+        BandwidthPrepayState::default()
+            .serialize(&mut a.data)
+            .unwrap();
+        assert_eq!(
+            GatekeeperRegistration::deserialize(&a.data),
+            Err(BandwidthPrepayError::UserdataDeserializeFailure)
+        );
+    }
+}
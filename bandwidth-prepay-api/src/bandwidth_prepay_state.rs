@@ -1,47 +1,559 @@
-use bincode::{deserialize, serialize_into, serialized_size};
+use crate::bandwidth_prepay_history::{ContractEvent, EventKind, HISTORY_LEN};
+use crate::bandwidth_prepay_instruction::DEFAULT_LAMPORTS_PER_KIB;
+use bincode::{deserialize, serialize_into};
 use serde_derive::{Deserialize, Serialize};
+use solana_sdk::hash::{hash, Hash};
+use solana_sdk::instruction::InstructionError;
 use solana_sdk::pubkey::Pubkey;
+use solana_sdk::transaction::TransactionError;
 use std::{error, fmt};
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// Errors returned by the program, surfaced to clients as
+/// `InstructionError::CustomError(code)`. The codes are part of the program's
+/// interface: never renumber a variant, only append new ones
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BandwidthPrepayError {
-    AlreadyInitialized,
-    UserdataTooSmall,
-    UserdataDeserializeFailure,
-    NotSignedByGatekeeper,
-    BalanceTooLow,
-    NoGatekeeperAccount,
-    NoProviderAccount,
-    NoInitiatorAccount,
+    AlreadyInitialized = 0,
+    UserdataTooSmall = 1,
+    UserdataDeserializeFailure = 2,
+    NotSignedByGatekeeper = 3,
+    BalanceTooLow = 4,
+    NoGatekeeperAccount = 5,
+    NoProviderAccount = 6,
+    NoInitiatorAccount = 7,
+    DataCapExceeded = 8,
+    ByteCounterDecreased = 9,
+    SpendExceedsRate = 10,
+    ReceiptContractMismatch = 11,
+    InvalidReceiptSignature = 12,
+    StaleReceipt = 13,
+    NotSignedByInitiator = 14,
+    NotExpired = 15,
+    InvalidClockAccount = 16,
+    ContractNotInitialized = 17,
+    ContractClosed = 18,
+    UnsupportedStateVersion = 19,
+    AccountNeedsMigration = 20,
+    AlreadyMigrated = 21,
+    NotSignedByContractParty = 22,
+    InvalidRevenueSplit = 23,
+    NotEnoughAccounts = 24,
+    InvalidAccountOwner = 25,
+    NotSignedByFunder = 26,
+    Overflow = 27,
+    ZeroAmount = 28,
+    AliasedAccounts = 29,
+    SessionOpen = 30,
+    InvalidContractAddress = 31,
+    GatekeeperNotRegistered = 32,
+    InvalidRegistrationAccount = 33,
+    InvalidBondAccount = 34,
+    NoOvercharge = 35,
+    AlreadyDisputed = 36,
+    DisputeWindowOpen = 37,
+    DisputeWindowClosed = 38,
+    TooManyDestinations = 39,
+}
+
+impl BandwidthPrepayError {
+    /// Recover the error behind an `InstructionError::CustomError` code
+    pub fn from_custom_error(code: u32) -> Option<Self> {
+        use BandwidthPrepayError::*;
+        let error = match code {
+            0 => AlreadyInitialized,
+            1 => UserdataTooSmall,
+            2 => UserdataDeserializeFailure,
+            3 => NotSignedByGatekeeper,
+            4 => BalanceTooLow,
+            5 => NoGatekeeperAccount,
+            6 => NoProviderAccount,
+            7 => NoInitiatorAccount,
+            8 => DataCapExceeded,
+            9 => ByteCounterDecreased,
+            10 => SpendExceedsRate,
+            11 => ReceiptContractMismatch,
+            12 => InvalidReceiptSignature,
+            13 => StaleReceipt,
+            14 => NotSignedByInitiator,
+            15 => NotExpired,
+            16 => InvalidClockAccount,
+            17 => ContractNotInitialized,
+            18 => ContractClosed,
+            19 => UnsupportedStateVersion,
+            20 => AccountNeedsMigration,
+            21 => AlreadyMigrated,
+            22 => NotSignedByContractParty,
+            23 => InvalidRevenueSplit,
+            24 => NotEnoughAccounts,
+            25 => InvalidAccountOwner,
+            26 => NotSignedByFunder,
+            27 => Overflow,
+            28 => ZeroAmount,
+            29 => AliasedAccounts,
+            30 => SessionOpen,
+            31 => InvalidContractAddress,
+            32 => GatekeeperNotRegistered,
+            33 => InvalidRegistrationAccount,
+            34 => InvalidBondAccount,
+            35 => NoOvercharge,
+            36 => AlreadyDisputed,
+            37 => DisputeWindowOpen,
+            38 => DisputeWindowClosed,
+            39 => TooManyDestinations,
+            _ => return None,
+        };
+        Some(error)
+    }
+
+    /// Recover the program error that caused a transaction to fail, if any
+    pub fn from_transaction_error(error: &TransactionError) -> Option<Self> {
+        match error {
+            TransactionError::InstructionError(_, InstructionError::CustomError(code)) => {
+                Self::from_custom_error(*code)
+            }
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for BandwidthPrepayError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "invalid")
+        use BandwidthPrepayError::*;
+        let message = match self {
+            AlreadyInitialized => "contract is already initialized",
+            UserdataTooSmall => "contract account data is too small",
+            UserdataDeserializeFailure => "contract account data could not be deserialized",
+            NotSignedByGatekeeper => "instruction was not signed by the gatekeeper",
+            BalanceTooLow => "contract balance is too low",
+            NoGatekeeperAccount => "gatekeeper account does not match the contract",
+            NoProviderAccount => "payee accounts do not match the contract",
+            NoInitiatorAccount => "initiator account does not match the contract",
+            DataCapExceeded => "byte count exceeds the contract data cap",
+            ByteCounterDecreased => "byte count is lower than already recorded",
+            SpendExceedsRate => "amount exceeds the agreed rate for the bytes forwarded",
+            ReceiptContractMismatch => "receipt is for a different contract",
+            InvalidReceiptSignature => "receipt was not signed by the initiator",
+            StaleReceipt => "receipt covers no more than has already been paid",
+            NotSignedByInitiator => "instruction was not signed by the initiator",
+            NotExpired => "contract has not expired yet",
+            InvalidClockAccount => "clock account is invalid",
+            ContractNotInitialized => "contract is not initialized",
+            ContractClosed => "contract is closed",
+            UnsupportedStateVersion => "contract state version is not supported",
+            AccountNeedsMigration => "contract account must be migrated first",
+            AlreadyMigrated => "contract account is already migrated",
+            NotSignedByContractParty => {
+                "instruction was not signed by the initiator or the gatekeeper"
+            }
+            InvalidRevenueSplit => "revenue split is invalid",
+            NotEnoughAccounts => "instruction is missing accounts",
+            InvalidAccountOwner => "contract account is not owned by the prepay program",
+            NotSignedByFunder => "top up was not signed by the funder",
+            Overflow => "lamport or counter arithmetic overflowed",
+            ZeroAmount => "amount must be greater than zero",
+            AliasedAccounts => "the same account is given more than one role",
+            SessionOpen => "the current session must be ended first",
+            InvalidContractAddress => "contract address does not match its derivation nonce",
+            GatekeeperNotRegistered => "gatekeeper is not registered",
+            InvalidRegistrationAccount => "account is not the gatekeeper's registration",
+            InvalidBondAccount => "account is not the gatekeeper's bond",
+            NoOvercharge => "receipt does not show the session was overcharged",
+            AlreadyDisputed => "session has already been disputed",
+            DisputeWindowOpen => "ended sessions are still open to disputes",
+            DisputeWindowClosed => "session is no longer open to disputes",
+            TooManyDestinations => "too many allowed destinations",
+        };
+        write!(f, "{}", message)
     }
 }
 
 impl error::Error for BandwidthPrepayError {}
 
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ContractStatus {
+    Uninitialized,
+    Initialized,
+    Closed,
+}
+
+impl Default for ContractStatus {
+    fn default() -> Self {
+        ContractStatus::Uninitialized
+    }
+}
+
+/// Most accounts a contract can split its payments across
+pub const MAX_RECIPIENTS: usize = 4;
+
+/// Ended sessions a contract keeps open to disputes
+pub const DISPUTABLE_SESSIONS: usize = 3;
+
+/// Slots after a session ends during which the initiator can dispute it,
+/// about an hour at 400ms slots
+pub const DISPUTE_WINDOW_SLOTS: u64 = 9_000;
+
+/// Most destinations a contract can restrict its connections to
+pub const MAX_DESTINATIONS: usize = 8;
+
+/// Basis points making up a whole payment
+pub const TOTAL_BASIS_POINTS: u16 = 10_000;
+
+/// Largest share of a charge, in basis points, that the gatekeeper fee may
+/// add on top of it. Bounding the fee by the charge means splitting a charge
+/// into many small spends can't collect more fees than one spend would
+pub const MAX_FEE_BASIS_POINTS: u16 = 1_000;
+
+/// Version tag stored in the first byte of every contract account. An all-zero
+/// tag marks an account that hasn't been initialized yet
+pub const STATE_VERSION: u8 = 1;
+
+/// Size of the untagged state written before accounts were versioned
+pub const LEGACY_STATE_SIZE: usize = 96;
+
+/// Space allocated for every contract account. Bytes past the serialized
+/// state stay zeroed, so fields appended in later versions must deserialize
+/// from zeros to their default, letting older accounts be read unchanged
+const ACCOUNT_SIZE: usize = 1024;
+
+/// Hash committing a contract to `destination`, given exactly as the
+/// initiator sends it in its connection requests
+pub fn destination_hash(destination: &str) -> Hash {
+    hash(destination.as_bytes())
+}
+
+/// Contract state as written by the original, unversioned program
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
+struct LegacyBandwidthPrepayState {
+    gatekeeper_id: Pubkey,
+    provider_id: Pubkey,
+    initiator_id: Pubkey,
+}
+
+impl From<LegacyBandwidthPrepayState> for BandwidthPrepayState {
+    fn from(legacy: LegacyBandwidthPrepayState) -> Self {
+        let status = if legacy == LegacyBandwidthPrepayState::default() {
+            ContractStatus::Uninitialized
+        } else {
+            ContractStatus::Initialized
+        };
+        Self {
+            gatekeeper_id: legacy.gatekeeper_id,
+            provider_id: legacy.provider_id,
+            initiator_id: legacy.initiator_id,
+            lamports_per_kib: DEFAULT_LAMPORTS_PER_KIB,
+            expiry_slot: u64::max_value(),
+            status,
+            ..Self::default()
+        }
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct BandwidthPrepayState {
     pub gatekeeper_id: Pubkey,
     pub provider_id: Pubkey,
     pub initiator_id: Pubkey,
+    pub lamports_per_kib: u64,
+    pub max_bytes: Option<u64>,
+    pub total_spent: u64,
+    pub total_bytes: u64,
+    pub expiry_slot: u64,
+    pub status: ContractStatus,
+    pub deposit_count: u64,
+    /// Contract balance as of the last instruction that wrote the state
+    pub recorded_balance: u64,
+    /// Revenue split as agreed in `ContractTerms::recipients`
+    pub recipients: Vec<(Pubkey, u16)>,
+    /// Lamports credited to the gatekeeper with every `Spend`
+    pub gatekeeper_fee: u64,
+    /// Whether `ChangeGatekeeper` needs the initiator's signature
+    pub rotation_requires_initiator: bool,
+    /// Sessions ended with `EndSession`. `total_spent` and `total_bytes`
+    /// count the current session only
+    pub session_count: u64,
+    /// Bytes forwarded over every session, which `max_bytes` caps. Unlike
+    /// `total_bytes` it isn't reset when a session ends
+    pub lifetime_bytes: u64,
+    /// Nonce the contract address was derived from, if any
+    pub nonce: Option<u64>,
+    /// Value receipts must name, as agreed in `ContractTerms::instance`
+    pub instance: u64,
+    /// Whether a gatekeeper the contract is handed to must be registered
+    pub require_registered_gatekeeper: bool,
+    /// The last `DISPUTABLE_SESSIONS` ended sessions, oldest first
+    pub ended_sessions: Vec<EndedSession>,
+    /// Totals of the highest receipt settled in the current session. They
+    /// are what the initiator acknowledged if it disputes the session
+    pub receipt_bytes: u64,
+    pub receipt_lamports: u64,
+    /// The gatekeeper the contract was last handed over from, and the first
+    /// session its successor served. It stays answerable for the sessions
+    /// before that until they can no longer be disputed
+    pub previous_gatekeeper: Option<(Pubkey, u64)>,
+    /// The last `HISTORY_LEN` operations on the contract, oldest first
+    pub history: Vec<ContractEvent>,
+    /// `destination_hash`es of the only destinations the gatekeeper may
+    /// connect to for this contract. Empty allows any destination
+    pub allowed_destinations: Vec<Hash>,
+    /// Lamports the gatekeeper may charge for each slot of a session, on top
+    /// of the per-KiB rate
+    pub lamports_per_slot: u64,
+    /// Slot of the current session's first charge. `None` until a charge
+    /// carrying a clock opens the session, and again once it ends
+    pub session_start_slot: Option<u64>,
+}
+
+/// What a session charged, kept after `EndSession` so the initiator can
+/// `Dispute` it for `DISPUTE_WINDOW_SLOTS`
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct EndedSession {
+    pub session: u64,
+    /// Lamports paid to the payees, not counting gatekeeper fees
+    pub spent: u64,
+    pub slots: u64,
+    /// Totals of the highest receipt settled in the session
+    pub receipt_bytes: u64,
+    pub receipt_lamports: u64,
+    pub end_slot: u64,
+    pub disputed: bool,
+}
+
+impl EndedSession {
+    /// Whether the initiator can still dispute the session at `slot`
+    pub fn in_dispute_window(&self, slot: u64) -> bool {
+        slot <= self.end_slot.saturating_add(DISPUTE_WINDOW_SLOTS)
+    }
 }
 
 impl BandwidthPrepayState {
     pub fn deserialize(input: &[u8]) -> Result<Self, BandwidthPrepayError> {
-        deserialize(input).map_err(|_| BandwidthPrepayError::UserdataDeserializeFailure)
+        if Self::is_legacy(input) {
+            let legacy: LegacyBandwidthPrepayState =
+                deserialize(input).map_err(|_| BandwidthPrepayError::UserdataDeserializeFailure)?;
+            return Ok(legacy.into());
+        }
+        match input.first() {
+            Some(0) => Ok(Self::default()),
+            Some(&STATE_VERSION) => deserialize(&input[1..])
+                .map_err(|_| BandwidthPrepayError::UserdataDeserializeFailure),
+            Some(_) => Err(BandwidthPrepayError::UnsupportedStateVersion),
+            None => Err(BandwidthPrepayError::UserdataDeserializeFailure),
+        }
     }
 
     pub fn serialize(&self, output: &mut [u8]) -> Result<(), BandwidthPrepayError> {
-        serialize_into(output, self).map_err(|_| BandwidthPrepayError::UserdataTooSmall)
+        if Self::is_legacy(output) {
+            Err(BandwidthPrepayError::AccountNeedsMigration)?
+        }
+        if output.is_empty() {
+            Err(BandwidthPrepayError::UserdataTooSmall)?
+        }
+        // Clear what a larger earlier state left behind, so the tail reads as
+        // zeros to any field appended later
+        output.iter_mut().for_each(|byte| *byte = 0);
+        output[0] = STATE_VERSION;
+        serialize_into(&mut output[1..], self).map_err(|_| BandwidthPrepayError::UserdataTooSmall)
+    }
+
+    /// Whether `input` holds an unversioned account that must be upgraded
+    /// with a `Migrate` instruction before the program can write to it. The
+    /// old program wrote either nothing or all three of its pubkeys
+    pub fn is_legacy(input: &[u8]) -> bool {
+        if input.len() != LEGACY_STATE_SIZE {
+            return false;
+        }
+        match deserialize::<LegacyBandwidthPrepayState>(input) {
+            Ok(legacy) => {
+                let unset = [
+                    legacy.gatekeeper_id,
+                    legacy.provider_id,
+                    legacy.initiator_id,
+                ]
+                .iter()
+                .filter(|pubkey| **pubkey == Pubkey::default())
+                .count();
+                unset == 0 || unset == 3
+            }
+            Err(_) => false,
+        }
     }
 
     pub fn max_size() -> usize {
-        let bandwidth_prepay_state = BandwidthPrepayState::default();
-        serialized_size(&bandwidth_prepay_state).unwrap() as usize
+        ACCOUNT_SIZE
+    }
+
+    /// Whether `recipients` is a split the contract can be initialized with
+    pub fn is_valid_split(recipients: &[(Pubkey, u16)]) -> bool {
+        if recipients.is_empty() {
+            return true;
+        }
+        let mut total = 0u32;
+        for (i, (pubkey, basis_points)) in recipients.iter().enumerate() {
+            if *basis_points == 0 || recipients[..i].iter().any(|(other, _)| other == pubkey) {
+                return false;
+            }
+            total += u32::from(*basis_points);
+        }
+        recipients.len() <= MAX_RECIPIENTS && total == u32::from(TOTAL_BASIS_POINTS)
+    }
+
+    /// Accounts every payment is made to, in the order `Spend` and `Settle`
+    /// expect them
+    pub fn payees(&self) -> Vec<Pubkey> {
+        if self.recipients.is_empty() {
+            vec![self.provider_id]
+        } else {
+            self.recipients.iter().map(|(pubkey, _)| *pubkey).collect()
+        }
+    }
+
+    /// Whether every payee is a different account from the others, the
+    /// gatekeeper and the initiator. Payments to an aliased payee would be
+    /// applied to one account through two positions of the same instruction
+    pub fn has_distinct_payees(&self) -> bool {
+        let payees = self.payees();
+        payees.iter().enumerate().all(|(i, payee)| {
+            payee != &self.gatekeeper_id
+                && payee != &self.initiator_id
+                && !payees[..i].contains(payee)
+        })
+    }
+
+    /// Divide `amount` between the payees. Each share is rounded down and the
+    /// remainder goes to the first payee, so the shares always add up to
+    /// `amount`
+    pub fn split(&self, amount: u64) -> Vec<u64> {
+        if self.recipients.is_empty() {
+            return vec![amount];
+        }
+        let mut shares: Vec<u64> = self
+            .recipients
+            .iter()
+            .map(|(_, basis_points)| {
+                (u128::from(amount) * u128::from(*basis_points) / u128::from(TOTAL_BASIS_POINTS))
+                    as u64
+            })
+            .collect();
+        let remainder = amount - shares.iter().sum::<u64>();
+        shares[0] += remainder;
+        shares
+    }
+
+    /// Keep the current session open to disputes after it ends at `slot`,
+    /// and reset the session's totals. Fails while every kept session can
+    /// still be disputed, so no session leaves the window early
+    pub fn end_session(&mut self, slot: u64) -> Result<(), BandwidthPrepayError> {
+        if self.ended_sessions.len() == DISPUTABLE_SESSIONS {
+            let oldest = &self.ended_sessions[0];
+            if !oldest.disputed && oldest.in_dispute_window(slot) {
+                Err(BandwidthPrepayError::DisputeWindowOpen)?
+            }
+            self.ended_sessions.remove(0);
+        }
+        self.ended_sessions.push(EndedSession {
+            session: self.session_count,
+            spent: self.total_spent,
+            slots: self.session_slots(Some(slot)),
+            receipt_bytes: self.receipt_bytes,
+            receipt_lamports: self.receipt_lamports,
+            end_slot: slot,
+            disputed: false,
+        });
+        self.session_count = self
+            .session_count
+            .checked_add(1)
+            .ok_or(BandwidthPrepayError::Overflow)?;
+        self.session_start_slot = None;
+        self.total_spent = 0;
+        self.total_bytes = 0;
+        self.receipt_bytes = 0;
+        self.receipt_lamports = 0;
+        Ok(())
+    }
+
+    /// Gatekeeper that served the ended `session`, whose bond a dispute of
+    /// it slashes
+    pub fn session_gatekeeper(&self, session: u64) -> &Pubkey {
+        match &self.previous_gatekeeper {
+            Some((previous, first_session)) if session < *first_session => previous,
+            _ => &self.gatekeeper_id,
+        }
+    }
+
+    /// Whether a session served by the previous gatekeeper can still be
+    /// disputed at `slot`
+    pub fn previous_gatekeeper_disputable(&self, slot: u64) -> bool {
+        let first_session = match self.previous_gatekeeper {
+            Some((_, first_session)) => first_session,
+            None => return false,
+        };
+        self.ended_sessions.iter().any(|ended| {
+            ended.session < first_session && !ended.disputed && ended.in_dispute_window(slot)
+        })
+    }
+
+    /// `lifetime_bytes` once the current session has carried `total_bytes`
+    pub fn lifetime_bytes_at(&self, total_bytes: u64) -> u64 {
+        self.lifetime_bytes
+            .saturating_add(total_bytes.saturating_sub(self.total_bytes))
+    }
+
+    /// Whether the data cap leaves room for the current session to carry
+    /// `total_bytes`
+    pub fn within_data_cap(&self, total_bytes: u64) -> bool {
+        self.max_bytes.map_or(true, |max_bytes| {
+            self.lifetime_bytes_at(total_bytes) <= max_bytes
+        })
+    }
+
+    /// Gatekeeper fee due on a charge of `amount`: the agreed fee, but no
+    /// more than `MAX_FEE_BASIS_POINTS` of the charge
+    pub fn fee(&self, amount: u64) -> u64 {
+        let max_fee =
+            u128::from(amount) * u128::from(MAX_FEE_BASIS_POINTS) / u128::from(TOTAL_BASIS_POINTS);
+        self.gatekeeper_fee.min(max_fee as u64)
+    }
+
+    /// Append an operation to `history`, dropping the oldest once it is full.
+    /// Operations that moved no lamports are left out
+    pub fn record(&mut self, kind: EventKind, amount: u64, slot: Option<u64>) {
+        if amount == 0 {
+            return;
+        }
+        if self.history.len() == HISTORY_LEN {
+            self.history.remove(0);
+        }
+        self.history.push(ContractEvent { kind, amount, slot });
+    }
+
+    /// Whether the gatekeeper may forward this contract's traffic to
+    /// `destination`
+    pub fn allows_destination(&self, destination: &str) -> bool {
+        self.allowed_destinations.is_empty()
+            || self
+                .allowed_destinations
+                .contains(&destination_hash(destination))
+    }
+
+    /// Slots the current session has lasted as of `slot`, or 0 when either
+    /// end is unknown
+    pub fn session_slots(&self, slot: Option<u64>) -> u64 {
+        match (self.session_start_slot, slot) {
+            (Some(start), Some(slot)) => slot.saturating_sub(start),
+            _ => 0,
+        }
+    }
+
+    /// Lamports the agreed terms allow to be charged for a session that has
+    /// carried `data_amount` bytes over `slots` slots
+    pub fn allowance(&self, data_amount: u64, slots: u64) -> u64 {
+        self.price(data_amount)
+            .saturating_add(self.lamports_per_slot.saturating_mul(slots))
+    }
+
+    /// Lamports the agreed rate allows to be charged for `data_amount` bytes
+    pub fn price(&self, data_amount: u64) -> u64 {
+        (u128::from(data_amount) * u128::from(self.lamports_per_kib) / 1024) as u64
     }
 }
 
@@ -49,23 +561,121 @@ impl BandwidthPrepayState {
 mod test {
     use super::*;
     use crate::id;
+    use bincode::serialized_size;
     use solana_sdk::account::Account;
 
     #[test]
     fn test_max_size() {
         let number = BandwidthPrepayState::max_size();
-        assert_eq!(number, 96);
+        assert_eq!(number, 1024);
+
+        let largest_state = BandwidthPrepayState {
+            max_bytes: Some(0),
+            nonce: Some(0),
+            recipients: vec![(Pubkey::default(), 0); MAX_RECIPIENTS],
+            ended_sessions: vec![EndedSession::default(); DISPUTABLE_SESSIONS],
+            previous_gatekeeper: Some((Pubkey::default(), 0)),
+            history: vec![
+                ContractEvent {
+                    kind: EventKind::Deposit,
+                    amount: 0,
+                    slot: Some(0),
+                };
+                HISTORY_LEN
+            ],
+            allowed_destinations: vec![Hash::default(); MAX_DESTINATIONS],
+            session_start_slot: Some(0),
+            ..BandwidthPrepayState::default()
+        };
+        assert!(1 + serialized_size(&largest_state).unwrap() as usize <= number);
     }
 
     #[test]
     fn test_serializer() {
-        let mut a = Account::new(0, 96, &id());
-        let b = BandwidthPrepayState::default();
+        let mut a = Account::new(0, BandwidthPrepayState::max_size(), &id());
+        let b = BandwidthPrepayState {
+            max_bytes: Some(1_000_000),
+            ..BandwidthPrepayState::default()
+        };
         b.serialize(&mut a.data).unwrap();
+        assert_eq!(a.data[0], STATE_VERSION);
         let c = BandwidthPrepayState::deserialize(&a.data).unwrap();
         assert_eq!(b, c);
     }
 
+    #[test]
+    fn test_serializer_clears_tail() {
+        let mut a = Account::new(0, BandwidthPrepayState::max_size(), &id());
+        let larger = BandwidthPrepayState {
+            allowed_destinations: vec![Hash::default(); MAX_DESTINATIONS],
+            recipients: vec![(Pubkey::new_rand(), 0); MAX_RECIPIENTS],
+            ..BandwidthPrepayState::default()
+        };
+        larger.serialize(&mut a.data).unwrap();
+
+        let smaller = BandwidthPrepayState::default();
+        smaller.serialize(&mut a.data).unwrap();
+        let size = 1 + serialized_size(&smaller).unwrap() as usize;
+        assert!(a.data[size..].iter().all(|byte| *byte == 0));
+        assert_eq!(BandwidthPrepayState::deserialize(&a.data), Ok(smaller));
+    }
+
+    #[test]
+    fn test_deserialize_uninitialized() {
+        let a = Account::new(0, BandwidthPrepayState::max_size(), &id());
+        assert_eq!(
+            BandwidthPrepayState::deserialize(&a.data),
+            Ok(BandwidthPrepayState::default())
+        );
+        assert_eq!(
+            BandwidthPrepayState::deserialize(&[]),
+            Err(BandwidthPrepayError::UserdataDeserializeFailure)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_unsupported_version() {
+        let mut a = Account::new(0, BandwidthPrepayState::max_size(), &id());
+        a.data[0] = STATE_VERSION + 1;
+        assert_eq!(
+            BandwidthPrepayState::deserialize(&a.data),
+            Err(BandwidthPrepayError::UnsupportedStateVersion)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_legacy() {
+        let legacy = LegacyBandwidthPrepayState {
+            gatekeeper_id: Pubkey::new_rand(),
+            provider_id: Pubkey::new_rand(),
+            initiator_id: Pubkey::new_rand(),
+        };
+        let mut a = Account::new(0, LEGACY_STATE_SIZE, &id());
+        serialize_into(&mut a.data[..], &legacy).unwrap();
+        assert!(BandwidthPrepayState::is_legacy(&a.data));
+
+        let state = BandwidthPrepayState::deserialize(&a.data).unwrap();
+        assert_eq!(state.gatekeeper_id, legacy.gatekeeper_id);
+        assert_eq!(state.provider_id, legacy.provider_id);
+        assert_eq!(state.initiator_id, legacy.initiator_id);
+        assert_eq!(state.lamports_per_kib, DEFAULT_LAMPORTS_PER_KIB);
+        assert_eq!(state.status, ContractStatus::Initialized);
+        assert_eq!(
+            state.serialize(&mut a.data),
+            Err(BandwidthPrepayError::AccountNeedsMigration)
+        );
+
+        // The old program never wrote only some of the pubkeys
+        let legacy = LegacyBandwidthPrepayState {
+            gatekeeper_id: Pubkey::new_rand(),
+            ..LegacyBandwidthPrepayState::default()
+        };
+        serialize_into(&mut a.data[..], &legacy).unwrap();
+        assert!(!BandwidthPrepayState::is_legacy(&a.data));
+        assert!(BandwidthPrepayState::is_legacy(&[0; LEGACY_STATE_SIZE]));
+        assert!(!BandwidthPrepayState::is_legacy(&[0; 1024]));
+    }
+
     #[test]
     fn test_serializer_userdata_too_small() {
         let mut a = Account::new(0, 1, &id());
@@ -75,4 +685,261 @@ mod test {
             Err(BandwidthPrepayError::UserdataTooSmall)
         );
     }
+
+    #[test]
+    fn test_error_codes() {
+        assert_eq!(BandwidthPrepayError::AlreadyInitialized as u32, 0);
+        assert_eq!(BandwidthPrepayError::BalanceTooLow as u32, 4);
+        assert_eq!(BandwidthPrepayError::AliasedAccounts as u32, 29);
+        assert_eq!(BandwidthPrepayError::InvalidContractAddress as u32, 31);
+        for code in 0..=39 {
+            let error = BandwidthPrepayError::from_custom_error(code).unwrap();
+            assert_eq!(error as u32, code);
+        }
+        assert_eq!(BandwidthPrepayError::from_custom_error(40), None);
+
+        let error = TransactionError::InstructionError(
+            0,
+            InstructionError::CustomError(BandwidthPrepayError::BalanceTooLow as u32),
+        );
+        assert_eq!(
+            BandwidthPrepayError::from_transaction_error(&error),
+            Some(BandwidthPrepayError::BalanceTooLow)
+        );
+        assert_eq!(
+            BandwidthPrepayError::from_transaction_error(&TransactionError::AccountInUse),
+            None
+        );
+        assert_eq!(
+            BandwidthPrepayError::BalanceTooLow.to_string(),
+            "contract balance is too low"
+        );
+    }
+
+    #[test]
+    fn test_is_valid_split() {
+        let a = Pubkey::new_rand();
+        let b = Pubkey::new_rand();
+        assert!(BandwidthPrepayState::is_valid_split(&[]));
+        assert!(BandwidthPrepayState::is_valid_split(&[(a, 10_000)]));
+        assert!(BandwidthPrepayState::is_valid_split(&[
+            (a, 7_000),
+            (b, 3_000)
+        ]));
+        assert!(!BandwidthPrepayState::is_valid_split(&[
+            (a, 7_000),
+            (b, 2_000)
+        ]));
+        assert!(!BandwidthPrepayState::is_valid_split(&[
+            (a, 10_000),
+            (b, 0)
+        ]));
+        assert!(!BandwidthPrepayState::is_valid_split(&[
+            (a, 5_000),
+            (a, 5_000)
+        ]));
+        let too_many: Vec<_> = (0..5).map(|_| (Pubkey::new_rand(), 2_000)).collect();
+        assert!(!BandwidthPrepayState::is_valid_split(&too_many));
+    }
+
+    #[test]
+    fn test_has_distinct_payees() {
+        let gatekeeper_id = Pubkey::new_rand();
+        let initiator_id = Pubkey::new_rand();
+        let provider_id = Pubkey::new_rand();
+        let mut state = BandwidthPrepayState {
+            gatekeeper_id,
+            initiator_id,
+            provider_id,
+            ..BandwidthPrepayState::default()
+        };
+        assert!(state.has_distinct_payees());
+
+        state.provider_id = gatekeeper_id;
+        assert!(!state.has_distinct_payees());
+        state.provider_id = initiator_id;
+        assert!(!state.has_distinct_payees());
+
+        state.recipients = vec![(provider_id, 5_000), (Pubkey::new_rand(), 5_000)];
+        assert!(state.has_distinct_payees());
+        state.recipients[1].0 = provider_id;
+        assert!(!state.has_distinct_payees());
+        state.recipients[1].0 = gatekeeper_id;
+        assert!(!state.has_distinct_payees());
+    }
+
+    #[test]
+    fn test_split() {
+        let provider_id = Pubkey::new_rand();
+        let state = BandwidthPrepayState {
+            provider_id,
+            ..BandwidthPrepayState::default()
+        };
+        assert_eq!(state.payees(), vec![provider_id]);
+        assert_eq!(state.split(7), vec![7]);
+
+        let recipients = vec![
+            (Pubkey::new_rand(), 5_000),
+            (Pubkey::new_rand(), 3_000),
+            (Pubkey::new_rand(), 2_000),
+        ];
+        let state = BandwidthPrepayState {
+            provider_id,
+            recipients: recipients.clone(),
+            ..BandwidthPrepayState::default()
+        };
+        assert_eq!(
+            state.payees(),
+            recipients
+                .iter()
+                .map(|(pubkey, _)| *pubkey)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(state.split(0), vec![0, 0, 0]);
+        assert_eq!(state.split(7), vec![4, 2, 1]);
+        assert_eq!(state.split(100), vec![50, 30, 20]);
+        let shares = state.split(u64::max_value());
+        let total: u128 = shares.iter().map(|share| u128::from(*share)).sum();
+        assert_eq!(total, u128::from(u64::max_value()));
+    }
+
+    #[test]
+    fn test_end_session() {
+        let mut state = BandwidthPrepayState {
+            total_spent: 30,
+            total_bytes: 40 * 1024,
+            receipt_bytes: 20 * 1024,
+            receipt_lamports: 20,
+            session_start_slot: Some(5),
+            ..BandwidthPrepayState::default()
+        };
+        state.end_session(10).unwrap();
+        assert_eq!(
+            state.ended_sessions,
+            vec![EndedSession {
+                session: 0,
+                spent: 30,
+                slots: 5,
+                receipt_bytes: 20 * 1024,
+                receipt_lamports: 20,
+                end_slot: 10,
+                disputed: false,
+            }]
+        );
+        assert_eq!(state.session_count, 1);
+        assert_eq!(state.session_start_slot, None);
+        assert_eq!(state.total_spent, 0);
+        assert_eq!(state.total_bytes, 0);
+        assert_eq!(state.receipt_lamports, 0);
+
+        // Sessions still open to disputes can't be pushed out
+        for slot in 11..DISPUTABLE_SESSIONS as u64 + 10 {
+            state.end_session(slot).unwrap();
+        }
+        assert_eq!(state.ended_sessions.len(), DISPUTABLE_SESSIONS);
+        assert_eq!(
+            state.end_session(10 + DISPUTE_WINDOW_SLOTS),
+            Err(BandwidthPrepayError::DisputeWindowOpen)
+        );
+        state.end_session(11 + DISPUTE_WINDOW_SLOTS).unwrap();
+        assert_eq!(state.ended_sessions.len(), DISPUTABLE_SESSIONS);
+        assert_eq!(state.ended_sessions[0].session, 1);
+
+        // Nor can a disputed one be disputed again, so it can go right away
+        state.ended_sessions[0].disputed = true;
+        state.end_session(11 + DISPUTE_WINDOW_SLOTS).unwrap();
+        assert_eq!(state.ended_sessions[0].session, 2);
+    }
+
+    #[test]
+    fn test_record() {
+        let mut state = BandwidthPrepayState::default();
+        for amount in 0..HISTORY_LEN as u64 + 2 {
+            state.record(EventKind::Spend, amount, Some(amount));
+        }
+        assert_eq!(state.history.len(), HISTORY_LEN);
+        assert_eq!(state.history[0].amount, 2);
+        assert_eq!(
+            state.history.last(),
+            Some(&ContractEvent {
+                kind: EventKind::Spend,
+                amount: HISTORY_LEN as u64 + 1,
+                slot: Some(HISTORY_LEN as u64 + 1),
+            })
+        );
+    }
+
+    #[test]
+    fn test_allows_destination() {
+        let mut state = BandwidthPrepayState::default();
+        assert!(state.allows_destination("127.0.0.1:1234"));
+
+        state.allowed_destinations = vec![destination_hash("127.0.0.1:1234")];
+        assert!(state.allows_destination("127.0.0.1:1234"));
+        assert!(!state.allows_destination("127.0.0.1:1235"));
+        assert!(!state.allows_destination("localhost:1234"));
+    }
+
+    #[test]
+    fn test_price() {
+        let state = BandwidthPrepayState {
+            lamports_per_kib: 3,
+            ..BandwidthPrepayState::default()
+        };
+        assert_eq!(state.price(0), 0);
+        assert_eq!(state.price(1023), 2);
+        assert_eq!(state.price(1024), 3);
+        assert_eq!(
+            state.price(u64::max_value()),
+            3 * (u64::max_value() / 1024) + 2
+        );
+    }
+
+    #[test]
+    fn test_within_data_cap() {
+        let mut state = BandwidthPrepayState {
+            max_bytes: Some(100),
+            lifetime_bytes: 60,
+            total_bytes: 20,
+            ..BandwidthPrepayState::default()
+        };
+        assert_eq!(state.lifetime_bytes_at(20), 60);
+        assert_eq!(state.lifetime_bytes_at(60), 100);
+        assert!(state.within_data_cap(60));
+        assert!(!state.within_data_cap(61));
+
+        state.max_bytes = None;
+        assert!(state.within_data_cap(u64::max_value()));
+    }
+
+    #[test]
+    fn test_fee() {
+        let state = BandwidthPrepayState {
+            gatekeeper_fee: 5,
+            ..BandwidthPrepayState::default()
+        };
+        assert_eq!(state.fee(0), 0);
+        assert_eq!(state.fee(1), 0);
+        assert_eq!(state.fee(49), 4);
+        assert_eq!(state.fee(50), 5);
+        assert_eq!(state.fee(u64::max_value()), 5);
+    }
+
+    #[test]
+    fn test_allowance() {
+        let mut state = BandwidthPrepayState {
+            lamports_per_kib: 3,
+            lamports_per_slot: 2,
+            ..BandwidthPrepayState::default()
+        };
+        assert_eq!(state.session_slots(Some(10)), 0);
+        assert_eq!(state.allowance(1024, 0), 3);
+        assert_eq!(state.allowance(1024, 5), 13);
+        assert_eq!(state.allowance(0, u64::max_value()), u64::max_value());
+
+        state.session_start_slot = Some(4);
+        assert_eq!(state.session_slots(None), 0);
+        assert_eq!(state.session_slots(Some(3)), 0);
+        assert_eq!(state.session_slots(Some(10)), 6);
+    }
 }
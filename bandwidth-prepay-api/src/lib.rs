@@ -1,5 +1,10 @@
+pub mod bandwidth_prepay_bond;
+pub mod bandwidth_prepay_frame;
+pub mod bandwidth_prepay_history;
 pub mod bandwidth_prepay_instruction;
 pub mod bandwidth_prepay_processor;
+pub mod bandwidth_prepay_receipt;
+pub mod bandwidth_prepay_registry;
 pub mod bandwidth_prepay_state;
 
 const BANDWIDTH_PREPAY_PROGRAM_ID: [u8; 32] = [
@@ -1,10 +1,11 @@
 use crate::cli::Config;
 use crate::gen_keys::GenKeys;
-use bandwidth_prepay_api::bandwidth_prepay_instruction;
+use bandwidth_prepay_api::bandwidth_prepay_instruction::{self, ContractTerms};
 use gatekeeper::accumulator::Accumulator;
 use gatekeeper::connection_params::NewConnParams;
-use gatekeeper::contract::{check_contract, submit_transaction_loop};
-use gatekeeper::gatekeeper::process_data;
+use gatekeeper::contract::{check_contract, submit_loop, Submission};
+use gatekeeper::gatekeeper::{check_pubsub, process_data};
+use gatekeeper::pricing::FlatRate;
 use log::*;
 use pubsub_client::client::start_pubsub;
 use pubsub_client::request::PubSubRequest;
@@ -12,7 +13,6 @@ use solana_sdk::client::Client;
 use solana_sdk::message::Message;
 use solana_sdk::pubkey::Pubkey;
 use solana_sdk::signature::{Keypair, KeypairUtil, Signature};
-use solana_sdk::system_instruction;
 use solana_sdk::transport::Result as TransportResult;
 use std::sync::mpsc::channel;
 use std::sync::Arc;
@@ -39,6 +39,15 @@ where
     let client = Arc::new(client);
     let gatekeeper_keypairs: Vec<_> = gatekeeper_keypairs.into_iter().map(Arc::new).collect();
     let client_keypairs: Vec<_> = client_keypairs.into_iter().map(Arc::new).collect();
+    if config.batch {
+        return do_batched_bandwidth_tps(
+            client,
+            &config,
+            gatekeeper_keypairs,
+            client_keypairs,
+            contracts,
+        );
+    }
 
     let threads: Vec<_> = contracts
         .into_iter()
@@ -66,27 +75,37 @@ where
                     .unwrap();
 
                     let (solana_sender, solana_receiver) = channel();
+                    let submit_client = client.clone();
+                    let submit_gatekeeper = gatekeeper.clone();
                     thread::spawn(move || {
-                        submit_transaction_loop(&solana_receiver);
+                        submit_loop(&*submit_client, &submit_gatekeeper, &solana_receiver);
                     });
 
-                    let (balance, contract_state) =
-                        check_contract(&params, &client, &gatekeeper.pubkey()).unwrap();
+                    let (balance, mut contract_state) =
+                        check_contract(&params.contract_pubkey, &client, &gatekeeper.pubkey())
+                            .unwrap();
 
-                    let mut accumulator = Accumulator::default();
+                    let mut accumulator = Accumulator::new(FlatRate {
+                        lamports_per_kib: contract_state.lamports_per_kib,
+                    });
                     accumulator.initiator_fund = balance;
 
                     // Offset contract payments to decrease AccountInUse errors paying provider
                     sleep(Duration::from_millis(150 * i as u64));
                     let mut counter = 0;
                     loop {
-                        if process_data(
+                        if check_pubsub(
                             &params,
                             &gatekeeper,
-                            &client,
-                            &contract_state,
+                            &mut contract_state,
                             &mut accumulator,
                             &pubsub_thread.receiver,
+                        ) || process_data(
+                            &params,
+                            // The contracts here aren't charged for time
+                            None,
+                            &contract_state,
+                            &mut accumulator,
                             1024,
                             &solana_sender,
                         ) {
@@ -125,6 +144,145 @@ where
     Ok(())
 }
 
+/// Like `do_bandwidth_tps`, but every gateway charges all of its contracts
+/// with one `SpendBatch` transaction per round, instead of one `Spend`
+/// transaction per contract
+fn do_batched_bandwidth_tps<T>(
+    client: Arc<T>,
+    config: &Config,
+    gatekeeper_keypairs: Vec<Arc<Keypair>>,
+    client_keypairs: Vec<Arc<Keypair>>,
+    contracts: Vec<Pubkey>,
+) -> TransportResult<()>
+where
+    T: 'static + Client + Send + Sync,
+{
+    let num_gateways = config.num_gateways as usize;
+    let fee_interval = config.fee_interval;
+    let provider = config.provider;
+    let refund_lamports = config.lamports / 5;
+
+    let mut gateway_contracts = vec![vec![]; num_gateways];
+    for (i, contract_pubkey) in contracts.into_iter().enumerate() {
+        gateway_contracts[(i + 1) % num_gateways].push((i, contract_pubkey));
+    }
+
+    let threads: Vec<_> = gateway_contracts
+        .into_iter()
+        .enumerate()
+        .filter(|(_, contracts)| !contracts.is_empty())
+        .map(|(gatekeeper_index, contracts)| {
+            let client = client.clone();
+            let gatekeeper = gatekeeper_keypairs[gatekeeper_index].clone();
+            let client_keypairs = client_keypairs.clone();
+            Builder::new()
+                .name("gatekeeper".to_string())
+                .spawn(move || {
+                    let (solana_sender, solana_receiver) = channel();
+                    let submit_client = client.clone();
+                    let submit_gatekeeper = gatekeeper.clone();
+                    thread::spawn(move || {
+                        submit_loop(&*submit_client, &submit_gatekeeper, &solana_receiver);
+                    });
+
+                    let mut accumulators: Vec<_> = contracts
+                        .into_iter()
+                        .map(|(i, contract_pubkey)| {
+                            let params = NewConnParams {
+                                contract_pubkey,
+                                destination: "somewhere".to_string(),
+                                fee_interval,
+                            };
+                            let (balance, contract_state) =
+                                check_contract(&contract_pubkey, &client, &gatekeeper.pubkey())
+                                    .unwrap();
+                            let mut accumulator = Accumulator::new(FlatRate {
+                                lamports_per_kib: contract_state.lamports_per_kib,
+                            });
+                            accumulator.initiator_fund = balance;
+                            (i, params, contract_state, accumulator)
+                        })
+                        .collect();
+
+                    // Each contract is charged as a single connection would
+                    // be, and what they owe goes on chain together
+                    let (batch_sender, batch_receiver) = channel();
+                    let mut counter = 0;
+                    loop {
+                        let mut forwarding = false;
+                        for (_, params, contract_state, accumulator) in &mut accumulators {
+                            // The contracts here aren't charged for time
+                            if !process_data(
+                                params,
+                                None,
+                                contract_state,
+                                accumulator,
+                                1024,
+                                &batch_sender,
+                            ) {
+                                forwarding = true;
+                            }
+                        }
+                        let charges: Vec<_> = batch_receiver
+                            .try_iter()
+                            .filter_map(|submission| match submission {
+                                Submission::Spend(charge) => Some(charge),
+                                _ => None,
+                            })
+                            .collect();
+                        if !charges.is_empty() {
+                            let submission = Submission::SpendBatch {
+                                provider_pubkey: provider,
+                                charges,
+                            };
+                            if let Err(e) = solana_sender.send(submission) {
+                                error!("Error sending amounts to be charged: {}", e);
+                            }
+                        }
+                        if !forwarding {
+                            break;
+                        }
+
+                        counter += 1;
+                        if counter == 200 {
+                            // Every 20sec
+                            for (i, params, _, accumulator) in &mut accumulators {
+                                fund_contract(
+                                    &client,
+                                    &params.contract_pubkey,
+                                    &client_keypairs[*i],
+                                    refund_lamports,
+                                )
+                                .unwrap();
+                                accumulator.initiator_fund += refund_lamports;
+                            }
+                            counter = 0;
+                        }
+                        sleep(Duration::from_millis(100));
+                    }
+                    let total_data_amount: u64 = accumulators
+                        .iter()
+                        .map(|(_, _, _, accumulator)| accumulator.total_data_amount)
+                        .sum();
+                    info!(
+                        "Bytes transmitted via gatekeeper {}: {}",
+                        gatekeeper.pubkey(),
+                        total_data_amount
+                    );
+                })
+                .unwrap()
+        })
+        .collect();
+
+    for t in threads {
+        if let Err(err) = t.join() {
+            println!("  join() failed with: {:?}", err);
+        }
+    }
+
+    Ok(())
+}
+
 fn fund_contract<T: Client>(
     client: &Arc<T>,
     contract_pubkey: &Pubkey,
@@ -132,9 +290,9 @@ fn fund_contract<T: Client>(
     lamports: u64,
 ) -> TransportResult<Signature> {
     let (blockhash, _) = client.get_recent_blockhash().unwrap();
-    let instruction =
-        system_instruction::transfer(&client_keypair.pubkey(), contract_pubkey, lamports);
-    let message = Message::new(vec![instruction]);
+    let instructions =
+        bandwidth_prepay_instruction::top_up(&client_keypair.pubkey(), contract_pubkey, lamports);
+    let message = Message::new(instructions);
     let signature = client.async_send_message(&[&client_keypair], message, blockhash)?;
     client.get_signature_status(&signature)?;
     Ok(signature)
@@ -151,13 +309,13 @@ pub fn initialize_contracts<T: Client>(
     let mut contracts = Vec::new();
     for (i, keypair) in client_keypairs.iter().enumerate() {
         let gatekeeper_index = (i + 1) % gatekeeper_keypairs.len();
-        let contract_pubkey = Pubkey::new_rand();
-        let instructions = bandwidth_prepay_instruction::initialize(
+        let (contract_pubkey, instructions) = bandwidth_prepay_instruction::initialize_derived(
             &keypair.pubkey(),
-            &contract_pubkey,
             &gatekeeper_keypairs[gatekeeper_index].pubkey(),
             provider,
+            0,
             lamports,
+            &ContractTerms::default(),
         );
         let message = Message::new(instructions);
         let signature = client.async_send_message(&[&keypair], message, blockhash)?;
@@ -257,6 +415,15 @@ mod tests {
         assert_eq!(state.gatekeeper_id, gatekeeper_keypairs[0].pubkey());
         assert_eq!(state.provider_id, provider);
         assert_eq!(state.initiator_id, client_keypairs[0].pubkey());
+        assert_eq!(
+            contract,
+            bandwidth_prepay_instruction::contract_address(
+                &client_keypairs[0].pubkey(),
+                &gatekeeper_keypairs[0].pubkey(),
+                &provider,
+                0
+            )
+        );
     }
 
     #[test]
@@ -14,6 +14,7 @@ pub struct Config {
     pub fee_interval: u16,
     pub lamports: u64,
     pub provider: Pubkey,
+    pub batch: bool,
 }
 
 impl Default for Config {
@@ -28,6 +29,7 @@ impl Default for Config {
             fee_interval: 1000,
             lamports: 100_000,
             provider: Pubkey::new_rand(),
+            batch: false,
         }
     }
 }
@@ -86,6 +88,12 @@ pub fn build_args<'a, 'b>() -> App<'a, 'b> {
                 .required(true)
                 .help("/path/to/provider/pubkey.json"),
         )
+        .arg(
+            Arg::with_name("batch")
+                .short("b")
+                .long("batch")
+                .help("Charge all of a gateway's contracts with one SpendBatch per interval"),
+        )
 }
 
 /// Parses a clap `ArgMatches` structure into a `Config`
@@ -123,5 +131,7 @@ pub fn extract_args<'a>(matches: &ArgMatches<'a>) -> Config {
             read_pubkey(matches.value_of("provider").unwrap()).expect("can't read provider pubkey");
     }
 
+    args.batch = matches.is_present("batch");
+
     args
 }
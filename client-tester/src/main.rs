@@ -1,14 +1,19 @@
+use bandwidth_prepay_api::bandwidth_prepay_frame::Frame;
+use bandwidth_prepay_api::bandwidth_prepay_instruction::ContractTerms;
 use clap::{App, Arg};
 use client::bandwidth_client::BandwidthClient;
 use pbr::ProgressBar;
 use provider_drone::DEFAULT_DRONE_PORT;
 use solana_client::rpc_client::RpcClient;
 use solana_sdk::pubkey::read_pubkey;
-use solana_sdk::signature::{read_keypair, KeypairUtil};
-use std::io::{Read, Write};
+use solana_sdk::signature::read_keypair;
+use std::io::Write;
 use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::time::Instant;
 
+/// How often, in packets, to acknowledge usage to the gatekeeper
+const PACKETS_PER_RECEIPT: usize = 1000;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
     let matches = App::new("Data Counter Tester")
@@ -27,8 +32,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .long("gatekeeper-pubkey")
                 .value_name("PATH")
                 .takes_value(true)
-                .required(true)
-                .help("/path/to/gatekeeper/pubkey.json"),
+                .help(
+                    "/path/to/gatekeeper/pubkey.json. Defaults to the cheapest registered \
+                     gatekeeper forwarding to the destination",
+                ),
         )
         .arg(
             Arg::with_name("provider")
@@ -53,8 +60,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .long("gatekeeper")
                 .value_name("HOST:PORT")
                 .takes_value(true)
-                .required(true)
-                .help("Gatekeeper RPC endpoint"),
+                .help("Gatekeeper RPC endpoint. Defaults to the one it registered"),
         )
         .arg(
             Arg::with_name("destination")
@@ -92,7 +98,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .get_matches();
 
     let client_account = read_keypair(matches.value_of("keypair").unwrap())?;
-    let gatekeeper_pubkey = read_pubkey(matches.value_of("gatekeeper_pubkey").unwrap())?;
     let provider_pubkey = read_pubkey(matches.value_of("provider").unwrap())?;
 
     // Set up Solana bandwidth prepayment contract
@@ -119,20 +124,59 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let fullnode_client = RpcClient::new_socket(rpc_addr);
     let client = BandwidthClient::new(client_account, fullnode_client);
 
-    let drone_addr = SocketAddr::new(host, DEFAULT_DRONE_PORT);
-    client.request_airdrop(&drone_addr, lamports + 1)?;
-    let prepay_account = client.initialize_contract(lamports, &gatekeeper_pubkey, &provider_pubkey);
-
-    let gatekeeper_addr = matches.value_of("gatekeeper_addr").unwrap();
     let destination = matches.value_of("destination").unwrap();
+    let (gatekeeper_pubkey, gatekeeper_addr, terms) = match matches.value_of("gatekeeper_pubkey") {
+        Some(path) => {
+            let gatekeeper_pubkey = read_pubkey(path)?;
+            let gatekeeper_addr = match matches.value_of("gatekeeper_addr") {
+                Some(gatekeeper_addr) => gatekeeper_addr.to_string(),
+                None => {
+                    client
+                        .get_gatekeeper_registration(&gatekeeper_pubkey)?
+                        .info
+                        .endpoint
+                }
+            };
+            (gatekeeper_pubkey, gatekeeper_addr, ContractTerms::default())
+        }
+        None => {
+            let registration = client
+                .find_gatekeeper(destination)?
+                .ok_or("No registered gatekeeper forwards to the destination")?;
+            println!(
+                "Using gatekeeper {} at {}",
+                registration.gatekeeper_id, registration.info.endpoint
+            );
+            let terms = ContractTerms {
+                lamports_per_kib: registration.info.lamports_per_kib,
+                require_registered_gatekeeper: true,
+                ..ContractTerms::default()
+            };
+            let gatekeeper_addr = matches
+                .value_of("gatekeeper_addr")
+                .map_or(registration.info.endpoint, str::to_string);
+            (registration.gatekeeper_id, gatekeeper_addr, terms)
+        }
+    };
     let destination: SocketAddr = destination.parse()?;
 
+    let drone_addr = SocketAddr::new(host, DEFAULT_DRONE_PORT);
+    client.request_airdrop(&drone_addr, lamports + 1)?;
+    let prepay_account = client.initialize_contract_with_terms(
+        lamports,
+        &gatekeeper_pubkey,
+        &provider_pubkey,
+        &terms,
+    )?;
+    let contract_state = client.get_contract_state(&prepay_account)?;
+
+    let gatekeeper_addr = gatekeeper_addr.as_str();
     let data_addr =
-        client.request_connection(gatekeeper_addr, destination, &prepay_account.pubkey())?;
+        client.request_framed_connection(gatekeeper_addr, destination, &prepay_account)?;
 
     let mut data_addr = TcpStream::connect(data_addr)?;
 
-    let to_send: Vec<u8> = vec![0; packet_size];
+    let to_send = Frame::Data(vec![0; packet_size]).to_bytes()?;
 
     let mut pings: Vec<u32> = Vec::with_capacity(num_packets);
 
@@ -140,15 +184,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     pb.message("Packets recieved: ");
 
     let begin = Instant::now();
-    for _ in 0..num_packets {
+    for i in 0..num_packets {
         let start = Instant::now();
         data_addr.write_all(&to_send)?;
 
-        let mut data = [0 as u8; 1024];
-        let amount = data_addr.read(&mut data)?;
+        // The echo may come back split across frames
+        let mut amount = 0;
+        while amount < packet_size {
+            match Frame::read_from(&mut data_addr)? {
+                Frame::Data(data) => amount += data.len(),
+                frame => return Err(format!("Unexpected frame: {:?}", frame).into()),
+            }
+        }
         pings.push(start.elapsed().subsec_micros());
         assert_eq!(amount, packet_size);
         pb.inc();
+
+        if (i + 1) % PACKETS_PER_RECEIPT == 0 {
+            // Every packet passes through the gatekeeper twice
+            let total_bytes = contract_state.total_bytes + (2 * packet_size * (i + 1)) as u64;
+            client.send_receipt(
+                &mut data_addr,
+                &prepay_account,
+                &contract_state,
+                total_bytes,
+                contract_state.price(total_bytes),
+            )?;
+        }
     }
     let time = begin.elapsed().subsec_micros();
     pb.finish();
@@ -162,6 +224,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         ((packet_size * num_packets * 2) as f64 / (f64::from(time) / 1_000_000f64)) / 1_000_000f64
     );
 
+    println!("Contract statement:");
+    for event in client.get_contract_history(&prepay_account)? {
+        println!("  {}", event);
+    }
+
     data_addr.shutdown(Shutdown::Both)?;
 
     Ok(())
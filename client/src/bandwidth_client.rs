@@ -1,7 +1,14 @@
-use bandwidth_prepay_api::bandwidth_prepay_instruction;
+use bandwidth_prepay_api::bandwidth_prepay_frame::Frame;
+use bandwidth_prepay_api::bandwidth_prepay_history::ContractEvent;
+use bandwidth_prepay_api::bandwidth_prepay_instruction::{self, ContractTerms};
+use bandwidth_prepay_api::bandwidth_prepay_receipt::UsageReceipt;
+use bandwidth_prepay_api::bandwidth_prepay_registry::{
+    registration_address, GatekeeperRegistration,
+};
+use bandwidth_prepay_api::bandwidth_prepay_state::{BandwidthPrepayError, BandwidthPrepayState};
 use log::{error, info};
 use serde_derive::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
 use solana_client::rpc_client::RpcClient;
 use solana_client::rpc_request::RpcError;
 use solana_drone::drone::request_airdrop_transaction;
@@ -11,11 +18,15 @@ use solana_sdk::signature::{Keypair, KeypairUtil};
 use solana_sdk::transaction::Transaction;
 use std::collections::HashMap;
 use std::error;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
 
 const MESSAGE_TERMINATOR: &str = "\n";
 
+/// `find_contracts` stops after this many consecutive nonces without a
+/// contract, so a closed contract doesn't hide the ones after it
+const CONTRACT_NONCE_GAP: u64 = 8;
+
 #[derive(Debug, Deserialize)]
 struct RpcResponse {
     jsonrpc: String,
@@ -63,28 +74,257 @@ impl BandwidthClient {
         lamports: u64,
         gatekeeper_pubkey: &Pubkey,
         provider_pubkey: &Pubkey,
-    ) -> Keypair {
-        let prepay_account = Keypair::new(); // New contract account
+    ) -> Result<Pubkey, RpcError> {
+        self.initialize_contract_with_terms(
+            lamports,
+            gatekeeper_pubkey,
+            provider_pubkey,
+            &ContractTerms::default(),
+        )
+    }
+
+    pub fn initialize_contract_with_terms(
+        &self,
+        lamports: u64,
+        gatekeeper_pubkey: &Pubkey,
+        provider_pubkey: &Pubkey,
+        terms: &ContractTerms,
+    ) -> Result<Pubkey, RpcError> {
+        let nonce = self.next_contract_nonce(gatekeeper_pubkey, provider_pubkey)?;
         let (blockhash, _) = self
             .fullnode_client
             .get_recent_blockhash()
             .unwrap_or_default();
 
-        let instructions = bandwidth_prepay_instruction::initialize(
+        // The nonce of a contract whose account was removed can come round
+        // again, so tell this contract's receipts apart from that one's
+        let terms = ContractTerms {
+            instance: rand::random(),
+            ..terms.clone()
+        };
+        let (prepay_account, instructions) = bandwidth_prepay_instruction::initialize_derived(
             &self.id.pubkey(),
-            &prepay_account.pubkey(),
             &gatekeeper_pubkey,
             &provider_pubkey,
+            nonce,
             lamports,
+            &terms,
         );
         let message = Message::new(instructions);
         let mut transaction = Transaction::new(&[&self.id], message, blockhash);
         let _ = self
             .fullnode_client
             .send_and_confirm_transaction(&mut transaction, &[&self.id])
-            .unwrap();
+            .map_err(|err| {
+                info!("initialize_contract: SendTransaction error: {:?}", err);
+                RpcError::RpcRequestError(err.to_string())
+            })?;
+
+        Ok(prepay_account)
+    }
+
+    /// Contracts this client holds with `gatekeeper_pubkey` and
+    /// `provider_pubkey`, with the nonces their addresses were derived from
+    pub fn find_contracts(
+        &self,
+        gatekeeper_pubkey: &Pubkey,
+        provider_pubkey: &Pubkey,
+    ) -> Result<Vec<(u64, Pubkey, BandwidthPrepayState)>, RpcError> {
+        let mut contracts = vec![];
+        let mut misses = 0;
+        let mut nonce = 0;
+        while misses < CONTRACT_NONCE_GAP {
+            let prepay_account = bandwidth_prepay_instruction::contract_address(
+                &self.id.pubkey(),
+                gatekeeper_pubkey,
+                provider_pubkey,
+                nonce,
+            );
+            match self.get_derived_account_data(&prepay_account)? {
+                Some(data) => {
+                    // Some other account at the address is no contract of ours
+                    if let Ok(state) = BandwidthPrepayState::deserialize(&data) {
+                        contracts.push((nonce, prepay_account, state));
+                    }
+                    misses = 0;
+                }
+                None => misses += 1,
+            }
+            nonce += 1;
+        }
+        Ok(contracts)
+    }
+
+    /// Lowest nonce whose derived contract address holds no account
+    fn next_contract_nonce(
+        &self,
+        gatekeeper_pubkey: &Pubkey,
+        provider_pubkey: &Pubkey,
+    ) -> Result<u64, RpcError> {
+        for nonce in 0.. {
+            let prepay_account = bandwidth_prepay_instruction::contract_address(
+                &self.id.pubkey(),
+                gatekeeper_pubkey,
+                provider_pubkey,
+                nonce,
+            );
+            if self.get_derived_account_data(&prepay_account)?.is_none() {
+                return Ok(nonce);
+            }
+        }
+        unreachable!()
+    }
+
+    /// Data of the account at a derived address, or `None` if there is no
+    /// account there. Any other failure is an error, so that an unreachable
+    /// fullnode isn't taken for a free address
+    fn get_derived_account_data(&self, address: &Pubkey) -> Result<Option<Vec<u8>>, RpcError> {
+        // The fullnode reports no balance for an address without an account,
+        // where fetching the account fails the same way as an unanswered
+        // request would
+        let balance = self.fullnode_client.get_balance(address).map_err(|err| {
+            info!("get_balance failed: {:?}", err);
+            RpcError::RpcRequestError(err.to_string())
+        })?;
+        if balance == 0 {
+            return Ok(None);
+        }
+        self.fullnode_client
+            .get_account_data(address)
+            .map(Some)
+            .map_err(|err| {
+                info!("get_account_data failed: {:?}", err);
+                RpcError::RpcRequestError(err.to_string())
+            })
+    }
+
+    pub fn get_contract_state(
+        &self,
+        prepay_account: &Pubkey,
+    ) -> Result<BandwidthPrepayState, RpcError> {
+        let data = self
+            .fullnode_client
+            .get_account_data(prepay_account)
+            .map_err(|err| {
+                info!("get_account_data failed: {:?}", err);
+                RpcError::RpcRequestError(err.to_string())
+            })?;
+        BandwidthPrepayState::deserialize(&data)
+            .map_err(|err| RpcError::RpcRequestError(err.to_string()))
+    }
 
-        prepay_account
+    /// Recent operations on the contract, oldest first, for showing the
+    /// initiator a statement
+    pub fn get_contract_history(
+        &self,
+        prepay_account: &Pubkey,
+    ) -> Result<Vec<ContractEvent>, RpcError> {
+        self.get_contract_state(prepay_account)
+            .map(|state| state.history)
+    }
+
+    /// What `gatekeeper_pubkey` publishes about itself, if it is registered
+    pub fn get_gatekeeper_registration(
+        &self,
+        gatekeeper_pubkey: &Pubkey,
+    ) -> Result<GatekeeperRegistration, RpcError> {
+        let data = self
+            .fullnode_client
+            .get_account_data(&registration_address(gatekeeper_pubkey))
+            .map_err(|err| {
+                info!("get_account_data failed: {:?}", err);
+                RpcError::RpcRequestError(err.to_string())
+            })?;
+        GatekeeperRegistration::deserialize(&data)
+            .map_err(|err| RpcError::RpcRequestError(err.to_string()))
+    }
+
+    /// Every gatekeeper registered with the prepay program
+    pub fn discover_gatekeepers(&self) -> Result<Vec<GatekeeperRegistration>, RpcError> {
+        let accounts = self
+            .fullnode_client
+            .get_program_accounts(&bandwidth_prepay_api::id())
+            .map_err(|err| {
+                info!("get_program_accounts failed: {:?}", err);
+                RpcError::RpcRequestError(err.to_string())
+            })?;
+        Ok(accounts
+            .into_iter()
+            .filter_map(|(pubkey, account)| {
+                GatekeeperRegistration::deserialize(&account.data)
+                    .ok()
+                    .filter(|registration| {
+                        pubkey == registration_address(&registration.gatekeeper_id)
+                    })
+            })
+            .collect())
+    }
+
+    /// The cheapest registered gatekeeper forwarding to `destination`, given
+    /// as HOST:PORT
+    pub fn find_gatekeeper(
+        &self,
+        destination: &str,
+    ) -> Result<Option<GatekeeperRegistration>, RpcError> {
+        Ok(self
+            .discover_gatekeepers()?
+            .into_iter()
+            .filter(|registration| {
+                let destinations = &registration.info.destinations;
+                destinations.is_empty() || destinations.iter().any(|d| d == destination)
+            })
+            .min_by_key(|registration| registration.info.lamports_per_kib))
+    }
+
+    pub fn top_up_contract(&self, prepay_account: &Pubkey, lamports: u64) -> Result<(), RpcError> {
+        let (blockhash, _) = self.fullnode_client.get_recent_blockhash().map_err(|err| {
+            info!("get_recent_blockhash failed: {:?}", err);
+            RpcError::RpcRequestError(err.to_string())
+        })?;
+
+        let instructions =
+            bandwidth_prepay_instruction::top_up(&self.id.pubkey(), prepay_account, lamports);
+        let message = Message::new(instructions);
+        let mut transaction = Transaction::new(&[&self.id], message, blockhash);
+        self.send_contract_transaction("top_up_contract", &mut transaction)
+    }
+
+    /// Return the remaining balance of an expired contract to this client
+    pub fn reclaim_contract(&self, prepay_account: &Pubkey) -> Result<(), RpcError> {
+        let (blockhash, _) = self.fullnode_client.get_recent_blockhash().map_err(|err| {
+            info!("get_recent_blockhash failed: {:?}", err);
+            RpcError::RpcRequestError(err.to_string())
+        })?;
+
+        let instruction = bandwidth_prepay_instruction::reclaim(&self.id.pubkey(), prepay_account);
+        let message = Message::new(vec![instruction]);
+        let mut transaction = Transaction::new(&[&self.id], message, blockhash);
+        self.send_contract_transaction("reclaim_contract", &mut transaction)
+    }
+
+    /// Send a transaction carrying prepay program instructions, reporting the
+    /// program's reason if it rejects them
+    fn send_contract_transaction(
+        &self,
+        context: &str,
+        transaction: &mut Transaction,
+    ) -> Result<(), RpcError> {
+        let _ = self
+            .fullnode_client
+            .send_and_confirm_transaction(transaction, &[&self.id])
+            .map_err(|err| {
+                let signature = transaction.signatures[0].to_string();
+                let reason = match self.fullnode_client.get_signature_status(&signature) {
+                    Ok(Some(Err(e))) => {
+                        BandwidthPrepayError::from_transaction_error(&e).map(|e| e.to_string())
+                    }
+                    _ => None,
+                }
+                .unwrap_or_else(|| err.to_string());
+                info!("{}: SendTransaction error: {}", context, reason);
+                RpcError::RpcRequestError(reason)
+            })?;
+        Ok(())
     }
 
     pub fn request_connection<A, B>(
@@ -97,8 +337,36 @@ impl BandwidthClient {
         SocketAddr: std::convert::From<B>,
         A: ToSocketAddrs,
     {
-        let mut gatekeeper = TcpStream::connect(gatekeeper_addr)?;
+        self.new_connection(gatekeeper_addr, destination_addr, prepay_account, false)
+    }
 
+    /// Like `request_connection`, but traffic on the connection goes in
+    /// `Frame`s both ways, so receipts can be sent along with it using
+    /// `send_receipt`
+    pub fn request_framed_connection<A, B>(
+        &self,
+        gatekeeper_addr: A,
+        destination_addr: B,
+        prepay_account: &Pubkey,
+    ) -> Result<SocketAddr, Box<dyn error::Error>>
+    where
+        SocketAddr: std::convert::From<B>,
+        A: ToSocketAddrs,
+    {
+        self.new_connection(gatekeeper_addr, destination_addr, prepay_account, true)
+    }
+
+    fn new_connection<A, B>(
+        &self,
+        gatekeeper_addr: A,
+        destination_addr: B,
+        prepay_account: &Pubkey,
+        framed: bool,
+    ) -> Result<SocketAddr, Box<dyn error::Error>>
+    where
+        SocketAddr: std::convert::From<B>,
+        A: ToSocketAddrs,
+    {
         let destination_addr = SocketAddr::from(destination_addr);
 
         let request_json = json!({
@@ -108,10 +376,54 @@ impl BandwidthClient {
                 "destination": format!("{}", destination_addr),
                 "contract_pubkey": format!("{}", prepay_account),
                 "initiator_pubkey": format!("{}", self.id.pubkey()),
+                "framed": framed,
             },
             "id": 1,
         });
-        let request = serde_json::to_string(&request_json).unwrap();
+        let (response, mut conn_addr) = Self::send_request(gatekeeper_addr, &request_json)?;
+
+        conn_addr.set_port(
+            response
+                .result
+                .get(&"port".to_string())
+                .expect("No port returned")
+                .parse()?,
+        );
+
+        Ok(conn_addr)
+    }
+
+    /// Sign a receipt for the usage so far in the contract's current session,
+    /// as of `contract_state`, and send it on a framed connection
+    pub fn send_receipt<W: Write>(
+        &self,
+        connection: &mut W,
+        prepay_account: &Pubkey,
+        contract_state: &BandwidthPrepayState,
+        total_bytes: u64,
+        total_lamports: u64,
+    ) -> io::Result<()> {
+        let receipt = UsageReceipt::new(
+            prepay_account,
+            contract_state.instance,
+            contract_state.session_count,
+            total_bytes,
+            total_lamports,
+        );
+        let signature = receipt.sign(&self.id);
+        connection.write_all(&Frame::Receipt(receipt, signature).to_bytes()?)
+    }
+
+    fn send_request<A>(
+        gatekeeper_addr: A,
+        request_json: &Value,
+    ) -> Result<(RpcResponse, SocketAddr), Box<dyn error::Error>>
+    where
+        A: ToSocketAddrs,
+    {
+        let mut gatekeeper = TcpStream::connect(gatekeeper_addr)?;
+
+        let request = serde_json::to_string(request_json).unwrap();
         let payload = format!("{}{}", request, MESSAGE_TERMINATOR);
         info!("Sending: {}", payload);
 
@@ -130,16 +442,32 @@ impl BandwidthClient {
         };
         info!("Recieved: {:?}", response);
 
-        let mut conn_addr = gatekeeper.peer_addr()?;
-        conn_addr.set_port(
-            response
-                .result
-                .get(&"port".to_string())
-                .expect("No port returned")
-                .parse()?,
-        );
-
+        let peer_addr = gatekeeper.peer_addr()?;
         gatekeeper.shutdown(Shutdown::Both)?;
-        Ok(conn_addr)
+        Ok((response, peer_addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_find_contracts_unreachable_fullnode() {
+        // Nothing listens on a port that has just been released
+        let fullnode_addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let client = BandwidthClient::new(Keypair::new(), RpcClient::new_socket(fullnode_addr));
+        let gatekeeper_pubkey = Pubkey::new_rand();
+        let provider_pubkey = Pubkey::new_rand();
+        assert!(client
+            .find_contracts(&gatekeeper_pubkey, &provider_pubkey)
+            .is_err());
+        assert!(client
+            .next_contract_nonce(&gatekeeper_pubkey, &provider_pubkey)
+            .is_err());
     }
 }
@@ -1,19 +1,36 @@
+use crate::pricing::PricingPolicy;
 use std::time::Instant;
 
-pub struct Accumulator {
+pub struct Accumulator<P: PricingPolicy> {
+    pub policy: P,
     pub total_data_amount: u64,
+    /// Lamports owed for the session so far, including `amount_charged`
+    pub total_cost: u64,
     pub amount_charged: u64,
     pub initiator_fund: u64,
+    /// Most recent slot seen, for the time the contract allows charging for
+    pub slot: Option<u64>,
+    pub started: Instant,
     pub now: Instant,
 }
 
-impl Default for Accumulator {
-    fn default() -> Accumulator {
+impl<P: PricingPolicy> Accumulator<P> {
+    pub fn new(policy: P) -> Self {
         Accumulator {
+            policy,
             total_data_amount: 0,
+            total_cost: 0,
             amount_charged: 0,
             initiator_fund: 0,
+            slot: None,
+            started: Instant::now(),
             now: Instant::now(),
         }
     }
+
+    /// Lamports the policy asks for once the session has carried
+    /// `total_bytes`
+    pub fn price(&self, total_bytes: u64) -> u64 {
+        self.policy.price(total_bytes, self.started.elapsed())
+    }
 }
@@ -1,44 +1,48 @@
 use crate::connection_params::NewConnParams;
 use bandwidth_prepay_api::bandwidth_prepay_instruction;
-use bandwidth_prepay_api::bandwidth_prepay_state::BandwidthPrepayState;
+use bandwidth_prepay_api::bandwidth_prepay_receipt::UsageReceipt;
+use bandwidth_prepay_api::bandwidth_prepay_registry::{
+    registration_address, GatekeeperInfo, GatekeeperRegistration,
+};
+use bandwidth_prepay_api::bandwidth_prepay_state::{BandwidthPrepayError, BandwidthPrepayState};
 use bs58;
 use jsonrpc_core::types::error::Error;
 use log::*;
 use solana_sdk::client::Client;
 use solana_sdk::message::Message;
 use solana_sdk::pubkey::Pubkey;
-use solana_sdk::signature::{Keypair, KeypairUtil};
-use solana_sdk::transaction::Transaction;
+use solana_sdk::signature::{Keypair, KeypairUtil, Signature};
 use solana_sdk::transport::{Result as TransportResult, TransportError};
+use std::collections::HashMap;
 use std::sync::mpsc::Receiver;
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
 use std::{io, mem};
 
 pub fn check_contract<T: Client>(
-    parsed_params: &NewConnParams,
+    contract_pubkey: &Pubkey,
     client: &Arc<T>,
     gatekeeper_id: &Pubkey,
 ) -> TransportResult<(u64, BandwidthPrepayState)> {
-    let data = client.get_account_data(&parsed_params.contract_pubkey)?;
+    let data = client.get_account_data(contract_pubkey)?;
     if data.is_none() {
         return Err(TransportError::IoError(io::Error::new(
             io::ErrorKind::Other,
             "Contract account contains no data".to_string(),
         )));
     }
-    let lamports = client.get_balance(&parsed_params.contract_pubkey)?;
+    let lamports = client.get_balance(contract_pubkey)?;
     let contract_state = BandwidthPrepayState::deserialize(&data.unwrap()).map_err(|err| {
         error!(
             "unable to deserialize contract account: {:?}, {}",
-            parsed_params.contract_pubkey, err
+            contract_pubkey, err
         );
         TransportError::IoError(io::Error::new(
             io::ErrorKind::Other,
             format!("Unable to deserialize contract account: {:?}", err),
         ))
     })?;
+    // Always compare against the on-chain key so a contract handed over with
+    // `ChangeGatekeeper` is served by its new gatekeeper only
     if gatekeeper_id != &contract_state.gatekeeper_id {
         error!(
             "incorrect contract_state gatekeeper_id: {:?}",
@@ -71,64 +75,245 @@ pub fn verify_pubkey(input: String) -> Result<Pubkey, Error> {
     }
 }
 
+/// Keeps `receipt` in `latest` if it is signed by the contract's initiator
+/// for the current session and acknowledges more lamports than the receipt
+/// already held for that session
+pub fn record_receipt(
+    latest: &mut Option<(UsageReceipt, Signature)>,
+    contract_state: &BandwidthPrepayState,
+    receipt: UsageReceipt,
+    signature: Signature,
+) -> bool {
+    if !receipt.verify(&signature, &contract_state.initiator_id) {
+        info!(
+            "record_receipt: receipt not signed by initiator {}",
+            contract_state.initiator_id
+        );
+        return false;
+    }
+    if receipt.instance != contract_state.instance {
+        info!(
+            "record_receipt: receipt for another instance of {}",
+            receipt.contract_id
+        );
+        return false;
+    }
+    if receipt.session != contract_state.session_count {
+        info!(
+            "record_receipt: receipt for session {} of {}, expected {}",
+            receipt.session, receipt.contract_id, contract_state.session_count
+        );
+        return false;
+    }
+    if let Some((latest, _)) = latest {
+        if latest.session == receipt.session && latest.total_lamports >= receipt.total_lamports {
+            info!(
+                "record_receipt: stale receipt for {}: {} <= {}",
+                receipt.contract_id, receipt.total_lamports, latest.total_lamports
+            );
+            return false;
+        }
+    }
+    *latest = Some((receipt, signature));
+    true
+}
+
+/// Log why the program rejected a contract instruction, decoding its error
+/// code when the failure came from the prepay program
+fn log_contract_error(action: &str, contract_pubkey: &Pubkey, err: &TransportError) {
+    let program_error = match err {
+        TransportError::TransactionError(e) => BandwidthPrepayError::from_transaction_error(e),
+        _ => None,
+    };
+    match program_error {
+        Some(e) => error!("{} failed for contract {}: {}", action, contract_pubkey, e),
+        None => error!(
+            "{} failed for contract {}: {:?}",
+            action, contract_pubkey, err
+        ),
+    }
+}
+
 pub fn charge_contract<T: Client>(
     parsed_params: &NewConnParams,
     client: &Arc<T>,
     contract_state: &BandwidthPrepayState,
     gatekeeper: &Keypair,
     amount: u64,
+    total_bytes: u64,
 ) -> TransportResult<()> {
     let message = build_spend_message(
         gatekeeper,
         &parsed_params.contract_pubkey,
-        &contract_state.provider_id,
+        &contract_state.payees(),
         amount,
+        total_bytes,
     );
-    let _ = client.send_message(&[gatekeeper], message)?;
+    let _ = client.send_message(&[gatekeeper], message).map_err(|err| {
+        log_contract_error("Spend", &parsed_params.contract_pubkey, &err);
+        err
+    })?;
     Ok(())
 }
 
 fn build_spend_message(
     gatekeeper: &Keypair,
     contract_pubkey: &Pubkey,
-    provider_id: &Pubkey,
+    payees: &[Pubkey],
     amount: u64,
+    total_bytes: u64,
 ) -> Message {
     let instruction = bandwidth_prepay_instruction::spend(
         &gatekeeper.pubkey(),
         &contract_pubkey,
-        &provider_id,
+        payees,
         amount,
+        total_bytes,
     );
     Message::new(vec![instruction])
 }
 
-pub fn build_and_sign_spend_transaction<T: Client>(
-    client: &Arc<T>,
-    gatekeeper: &Keypair,
-    contract_pubkey: &Pubkey,
-    provider_id: &Pubkey,
-    amount: u64,
-) -> Transaction {
-    let (blockhash, _) = client.get_recent_blockhash().unwrap();
-    let message = build_spend_message(gatekeeper, contract_pubkey, provider_id, amount);
-    Transaction::new(&[gatekeeper], message, blockhash)
+/// A `Spend` for the submitter to put on chain
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Charge {
+    pub contract_pubkey: Pubkey,
+    /// The contract's `BandwidthPrepayState::payees()`
+    pub payees: Vec<Pubkey>,
+    pub amount: u64,
+    pub total_bytes: u64,
+}
+
+/// Work for `submit_loop`
+pub enum Submission {
+    Spend(Charge),
+    /// One `SpendBatch` charging contracts that all pay `provider_pubkey`
+    SpendBatch {
+        provider_pubkey: Pubkey,
+        charges: Vec<Charge>,
+    },
+    /// Run once every earlier submission for the contract has been through,
+    /// with the lamports its failed charges still owe
+    Finish(Pubkey, Box<dyn FnOnce(u64) + Send>),
 }
 
-pub fn submit_transaction_loop<T: Client>(solana_receiver: &Receiver<(Arc<T>, Transaction)>) {
-    loop {
-        if let Ok((client, transaction)) = solana_receiver.try_recv() {
-            if let Err(e) = client.async_send_transaction(transaction) {
-                error!(
-                    "Error sending charge transaction to solana fullnode: {:?}",
-                    e
+/// Put a gatekeeper's charges on chain in the order they were made, each
+/// confirmed before the next is sent. The program only accepts a contract's
+/// cumulative byte count going up, so a charge overtaken by a later one would
+/// be lost. A charge that couldn't be sent is added to the contract's next
+/// one; one the program rejected is dropped. A batch the program rejected is
+/// charged again one contract at a time, so one bad charge doesn't take the
+/// others down with it
+pub fn submit_loop<T: Client>(
+    client: &T,
+    gatekeeper: &Keypair,
+    submissions: &Receiver<Submission>,
+) {
+    // Lamports of failed charges, by contract
+    let mut unpaid: HashMap<Pubkey, u64> = HashMap::new();
+    let owed = |charge: &Charge, unpaid: &mut HashMap<Pubkey, u64>| Charge {
+        amount: charge.amount + unpaid.remove(&charge.contract_pubkey).unwrap_or(0),
+        ..charge.clone()
+    };
+    for submission in submissions.iter() {
+        match submission {
+            Submission::Spend(charge) => {
+                let charge = owed(&charge, &mut unpaid);
+                submit_spend(client, gatekeeper, charge, &mut unpaid);
+            }
+            Submission::SpendBatch {
+                provider_pubkey,
+                charges,
+            } => {
+                let charges: Vec<_> = charges
+                    .iter()
+                    .map(|charge| owed(charge, &mut unpaid))
+                    .collect();
+                let spends: Vec<_> = charges
+                    .iter()
+                    .map(|charge| (charge.contract_pubkey, charge.amount, charge.total_bytes))
+                    .collect();
+                let instruction = bandwidth_prepay_instruction::spend_batch(
+                    &gatekeeper.pubkey(),
+                    &provider_pubkey,
+                    &spends,
                 );
-            };
+                let message = Message::new(vec![instruction]);
+                if let Err(err) = client.send_message(&[gatekeeper], message) {
+                    log_contract_error("SpendBatch", &provider_pubkey, &err);
+                    if is_program_error(&err) {
+                        for charge in charges {
+                            submit_spend(client, gatekeeper, charge, &mut unpaid);
+                        }
+                    } else {
+                        for charge in charges {
+                            unpaid.insert(charge.contract_pubkey, charge.amount);
+                        }
+                    }
+                }
+            }
+            Submission::Finish(contract_pubkey, finish) => {
+                finish(unpaid.remove(&contract_pubkey).unwrap_or(0));
+            }
+        }
+    }
+}
+
+/// Send `charge` as a `Spend`, keeping it in `unpaid` if it couldn't be sent
+fn submit_spend<T: Client>(
+    client: &T,
+    gatekeeper: &Keypair,
+    charge: Charge,
+    unpaid: &mut HashMap<Pubkey, u64>,
+) {
+    let message = build_spend_message(
+        gatekeeper,
+        &charge.contract_pubkey,
+        &charge.payees,
+        charge.amount,
+        charge.total_bytes,
+    );
+    if let Err(err) = client.send_message(&[gatekeeper], message) {
+        log_contract_error("Spend", &charge.contract_pubkey, &err);
+        if !is_program_error(&err) {
+            unpaid.insert(charge.contract_pubkey, charge.amount);
+        }
+    }
+}
+
+/// Whether the program rejected the transaction, so sending it again would
+/// fail the same way
+fn is_program_error(err: &TransportError) -> bool {
+    match err {
+        TransportError::TransactionError(e) => {
+            BandwidthPrepayError::from_transaction_error(e).is_some()
         }
-        thread::sleep(Duration::from_millis(10));
+        _ => false,
     }
 }
 
+pub fn settle_contract<T: Client>(
+    parsed_params: &NewConnParams,
+    client: &Arc<T>,
+    contract_state: &BandwidthPrepayState,
+    gatekeeper: &Keypair,
+    receipt: &UsageReceipt,
+    signature: &Signature,
+) -> TransportResult<()> {
+    let instruction = bandwidth_prepay_instruction::settle(
+        &gatekeeper.pubkey(),
+        &parsed_params.contract_pubkey,
+        &contract_state.payees(),
+        receipt,
+        signature,
+    );
+    let message = Message::new(vec![instruction]);
+    let _ = client.send_message(&[gatekeeper], message).map_err(|err| {
+        log_contract_error("Settle", &parsed_params.contract_pubkey, &err);
+        err
+    })?;
+    Ok(())
+}
+
 pub fn refund<T: Client>(
     parsed_params: &NewConnParams,
     client: &Arc<T>,
@@ -141,20 +326,109 @@ pub fn refund<T: Client>(
         &contract_state.initiator_id,
     );
     let message = Message::new(vec![instruction]);
-    let _ = client.send_message(&[gatekeeper], message)?;
+    let _ = client.send_message(&[gatekeeper], message).map_err(|err| {
+        log_contract_error("Refund", &parsed_params.contract_pubkey, &err);
+        err
+    })?;
+    Ok(())
+}
+
+pub fn close_contract<T: Client>(
+    parsed_params: &NewConnParams,
+    client: &Arc<T>,
+    contract_state: &BandwidthPrepayState,
+    gatekeeper: &Keypair,
+) -> TransportResult<()> {
+    let instruction = bandwidth_prepay_instruction::close(
+        &gatekeeper.pubkey(),
+        &parsed_params.contract_pubkey,
+        &contract_state.initiator_id,
+    );
+    let message = Message::new(vec![instruction]);
+    let _ = client.send_message(&[gatekeeper], message).map_err(|err| {
+        log_contract_error("Close", &parsed_params.contract_pubkey, &err);
+        err
+    })?;
+    Ok(())
+}
+
+/// Charge the last `final_spend` lamports of a session and leave the rest of
+/// the balance in the contract for the initiator's next connection
+pub fn end_session<T: Client>(
+    parsed_params: &NewConnParams,
+    client: &Arc<T>,
+    contract_state: &BandwidthPrepayState,
+    gatekeeper: &Keypair,
+    final_spend: u64,
+    total_bytes: u64,
+) -> TransportResult<()> {
+    let instruction = bandwidth_prepay_instruction::end_session(
+        &gatekeeper.pubkey(),
+        &parsed_params.contract_pubkey,
+        &contract_state.payees(),
+        final_spend,
+        total_bytes,
+    );
+    let message = Message::new(vec![instruction]);
+    let _ = client.send_message(&[gatekeeper], message).map_err(|err| {
+        log_contract_error("EndSession", &parsed_params.contract_pubkey, &err);
+        err
+    })?;
+    Ok(())
+}
+
+/// Lamports funding the gatekeeper's registration account
+pub const REGISTRATION_LAMPORTS: u64 = 1;
+
+/// Publish `info` in the gatekeeper's on-chain registration, creating the
+/// registration if the gatekeeper has none yet
+pub fn publish_registration<T: Client>(
+    client: &Arc<T>,
+    gatekeeper: &Keypair,
+    info: &GatekeeperInfo,
+) -> TransportResult<()> {
+    let registration_pubkey = registration_address(&gatekeeper.pubkey());
+    let registered = client
+        .get_account_data(&registration_pubkey)?
+        .map_or(false, |data| {
+            GatekeeperRegistration::deserialize(&data).is_ok()
+        });
+    let instructions = if registered {
+        vec![bandwidth_prepay_instruction::update_registration(
+            &gatekeeper.pubkey(),
+            info,
+        )]
+    } else {
+        bandwidth_prepay_instruction::register_gatekeeper(
+            &gatekeeper.pubkey(),
+            REGISTRATION_LAMPORTS,
+            info,
+        )
+    };
+    let message = Message::new(instructions);
+    let _ = client.send_message(&[gatekeeper], message).map_err(|err| {
+        error!(
+            "RegisterGatekeeper failed for registration {}: {:?}",
+            registration_pubkey, err
+        );
+        err
+    })?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bandwidth_prepay_api::bandwidth_prepay_history::{ContractEvent, EventKind};
+    use bandwidth_prepay_api::bandwidth_prepay_instruction::ContractTerms;
+    use bandwidth_prepay_api::bandwidth_prepay_state::ContractStatus;
     use bandwidth_prepay_api::{self, bandwidth_prepay_processor::process_instruction};
     use solana_runtime::bank::Bank;
     use solana_runtime::bank_client::BankClient;
     use solana_sdk::client::SyncClient;
     use solana_sdk::genesis_block::create_genesis_block;
     use solana_sdk::system_instruction;
-    use std::sync::mpsc::channel;
+    use std::sync::mpsc::{channel, Sender};
     use std::thread::Builder;
 
     #[test]
@@ -201,6 +475,34 @@ mod tests {
             gatekeeper_id: gatekeeper.clone(),
             provider_id: provider.clone(),
             initiator_id: alice_pubkey.clone(),
+            lamports_per_kib: 1,
+            max_bytes: None,
+            total_spent: 0,
+            total_bytes: 0,
+            expiry_slot: u64::max_value(),
+            status: ContractStatus::Initialized,
+            deposit_count: 0,
+            recorded_balance: 500,
+            recipients: vec![],
+            gatekeeper_fee: 0,
+            rotation_requires_initiator: false,
+            session_count: 0,
+            lifetime_bytes: 0,
+            nonce: None,
+            instance: 0,
+            require_registered_gatekeeper: false,
+            ended_sessions: vec![],
+            receipt_bytes: 0,
+            receipt_lamports: 0,
+            previous_gatekeeper: None,
+            history: vec![ContractEvent {
+                kind: EventKind::Deposit,
+                amount: 500,
+                slot: Some(0),
+            }],
+            allowed_destinations: vec![],
+            lamports_per_slot: 0,
+            session_start_slot: None,
         };
 
         let instructions = bandwidth_prepay_instruction::initialize(
@@ -209,22 +511,68 @@ mod tests {
             &gatekeeper,
             &provider,
             500,
+            &ContractTerms::default(),
         );
         let message = Message::new(instructions);
         client.send_message(&[&alice_keypair], message).unwrap();
 
         assert_eq!(
-            check_contract(&params, &client, &gatekeeper).unwrap(),
+            check_contract(&params.contract_pubkey, &client, &gatekeeper).unwrap(),
             (500, expected_state)
         );
 
-        assert!(check_contract(&params, &client, &Pubkey::new(&vec![4; 32])).is_err());
+        assert!(
+            check_contract(&params.contract_pubkey, &client, &Pubkey::new(&vec![4; 32])).is_err()
+        );
         let params = NewConnParams {
             contract_pubkey: Pubkey::new(&vec![5; 32]),
             destination: "127.0.0.1:1234".to_string(),
             fee_interval: 1000,
         };
-        assert!(check_contract(&params, &client, &gatekeeper).is_err());
+        assert!(check_contract(&params.contract_pubkey, &client, &gatekeeper).is_err());
+    }
+
+    #[test]
+    fn test_check_contract_follows_gatekeeper_change() {
+        let (genesis_block, alice_keypair) = create_genesis_block(10_000);
+        let mut bank = Bank::new(&genesis_block);
+        bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
+        let client = Arc::new(BankClient::new(bank));
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let new_gatekeeper = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        client.send_message(&[&alice_keypair], message).unwrap();
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        client.send_message(&[&alice_keypair], message).unwrap();
+        assert!(check_contract(&contract, &client, &gatekeeper.pubkey()).is_ok());
+
+        let instruction = bandwidth_prepay_instruction::change_gatekeeper(
+            &gatekeeper.pubkey(),
+            &contract,
+            &new_gatekeeper,
+            None,
+        );
+        let message = Message::new(vec![instruction]);
+        client.send_message(&[&gatekeeper], message).unwrap();
+
+        assert!(check_contract(&contract, &client, &gatekeeper.pubkey()).is_err());
+        let (_, state) = check_contract(&contract, &client, &new_gatekeeper).unwrap();
+        assert_eq!(state.gatekeeper_id, new_gatekeeper);
     }
 
     #[test]
@@ -246,6 +594,7 @@ mod tests {
             &gatekeeper.pubkey(),
             &provider,
             500,
+            &ContractTerms::default(),
         );
         let message = Message::new(instructions);
         bank_client
@@ -268,9 +617,10 @@ mod tests {
             gatekeeper_id: gatekeeper.pubkey(),
             provider_id: provider.clone(),
             initiator_id: alice_pubkey.clone(),
+            ..BandwidthPrepayState::default()
         };
 
-        charge_contract(&params, &bank_client, &state, &gatekeeper, 100).unwrap();
+        charge_contract(&params, &bank_client, &state, &gatekeeper, 100, 100 * 1024).unwrap();
 
         let balance = bank_client.get_balance(&contract).unwrap();
         assert_eq!(balance, 400);
@@ -279,54 +629,197 @@ mod tests {
         assert_eq!(state.gatekeeper_id, gatekeeper.pubkey());
         assert_eq!(state.provider_id, provider);
         assert_eq!(state.initiator_id, alice_pubkey);
+        assert_eq!(state.total_bytes, 100 * 1024);
         let balance = bank_client.get_balance(&provider).unwrap();
         assert_eq!(balance, 100);
+
+        // The program's reason for rejecting a charge can be recovered
+        let err = charge_contract(&params, &bank_client, &state, &gatekeeper, 1000, 200 * 1024)
+            .unwrap_err();
+        match err {
+            TransportError::TransactionError(e) => assert_eq!(
+                BandwidthPrepayError::from_transaction_error(&e),
+                Some(BandwidthPrepayError::BalanceTooLow)
+            ),
+            _ => panic!("expected a transaction error"),
+        }
+    }
+
+    /// Wait until `submit_loop` has been through everything sent before
+    fn flush(sender: &Sender<Submission>) {
+        let (done_sender, done_receiver) = channel();
+        let finish = Box::new(move |_: u64| done_sender.send(()).unwrap());
+        sender
+            .send(Submission::Finish(Pubkey::new_rand(), finish))
+            .unwrap();
+        done_receiver.recv().unwrap();
     }
 
     #[test]
-    fn test_submit_transaction_loop() {
+    fn test_submit_loop() {
         let (genesis_block, alice_keypair) = create_genesis_block(10_000);
         let mut bank = Bank::new(&genesis_block);
         bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
         let bank_client = Arc::new(BankClient::new(bank));
-        let client_clone0 = bank_client.clone();
-        let client_clone1 = bank_client.clone();
 
         let alice_pubkey = alice_keypair.pubkey();
-        let recipient = Keypair::new().pubkey();
+        let contract = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let gatekeeper_pubkey = gatekeeper.pubkey();
+        let provider = Keypair::new().pubkey();
+
+        // Initialize Contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper_pubkey,
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let charge = |amount, total_bytes| {
+            Submission::Spend(Charge {
+                contract_pubkey: contract,
+                payees: vec![provider],
+                amount,
+                total_bytes,
+            })
+        };
 
         let (sender, receiver) = channel();
+        let client = bank_client.clone();
         Builder::new()
-            .name("test_submit_transaction_loop".to_string())
-            .spawn(move || {
-                submit_transaction_loop(&receiver);
-            })
+            .name("test_submit_loop".to_string())
+            .spawn(move || submit_loop(&client, &gatekeeper, &receiver))
             .unwrap();
 
-        let instruction = system_instruction::transfer(&alice_pubkey, &recipient, 100);
+        // The gatekeeper can't pay for the transaction yet, so the charge is
+        // held for the next one
+        sender.send(charge(100, 100 * 1024)).unwrap();
+        flush(&sender);
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 500);
+
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper_pubkey, 1);
         let message = Message::new(vec![instruction]);
-        let (blockhash, _) = bank_client.get_recent_blockhash().unwrap();
-        let transaction = Transaction::new(&[&alice_keypair], message, blockhash);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
 
-        sender.send((client_clone0, transaction)).unwrap();
-        let mut balance = 0;
-        while balance == 0 {
-            balance = bank_client.get_balance(&recipient).unwrap();
-        }
-        assert_eq!(balance, 100);
-        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_900);
+        // Charges sent back to back land in order
+        sender.send(charge(50, 150 * 1024)).unwrap();
+        sender.send(charge(25, 175 * 1024)).unwrap();
+        // One the program rejects isn't retried
+        sender.send(charge(1000, 200 * 1024)).unwrap();
+        let (owed_sender, owed_receiver) = channel();
+        let finish = Box::new(move |owed: u64| owed_sender.send(owed).unwrap());
+        sender.send(Submission::Finish(contract, finish)).unwrap();
+        assert_eq!(owed_receiver.recv().unwrap(), 0);
 
-        let instruction = system_instruction::transfer(&alice_pubkey, &recipient, 90);
-        let message = Message::new(vec![instruction]);
-        let (blockhash, _) = bank_client.get_recent_blockhash().unwrap();
-        let transaction = Transaction::new(&[&alice_keypair], message, blockhash);
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 325);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 175);
+        let account_data = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account_data).unwrap();
+        assert_eq!(state.total_bytes, 175 * 1024);
+    }
 
-        sender.send((client_clone1, transaction)).unwrap();
-        while balance == 100 {
-            balance = bank_client.get_balance(&recipient).unwrap();
+    #[test]
+    fn test_submit_loop_spend_batch() {
+        let (genesis_block, alice_keypair) = create_genesis_block(10_000);
+        let mut bank = Bank::new(&genesis_block);
+        bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
+        let bank_client = Arc::new(BankClient::new(bank));
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Keypair::new();
+        let provider = Keypair::new().pubkey();
+
+        // Initialize Contracts
+        let contracts: Vec<_> = (0..2).map(|_| Keypair::new().pubkey()).collect();
+        for contract in &contracts {
+            let instructions = bandwidth_prepay_instruction::initialize(
+                &alice_pubkey,
+                contract,
+                &gatekeeper.pubkey(),
+                &provider,
+                500,
+                &ContractTerms::default(),
+            );
+            let message = Message::new(instructions);
+            bank_client
+                .send_message(&[&alice_keypair], message)
+                .unwrap();
         }
-        assert_eq!(balance, 190);
-        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_810);
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let (sender, receiver) = channel();
+        let client = bank_client.clone();
+        Builder::new()
+            .name("test_submit_loop_spend_batch".to_string())
+            .spawn(move || submit_loop(&client, &gatekeeper, &receiver))
+            .unwrap();
+
+        let charges = vec![
+            Charge {
+                contract_pubkey: contracts[0],
+                payees: vec![provider],
+                amount: 100,
+                total_bytes: 100 * 1024,
+            },
+            Charge {
+                contract_pubkey: contracts[1],
+                payees: vec![provider],
+                amount: 50,
+                total_bytes: 50 * 1024,
+            },
+        ];
+        sender
+            .send(Submission::SpendBatch {
+                provider_pubkey: provider,
+                charges,
+            })
+            .unwrap();
+        flush(&sender);
+
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 150);
+        assert_eq!(bank_client.get_balance(&contracts[0]).unwrap(), 400);
+        assert_eq!(bank_client.get_balance(&contracts[1]).unwrap(), 450);
+
+        // A charge the program rejects fails the whole batch, but the other
+        // contracts are still charged on their own
+        let charges = vec![
+            Charge {
+                contract_pubkey: contracts[0],
+                payees: vec![provider],
+                amount: 1000,
+                total_bytes: 1100 * 1024,
+            },
+            Charge {
+                contract_pubkey: contracts[1],
+                payees: vec![provider],
+                amount: 50,
+                total_bytes: 100 * 1024,
+            },
+        ];
+        sender
+            .send(Submission::SpendBatch {
+                provider_pubkey: provider,
+                charges,
+            })
+            .unwrap();
+        flush(&sender);
+
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 200);
+        assert_eq!(bank_client.get_balance(&contracts[0]).unwrap(), 400);
+        assert_eq!(bank_client.get_balance(&contracts[1]).unwrap(), 400);
     }
 
     #[test]
@@ -348,6 +841,7 @@ mod tests {
             &gatekeeper.pubkey(),
             &provider,
             500,
+            &ContractTerms::default(),
         );
         let message = Message::new(instructions);
         bank_client
@@ -370,9 +864,10 @@ mod tests {
             gatekeeper_id: gatekeeper.pubkey(),
             provider_id: provider.clone(),
             initiator_id: alice_pubkey.clone(),
+            ..BandwidthPrepayState::default()
         };
 
-        charge_contract(&params, &bank_client, &state, &gatekeeper, 100).unwrap();
+        charge_contract(&params, &bank_client, &state, &gatekeeper, 100, 100 * 1024).unwrap();
         refund(&params, &bank_client, &state, &gatekeeper).unwrap();
 
         let balance = bank_client.get_balance(&contract).unwrap();
@@ -382,4 +877,264 @@ mod tests {
         let balance = bank_client.get_balance(&alice_pubkey).unwrap();
         assert_eq!(balance, 9_899);
     }
+
+    #[test]
+    fn test_record_receipt() {
+        let initiator = Keypair::new();
+        let contract = Pubkey::new_rand();
+        let state = BandwidthPrepayState {
+            initiator_id: initiator.pubkey(),
+            ..BandwidthPrepayState::default()
+        };
+        let mut latest = None;
+
+        let receipt = UsageReceipt::new(&contract, 0, 0, 2048, 2);
+        let signature = receipt.sign(&Keypair::new());
+        assert!(!record_receipt(&mut latest, &state, receipt, signature));
+
+        let receipt = UsageReceipt::new(&contract, 0, 0, 2048, 2);
+        let signature = receipt.sign(&initiator);
+        assert!(record_receipt(&mut latest, &state, receipt, signature));
+
+        let receipt = UsageReceipt::new(&contract, 0, 0, 1024, 1);
+        let signature = receipt.sign(&initiator);
+        assert!(!record_receipt(&mut latest, &state, receipt, signature));
+
+        // Receipts for an earlier contract at the same address are rejected
+        let receipt = UsageReceipt::new(&contract, 1, 0, 4096, 4);
+        let signature = receipt.sign(&initiator);
+        assert!(!record_receipt(&mut latest, &state, receipt, signature));
+
+        let receipt = UsageReceipt::new(&contract, 0, 0, 4096, 4);
+        let signature = receipt.sign(&initiator);
+        assert!(record_receipt(
+            &mut latest,
+            &state,
+            receipt.clone(),
+            signature
+        ));
+        assert_eq!(latest, Some((receipt, signature)));
+
+        // Receipts for another session are rejected, and a new session starts
+        // over from zero
+        let receipt = UsageReceipt::new(&contract, 0, 1, 1024, 1);
+        let signature = receipt.sign(&initiator);
+        assert!(!record_receipt(
+            &mut latest,
+            &state,
+            receipt.clone(),
+            signature
+        ));
+        let state = BandwidthPrepayState {
+            session_count: 1,
+            ..state
+        };
+        assert!(record_receipt(&mut latest, &state, receipt, signature));
+    }
+
+    #[test]
+    fn test_settle_contract() {
+        let (genesis_block, alice_keypair) = create_genesis_block(10_000);
+        let mut bank = Bank::new(&genesis_block);
+        bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
+        let bank_client = Arc::new(BankClient::new(bank));
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let provider = Keypair::new().pubkey();
+
+        // Initialize Contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let params = NewConnParams {
+            contract_pubkey: contract.clone(),
+            destination: "127.0.0.1:1234".to_string(),
+            fee_interval: 1000,
+        };
+        let (_, state) = check_contract(&contract, &bank_client, &gatekeeper.pubkey()).unwrap();
+
+        let receipt = UsageReceipt::new(&contract, 0, 0, 150 * 1024, 150);
+        let signature = receipt.sign(&alice_keypair);
+        settle_contract(
+            &params,
+            &bank_client,
+            &state,
+            &gatekeeper,
+            &receipt,
+            &signature,
+        )
+        .unwrap();
+
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 350);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 150);
+    }
+
+    #[test]
+    fn test_close_contract() {
+        let (genesis_block, alice_keypair) = create_genesis_block(10_000);
+        let mut bank = Bank::new(&genesis_block);
+        bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
+        let bank_client = Arc::new(BankClient::new(bank));
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let provider = Keypair::new().pubkey();
+
+        // Initialize Contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let params = NewConnParams {
+            contract_pubkey: contract.clone(),
+            destination: "127.0.0.1:1234".to_string(),
+            fee_interval: 1000,
+        };
+        let state = BandwidthPrepayState {
+            gatekeeper_id: gatekeeper.pubkey(),
+            provider_id: provider.clone(),
+            initiator_id: alice_pubkey.clone(),
+            ..BandwidthPrepayState::default()
+        };
+
+        charge_contract(&params, &bank_client, &state, &gatekeeper, 100, 100 * 1024).unwrap();
+        close_contract(&params, &bank_client, &state, &gatekeeper).unwrap();
+
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 100);
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_899);
+        assert!(
+            charge_contract(&params, &bank_client, &state, &gatekeeper, 1, 200 * 1024).is_err()
+        );
+    }
+
+    #[test]
+    fn test_end_session() {
+        let (genesis_block, alice_keypair) = create_genesis_block(10_000);
+        let mut bank = Bank::new(&genesis_block);
+        bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
+        let bank_client = Arc::new(BankClient::new(bank));
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let contract = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let provider = Keypair::new().pubkey();
+
+        // Initialize Contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            500,
+            &ContractTerms::default(),
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let params = NewConnParams {
+            contract_pubkey: contract.clone(),
+            destination: "127.0.0.1:1234".to_string(),
+            fee_interval: 1000,
+        };
+        let (_, state) = check_contract(&contract, &bank_client, &gatekeeper.pubkey()).unwrap();
+
+        charge_contract(&params, &bank_client, &state, &gatekeeper, 100, 100 * 1024).unwrap();
+        end_session(&params, &bank_client, &state, &gatekeeper, 50, 150 * 1024).unwrap();
+
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 350);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 150);
+        let (_, state) = check_contract(&contract, &bank_client, &gatekeeper.pubkey()).unwrap();
+        assert_eq!(state.status, ContractStatus::Initialized);
+        assert_eq!(state.session_count, 1);
+        assert_eq!(state.total_bytes, 0);
+
+        // The next session counts its bytes from zero again
+        charge_contract(&params, &bank_client, &state, &gatekeeper, 10, 10 * 1024).unwrap();
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 160);
+    }
+
+    #[test]
+    fn test_publish_registration() {
+        let (genesis_block, alice_keypair) = create_genesis_block(10_000);
+        let mut bank = Bank::new(&genesis_block);
+        bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
+        let bank_client = Arc::new(BankClient::new(bank));
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Keypair::new();
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let info = GatekeeperInfo {
+            endpoint: "127.0.0.1:8122".to_string(),
+            lamports_per_kib: 2,
+            destinations: vec![],
+        };
+        publish_registration(&bank_client, &gatekeeper, &info).unwrap();
+        let registration_pubkey = registration_address(&gatekeeper.pubkey());
+        assert_eq!(
+            bank_client.get_balance(&registration_pubkey).unwrap(),
+            REGISTRATION_LAMPORTS
+        );
+
+        // Publishing again updates the existing registration
+        let info = GatekeeperInfo {
+            lamports_per_kib: 3,
+            ..info
+        };
+        publish_registration(&bank_client, &gatekeeper, &info).unwrap();
+        let data = bank_client
+            .get_account_data(&registration_pubkey)
+            .unwrap()
+            .unwrap();
+        let registration = GatekeeperRegistration::deserialize(&data).unwrap();
+        assert_eq!(registration.gatekeeper_id, gatekeeper.pubkey());
+        assert_eq!(registration.info, info);
+    }
 }
@@ -1,5 +1,6 @@
 pub mod accumulator;
-mod business_logic;
 pub mod connection_params;
 pub mod contract;
 pub mod gatekeeper;
+pub mod pricing;
+pub mod reactor;
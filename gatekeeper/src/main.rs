@@ -1,12 +1,15 @@
+use bandwidth_prepay_api::bandwidth_prepay_instruction::DEFAULT_LAMPORTS_PER_KIB;
+use bandwidth_prepay_api::bandwidth_prepay_registry::GatekeeperInfo;
 use clap::{App, Arg};
 use gatekeeper::connection_params::NewConnParams;
 use gatekeeper::contract::*;
-use gatekeeper::gatekeeper::forwarder;
+use gatekeeper::pricing::{FlatRate, FreeAllowance, Pricing, PricingPolicy, TieredRate, TimeRate};
+use gatekeeper::reactor::Reactor;
 use jsonrpc_core::types::error::{Error, ErrorCode};
 use jsonrpc_core::{IoHandler, Params};
 use jsonrpc_tcp_server::ServerBuilder;
 use log::*;
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
 use solana_client::rpc_client::RpcClient;
 use solana_client::rpc_request::RpcRequest;
 use solana_client::thin_client::create_client;
@@ -14,9 +17,7 @@ use solana_drone::drone::request_airdrop_transaction;
 use solana_sdk::client::{AsyncClient, SyncClient};
 use solana_sdk::signature::{read_keypair, KeypairUtil};
 use std::net::SocketAddr;
-use std::sync::mpsc::channel;
 use std::sync::Arc;
-use std::thread;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
@@ -54,6 +55,74 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .takes_value(true)
                 .help("How often to charge contract"),
         )
+        .arg(
+            Arg::with_name("advertise")
+                .long("advertise")
+                .value_name("HOST:PORT")
+                .takes_value(true)
+                .help(
+                    "Publish this RPC listener address in the on-chain gatekeeper registry, \
+                     along with the pricing policy's rate per KiB. \
+                     Policies with no single rate per KiB can't be published",
+                ),
+        )
+        .arg(
+            Arg::with_name("lamports_per_kib")
+                .long("lamports-per-kib")
+                .value_name("LAMPORTS")
+                .takes_value(true)
+                .help(
+                    "Price to publish in the registry. Defaults to 1. \
+                     Flat pricing charges each contract the rate it was created with",
+                ),
+        )
+        .arg(
+            Arg::with_name("destination")
+                .long("destination")
+                .value_name("HOST:PORT")
+                .takes_value(true)
+                .multiple(true)
+                .requires("advertise")
+                .help("Destination to publish in the registry. May be repeated"),
+        )
+        .arg(
+            Arg::with_name("pricing")
+                .long("pricing")
+                .value_name("POLICY")
+                .takes_value(true)
+                .possible_values(&["flat", "tiered", "time", "free-allowance"])
+                .default_value("flat")
+                .help("How to charge for a session"),
+        )
+        .arg(
+            Arg::with_name("tier")
+                .long("tier")
+                .value_name("BYTES:LAMPORTS_PER_KIB")
+                .takes_value(true)
+                .multiple(true)
+                .required_if("pricing", "tiered")
+                .help("Rate charged once a session has carried BYTES. May be repeated"),
+        )
+        .arg(
+            Arg::with_name("lamports_per_second")
+                .long("lamports-per-second")
+                .value_name("LAMPORTS")
+                .takes_value(true)
+                .required_if("pricing", "time")
+                .help(
+                    "Price of each second connected, with time pricing. \
+                     Traffic is also charged for if --lamports-per-kib is given. \
+                     Only contracts with a rate per slot are served",
+                ),
+        )
+        .arg(
+            Arg::with_name("free_bytes")
+                .long("free-bytes")
+                .value_name("BYTES")
+                .takes_value(true)
+                .required_if("pricing", "free-allowance")
+                .help("Bytes carried free each session before charging per KiB"),
+        )
         .get_matches();
     let gatekeeper_keypair_path = matches.value_of("keypair").unwrap().to_string();
     let gatekeeper = read_keypair(&gatekeeper_keypair_path).unwrap();
@@ -84,57 +153,128 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         1
     } * 1000;
 
+    let lamports_per_kib = matches
+        .value_of("lamports_per_kib")
+        .map_or(DEFAULT_LAMPORTS_PER_KIB, |lamports| {
+            lamports.parse().unwrap()
+        });
+    let pricing = match matches.value_of("pricing").unwrap() {
+        "tiered" => {
+            let mut tiers: Vec<_> = matches.values_of("tier").unwrap().map(parse_tier).collect();
+            tiers.sort();
+            Pricing::Tiered(TieredRate { tiers })
+        }
+        "time" => Pricing::Time(TimeRate {
+            lamports_per_second: matches
+                .value_of("lamports_per_second")
+                .unwrap()
+                .parse()
+                .unwrap(),
+            // Traffic is only charged for on top when a rate is given
+            lamports_per_kib: matches
+                .value_of("lamports_per_kib")
+                .map_or(0, |lamports| lamports.parse().unwrap()),
+        }),
+        "free-allowance" => Pricing::FreeAllowance(FreeAllowance {
+            free_bytes: matches.value_of("free_bytes").unwrap().parse().unwrap(),
+            lamports_per_kib,
+        }),
+        _ => Pricing::Flat(FlatRate { lamports_per_kib }),
+    };
+    info!("Pricing: {:?}", pricing);
+
+    let advertised_rate = pricing.advertised_rate();
+    if matches.is_present("advertise") && advertised_rate.is_none() {
+        return Err(format!(
+            "--advertise needs a pricing policy with a single rate per KiB, not {:?}",
+            pricing
+        )
+        .into());
+    }
+    let registration_info = matches
+        .value_of("advertise")
+        .map(|endpoint| GatekeeperInfo {
+            endpoint: endpoint.to_string(),
+            lamports_per_kib: advertised_rate.unwrap(),
+            destinations: matches
+                .values_of("destination")
+                .map_or(vec![], |destinations| {
+                    destinations
+                        .map(|destination| destination.to_string())
+                        .collect()
+                }),
+        });
+
     // TODO: handle initial account funding properly, probably separate from this script
     let balance = client.get_balance(&gatekeeper.pubkey()).unwrap_or(0);
     if balance == 0 {
+        let lamports = if registration_info.is_some() {
+            1 + REGISTRATION_LAMPORTS
+        } else {
+            1
+        };
         let (blockhash, _) = client.get_recent_blockhash().unwrap();
-        match request_airdrop_transaction(&drone_addr, &gatekeeper.pubkey(), 1, blockhash) {
+        match request_airdrop_transaction(&drone_addr, &gatekeeper.pubkey(), lamports, blockhash) {
             Ok(transaction) => {
                 let signature = client.async_send_transaction(transaction).unwrap();
                 client.get_signature_status(&signature).unwrap();
             }
             Err(e) => {
                 error!(
-                    "Error requesting airdrop: {:?} to addr: {:?} amount: 1",
-                    e, drone_addr
+                    "Error requesting airdrop: {:?} to addr: {:?} amount: {}",
+                    e, drone_addr, lamports
                 );
             }
         }
     }
 
     let client = Arc::new(client);
+    if let Some(info) = registration_info {
+        publish_registration(&client, &gatekeeper, &info)?;
+        info!("Registered gatekeeper at {}", info.endpoint);
+    }
+    let gatekeeper_pubkey = gatekeeper.pubkey();
+    let charges_time = pricing.charges_time();
+    let reactor = Arc::new(Reactor::start(
+        gatekeeper,
+        client.clone(),
+        ws_addr,
+        pricing,
+        fee_interval,
+    )?);
 
     let mut io = IoHandler::default();
     io.add_method("newConnection", move |params: Params| {
-        let flat_params: serde_json::map::Map<String, Value> = params.parse()?;
+        let flat_params: Map<String, Value> = params.parse()?;
         let parsed_params = NewConnParams {
             contract_pubkey: verify_pubkey(
-                flat_params["contract_pubkey"].as_str().unwrap().to_string(),
+                str_param(&flat_params, "contract_pubkey")?.to_string(),
             )?,
-            destination: flat_params["destination"].as_str().unwrap().to_string(),
+            destination: str_param(&flat_params, "destination")?.to_string(),
             fee_interval,
         };
-        let initiator_pubkey = verify_pubkey(
-            flat_params["initiator_pubkey"]
-                .as_str()
-                .unwrap()
-                .to_string(),
-        )?;
+        // Framed connections carry the initiator's receipts with its traffic
+        let framed = flat_params
+            .get("framed")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let initiator_pubkey =
+            verify_pubkey(str_param(&flat_params, "initiator_pubkey")?.to_string())?;
         info!(
             "Received forward request to '{}', contract: {:?}",
             &parsed_params.destination, &parsed_params.contract_pubkey
         );
 
-        let gatekeeper = read_keypair(&gatekeeper_keypair_path).unwrap();
-
         let (balance, contract_state) =
-            check_contract(&parsed_params, &client, &gatekeeper.pubkey()).map_err(|e| {
-                error!(
-                    "could not check contract: {:?} {:?}",
-                    parsed_params.contract_pubkey, e
-                );
-                Error::invalid_request()
-            })?;
+            check_contract(&parsed_params.contract_pubkey, &client, &gatekeeper_pubkey).map_err(
+                |e| {
+                    error!(
+                        "could not check contract: {:?} {:?}",
+                        parsed_params.contract_pubkey, e
+                    );
+                    Error::invalid_request()
+                },
+            )?;
         if balance == 0 {
             error!("prepay balance is 0: {:?}", parsed_params.contract_pubkey);
             return Err(Error::invalid_request());
@@ -146,26 +286,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             );
             return Err(Error::invalid_request());
         }
+        if !contract_state.allows_destination(&parsed_params.destination) {
+            error!(
+                "destination '{}' is not allowed by contract {:?}",
+                parsed_params.destination, parsed_params.contract_pubkey
+            );
+            return Err(Error::invalid_request());
+        }
+        // The program caps spends at the contract's rates, so time it has no
+        // rate for would go unbilled
+        if charges_time && contract_state.lamports_per_slot == 0 {
+            error!(
+                "contract {:?} has no rate per slot to pay for connection time",
+                parsed_params.contract_pubkey
+            );
+            return Err(Error::invalid_request());
+        }
 
         info!(
             "Starting new connection to '{}'",
             &parsed_params.destination
         );
 
-        let client = client.clone();
-        let (send, recv) = channel();
-        thread::spawn(move || {
-            forwarder(
-                &parsed_params,
-                &gatekeeper,
-                &client,
-                &contract_state,
-                balance,
-                ws_addr,
-                send,
-            )
-        });
-        match recv.recv() {
+        match reactor.open_session(parsed_params, contract_state, balance, framed) {
             Ok(new_port) => {
                 let ret = json!({ "port": format!("{}", new_port) });
                 info!(
@@ -174,8 +317,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 );
                 Ok(ret)
             }
-            Err(_e) => {
-                error!("Could not get port from forwarder thread");
+            Err(e) => {
+                error!("Could not start gatekeeper channel: {}", e);
                 Err(Error::new(ErrorCode::ServerError(2)))
             }
         }
@@ -188,3 +331,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// The string parameter `name` of an RPC request
+fn str_param<'a>(params: &'a Map<String, Value>, name: &str) -> Result<&'a str, Error> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::invalid_params(name))
+}
+
+/// Parse a `--tier` value of the form BYTES:LAMPORTS_PER_KIB
+fn parse_tier(tier: &str) -> (u64, u64) {
+    let mut parts = tier.splitn(2, ':');
+    let bytes = parts.next().unwrap().parse().unwrap();
+    let lamports_per_kib = parts
+        .next()
+        .expect("tier must be BYTES:LAMPORTS_PER_KIB")
+        .parse()
+        .unwrap();
+    (bytes, lamports_per_kib)
+}
@@ -0,0 +1,282 @@
+use bandwidth_prepay_api::bandwidth_prepay_state::BandwidthPrepayState;
+use std::time::Duration;
+
+/// Decides what a gatekeeper charges for a session
+pub trait PricingPolicy {
+    /// Lamports owed for a session that has carried `total_bytes` over
+    /// `elapsed`. Must never decrease as either grows
+    fn price(&self, total_bytes: u64, elapsed: Duration) -> u64;
+
+    /// Whether the price grows with time connected, which a contract only
+    /// pays for if it has a rate per slot
+    fn charges_time(&self) -> bool {
+        false
+    }
+
+    /// The single rate per KiB this policy charges, to publish in the
+    /// registry, or `None` if the price depends on more than the bytes
+    fn advertised_rate(&self) -> Option<u64> {
+        None
+    }
+
+    /// The policy to bill a session on `contract_state` with. Most policies
+    /// set their own rates whatever the contract
+    fn for_contract(&self, _contract_state: &BandwidthPrepayState) -> Self
+    where
+        Self: Clone,
+    {
+        self.clone()
+    }
+}
+
+/// The same rate for every byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlatRate {
+    pub lamports_per_kib: u64,
+}
+
+impl PricingPolicy for FlatRate {
+    fn price(&self, total_bytes: u64, _elapsed: Duration) -> u64 {
+        (u128::from(total_bytes) * u128::from(self.lamports_per_kib) / 1024) as u64
+    }
+
+    fn advertised_rate(&self) -> Option<u64> {
+        Some(self.lamports_per_kib)
+    }
+
+    /// Charge the rate the initiator agreed to in its contract
+    fn for_contract(&self, contract_state: &BandwidthPrepayState) -> Self {
+        FlatRate {
+            lamports_per_kib: contract_state.lamports_per_kib,
+        }
+    }
+}
+
+/// Volume pricing: each rate applies to the bytes between its threshold and
+/// the next one's
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TieredRate {
+    /// `(bytes, lamports_per_kib)` pairs sorted by `bytes`. Bytes below the
+    /// first threshold are free
+    pub tiers: Vec<(u64, u64)>,
+}
+
+impl PricingPolicy for TieredRate {
+    fn price(&self, total_bytes: u64, _elapsed: Duration) -> u64 {
+        let mut price = 0u128;
+        for (i, &(start, lamports_per_kib)) in self.tiers.iter().enumerate() {
+            let end = self
+                .tiers
+                .get(i + 1)
+                .map_or(total_bytes, |&(next, _)| next.min(total_bytes));
+            price += u128::from(end.saturating_sub(start)) * u128::from(lamports_per_kib);
+        }
+        (price / 1024) as u64
+    }
+
+    fn advertised_rate(&self) -> Option<u64> {
+        match self.tiers[..] {
+            [(0, lamports_per_kib)] => Some(lamports_per_kib),
+            _ => None,
+        }
+    }
+}
+
+/// Billing by connection time, whether or not any traffic flows, plus an
+/// optional per-KiB rate for the traffic that does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeRate {
+    pub lamports_per_second: u64,
+    pub lamports_per_kib: u64,
+}
+
+impl PricingPolicy for TimeRate {
+    fn price(&self, total_bytes: u64, elapsed: Duration) -> u64 {
+        let traffic = FlatRate {
+            lamports_per_kib: self.lamports_per_kib,
+        };
+        let time = (u128::from(self.lamports_per_second) * elapsed.as_millis() / 1000) as u64;
+        time.saturating_add(traffic.price(total_bytes, elapsed))
+    }
+
+    fn charges_time(&self) -> bool {
+        self.lamports_per_second > 0
+    }
+
+    fn advertised_rate(&self) -> Option<u64> {
+        if self.charges_time() {
+            None
+        } else {
+            Some(self.lamports_per_kib)
+        }
+    }
+}
+
+/// A flat rate once the first `free_bytes` of a session have been carried
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FreeAllowance {
+    pub free_bytes: u64,
+    pub lamports_per_kib: u64,
+}
+
+impl PricingPolicy for FreeAllowance {
+    fn price(&self, total_bytes: u64, elapsed: Duration) -> u64 {
+        let paid = FlatRate {
+            lamports_per_kib: self.lamports_per_kib,
+        };
+        paid.price(total_bytes.saturating_sub(self.free_bytes), elapsed)
+    }
+
+    fn advertised_rate(&self) -> Option<u64> {
+        if self.free_bytes == 0 {
+            Some(self.lamports_per_kib)
+        } else {
+            None
+        }
+    }
+}
+
+/// Any of the policies above, as chosen by the gatekeeper's configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pricing {
+    Flat(FlatRate),
+    Tiered(TieredRate),
+    Time(TimeRate),
+    FreeAllowance(FreeAllowance),
+}
+
+impl PricingPolicy for Pricing {
+    fn price(&self, total_bytes: u64, elapsed: Duration) -> u64 {
+        match self {
+            Pricing::Flat(policy) => policy.price(total_bytes, elapsed),
+            Pricing::Tiered(policy) => policy.price(total_bytes, elapsed),
+            Pricing::Time(policy) => policy.price(total_bytes, elapsed),
+            Pricing::FreeAllowance(policy) => policy.price(total_bytes, elapsed),
+        }
+    }
+
+    fn charges_time(&self) -> bool {
+        match self {
+            Pricing::Time(policy) => policy.charges_time(),
+            _ => false,
+        }
+    }
+
+    fn advertised_rate(&self) -> Option<u64> {
+        match self {
+            Pricing::Flat(policy) => policy.advertised_rate(),
+            Pricing::Tiered(policy) => policy.advertised_rate(),
+            Pricing::Time(policy) => policy.advertised_rate(),
+            Pricing::FreeAllowance(policy) => policy.advertised_rate(),
+        }
+    }
+
+    fn for_contract(&self, contract_state: &BandwidthPrepayState) -> Self {
+        match self {
+            Pricing::Flat(policy) => Pricing::Flat(policy.for_contract(contract_state)),
+            _ => self.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_flat_rate() {
+        let policy = FlatRate {
+            lamports_per_kib: 3,
+        };
+        let elapsed = Duration::from_secs(10);
+        assert_eq!(policy.price(0, elapsed), 0);
+        assert_eq!(policy.price(1023, elapsed), 2);
+        assert_eq!(policy.price(1024, elapsed), 3);
+        assert_eq!(policy.price(2048, Duration::from_secs(0)), 6);
+        assert_eq!(policy.advertised_rate(), Some(3));
+
+        let contract_state = BandwidthPrepayState {
+            lamports_per_kib: 5,
+            ..BandwidthPrepayState::default()
+        };
+        let policy = Pricing::Flat(policy).for_contract(&contract_state);
+        assert_eq!(policy.price(1024, elapsed), 5);
+        let policy = Pricing::FreeAllowance(FreeAllowance {
+            free_bytes: 0,
+            lamports_per_kib: 3,
+        });
+        assert_eq!(policy.for_contract(&contract_state).price(1024, elapsed), 3);
+    }
+
+    #[test]
+    fn test_tiered_rate() {
+        let policy = TieredRate {
+            tiers: vec![(0, 4), (2048, 2), (4096, 1)],
+        };
+        let elapsed = Duration::from_secs(0);
+        assert_eq!(policy.price(1024, elapsed), 4);
+        assert_eq!(policy.price(2048, elapsed), 8);
+        assert_eq!(policy.price(3072, elapsed), 10);
+        assert_eq!(policy.price(4096, elapsed), 12);
+        assert_eq!(policy.price(10 * 1024, elapsed), 18);
+
+        let policy = TieredRate {
+            tiers: vec![(1024, 1)],
+        };
+        assert_eq!(policy.price(1024, elapsed), 0);
+        assert_eq!(policy.price(2048, elapsed), 1);
+        assert_eq!(policy.advertised_rate(), None);
+
+        let policy = Pricing::Tiered(TieredRate {
+            tiers: vec![(0, 3)],
+        });
+        assert_eq!(policy.advertised_rate(), Some(3));
+    }
+
+    #[test]
+    fn test_time_rate() {
+        let policy = TimeRate {
+            lamports_per_second: 10,
+            lamports_per_kib: 0,
+        };
+        assert_eq!(policy.price(1024, Duration::from_secs(0)), 0);
+        assert_eq!(policy.price(0, Duration::from_millis(1500)), 15);
+        assert_eq!(policy.price(1024, Duration::from_millis(1500)), 15);
+
+        let policy = TimeRate {
+            lamports_per_second: 10,
+            lamports_per_kib: 2,
+        };
+        assert_eq!(policy.price(1024, Duration::from_secs(0)), 2);
+        assert_eq!(policy.price(1024, Duration::from_millis(1500)), 17);
+        assert!(Pricing::Time(policy).charges_time());
+
+        let policy = TimeRate {
+            lamports_per_second: 0,
+            lamports_per_kib: 2,
+        };
+        assert!(!policy.charges_time());
+        assert_eq!(Pricing::Time(policy).advertised_rate(), Some(2));
+        let policy = TimeRate {
+            lamports_per_second: 10,
+            lamports_per_kib: 2,
+        };
+        assert_eq!(Pricing::Time(policy).advertised_rate(), None);
+        let policy = FlatRate {
+            lamports_per_kib: 2,
+        };
+        assert!(!Pricing::Flat(policy).charges_time());
+    }
+
+    #[test]
+    fn test_free_allowance() {
+        let policy = Pricing::FreeAllowance(FreeAllowance {
+            free_bytes: 4096,
+            lamports_per_kib: 2,
+        });
+        let elapsed = Duration::from_secs(0);
+        assert_eq!(policy.price(4096, elapsed), 0);
+        assert_eq!(policy.price(5120, elapsed), 2);
+        assert_eq!(policy.advertised_rate(), None);
+    }
+}
@@ -0,0 +1,817 @@
+use crate::accumulator::Accumulator;
+use crate::connection_params::NewConnParams;
+use crate::contract::*;
+use crate::gatekeeper::{process_data, update_contract};
+use crate::pricing::PricingPolicy;
+use bandwidth_prepay_api::bandwidth_prepay_frame::Frame;
+use bandwidth_prepay_api::bandwidth_prepay_receipt::UsageReceipt;
+use bandwidth_prepay_api::bandwidth_prepay_state::{BandwidthPrepayState, ContractStatus};
+use log::*;
+use mio::net::{TcpListener, TcpStream};
+use mio::unix::UnixReady;
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use pubsub_client::client::{PubSubClient, SubscriptionEvent};
+use pubsub_client::request::PubSubRequest;
+use solana_sdk::account::Account;
+use solana_sdk::client::Client;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, KeypairUtil, Signature};
+use solana_sdk::transport::Result as TransportResult;
+use std::collections::HashMap;
+use std::error;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Readied whenever commands are waiting for the event loop
+const WAKER: Token = Token(0);
+
+/// Session tokens carry the session id above `SOCKET_BITS` and which of its
+/// sockets the event is for below
+const SOCKET_BITS: usize = 2;
+const LISTENER: usize = 0;
+const ORIGIN: usize = 1;
+const DESTINATION: usize = 2;
+
+fn token(session_id: usize, socket: usize) -> Token {
+    Token(session_id << SOCKET_BITS | socket)
+}
+
+/// How long an initiator has to connect once its session is opened
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes held for a socket that can't keep up before its peer is read again
+const MAX_PENDING: usize = 64 * 1024;
+
+/// A connection being forwarded, and what it has been charged so far
+struct Session<P: PricingPolicy> {
+    params: NewConnParams,
+    contract_state: BandwidthPrepayState,
+    accumulator: Accumulator<P>,
+    /// Waits for the initiator, who connects once `open_session` returns
+    listener: TcpListener,
+    origin: Option<TcpStream>,
+    destination: TcpStream,
+    /// Whether the initiator's traffic comes in `Frame`s, with its receipts
+    framed: bool,
+    /// Bytes from a framed initiator short of a whole frame
+    undecoded: Vec<u8>,
+    /// Latest receipt a framed initiator sent over this connection
+    receipt: Option<(UsageReceipt, Signature)>,
+    /// Read from one socket but not yet written to the other
+    to_origin: Vec<u8>,
+    to_destination: Vec<u8>,
+    /// Pubsub subscription to the contract's account
+    subscription: u64,
+    opened: Instant,
+}
+
+enum Command<P: PricingPolicy> {
+    Open(Box<Session<P>>),
+    /// The account behind a session's pubsub subscription changed on chain
+    Update(u64, Account),
+    /// Pubsub reconnected and renewed the subscription, possibly missing
+    /// notifications while it was down
+    Resubscribed(u64),
+    /// Pubsub reconnected but couldn't renew the subscription
+    Lost(u64),
+}
+
+/// Queues commands for the event loop and wakes it to run them
+#[derive(Clone)]
+struct Waker<P: PricingPolicy> {
+    sender: Sender<Command<P>>,
+    set_readiness: SetReadiness,
+}
+
+impl<P: PricingPolicy> Waker<P> {
+    fn send(&self, command: Command<P>) -> bool {
+        self.sender.send(command).is_ok()
+            && self.set_readiness.set_readiness(Ready::readable()).is_ok()
+    }
+}
+
+/// Serves every session of a gatekeeper from a single `mio` event loop. All
+/// sessions share one thread submitting their transactions and one pubsub
+/// connection carrying a subscription to each of their contracts
+pub struct Reactor<P: PricingPolicy> {
+    waker: Mutex<Waker<P>>,
+    pubsub: Arc<PubSubClient>,
+    notifications: Mutex<Sender<SubscriptionEvent>>,
+    policy: P,
+}
+
+impl<P> Reactor<P>
+where
+    P: 'static + PricingPolicy + Clone + Send,
+{
+    pub fn start<T>(
+        gatekeeper: Keypair,
+        client: Arc<T>,
+        ws_addr: SocketAddr,
+        policy: P,
+        fee_interval: u16,
+    ) -> Result<Self, Box<dyn error::Error>>
+    where
+        T: 'static + Client + Send + Sync,
+    {
+        let poll = Poll::new()?;
+        let (registration, set_readiness) = Registration::new2();
+        poll.register(&registration, WAKER, Ready::readable(), PollOpt::edge())?;
+        let (sender, commands) = channel();
+        let waker = Waker {
+            sender,
+            set_readiness: set_readiness.clone(),
+        };
+
+        let pubsub = Arc::new(PubSubClient::connect(format!("ws://{}", ws_addr))?);
+        let (notifications, notification_receiver) = channel();
+        let notifier = waker.clone();
+        thread::spawn(move || {
+            for event in notification_receiver.iter() {
+                match event {
+                    SubscriptionEvent::Notification(notification) => {
+                        match serde_json::from_value(notification.result) {
+                            Ok(account) => {
+                                notifier.send(Command::Update(notification.subscription, account));
+                            }
+                            Err(e) => warn!("Unexpected account notification: {:?}", e),
+                        }
+                    }
+                    SubscriptionEvent::Resubscribed(subscription) => {
+                        notifier.send(Command::Resubscribed(subscription));
+                    }
+                    SubscriptionEvent::Failed(subscription) => {
+                        notifier.send(Command::Lost(subscription));
+                    }
+                }
+            }
+        });
+
+        // Catch up on contracts whose notifications may have been missed,
+        // off the event loop since it means waiting on rpc
+        let (refresh_sender, refresh_receiver) = channel::<(u64, Pubkey)>();
+        let refresh_client = client.clone();
+        let refresher = waker.clone();
+        thread::spawn(move || {
+            for (subscription, contract_pubkey) in refresh_receiver.iter() {
+                match fetch_account(&*refresh_client, &contract_pubkey) {
+                    Ok(account) => {
+                        refresher.send(Command::Update(subscription, account));
+                    }
+                    Err(e) => warn!("Could not refresh {}: {:?}", contract_pubkey, e),
+                }
+            }
+        });
+
+        // Keep the latest slot at hand, so billing for time never waits on rpc
+        let slot = Arc::new(AtomicU64::new(0));
+        let slot_client = client.clone();
+        let latest_slot = slot.clone();
+        let slot_interval = Duration::from_millis(u64::from(fee_interval));
+        thread::spawn(move || {
+            while Arc::strong_count(&latest_slot) > 1 {
+                match slot_client.get_slot() {
+                    Ok(slot) => latest_slot.store(slot, Ordering::Relaxed),
+                    Err(e) => warn!("Could not get slot: {:?}", e),
+                }
+                thread::sleep(slot_interval);
+            }
+        });
+
+        let gatekeeper = Arc::new(gatekeeper);
+        let (solana_sender, solana_receiver) = channel();
+        let submit_client = client.clone();
+        let submit_gatekeeper = gatekeeper.clone();
+        thread::spawn(move || {
+            submit_loop(&*submit_client, &submit_gatekeeper, &solana_receiver);
+        });
+
+        let event_loop = EventLoop {
+            poll,
+            _registration: registration,
+            set_readiness,
+            commands,
+            sessions: HashMap::new(),
+            subscriptions: HashMap::new(),
+            next_session_id: 1,
+            gatekeeper,
+            client,
+            slot,
+            pubsub: pubsub.clone(),
+            early_updates: HashMap::new(),
+            solana_sender,
+            refresh_sender,
+            fee_interval: Duration::from_millis(u64::from(fee_interval)),
+        };
+        thread::spawn(move || event_loop.run());
+
+        Ok(Reactor {
+            waker: Mutex::new(waker),
+            pubsub,
+            notifications: Mutex::new(notifications),
+            policy,
+        })
+    }
+
+    /// Connect to the session's destination and start listening for the
+    /// initiator, returning the port it should connect to. A `framed`
+    /// initiator sends its receipts in between its traffic
+    pub fn open_session(
+        &self,
+        params: NewConnParams,
+        contract_state: BandwidthPrepayState,
+        balance: u64,
+        framed: bool,
+    ) -> io::Result<u16> {
+        info!("Connecting to {}", params.destination);
+        let destination = std::net::TcpStream::connect(params.destination.clone())?;
+        let destination = TcpStream::from_stream(destination)?;
+        info!("Connected to {}", destination.peer_addr()?);
+
+        let listener = std::net::TcpListener::bind("0.0.0.0:0")?;
+        let port = listener.local_addr()?.port();
+        let listener = TcpListener::from_std(listener)?;
+
+        let notifications = self.notifications.lock().unwrap().clone();
+        let subscription = self
+            .pubsub
+            .subscribe(
+                PubSubRequest::Account,
+                &params.contract_pubkey,
+                notifications,
+            )
+            .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+
+        let mut accumulator = Accumulator::new(self.policy.for_contract(&contract_state));
+        accumulator.initiator_fund = balance;
+        // Carry on from a session the last connection couldn't end
+        accumulator.total_data_amount = contract_state.total_bytes;
+        accumulator.total_cost = contract_state.total_spent;
+        let session = Session {
+            params,
+            contract_state,
+            accumulator,
+            listener,
+            origin: None,
+            destination,
+            framed,
+            undecoded: vec![],
+            receipt: None,
+            to_origin: vec![],
+            to_destination: vec![],
+            subscription,
+            opened: Instant::now(),
+        };
+        if self
+            .waker
+            .lock()
+            .unwrap()
+            .send(Command::Open(Box::new(session)))
+        {
+            Ok(port)
+        } else {
+            Err(io::Error::new(ErrorKind::Other, "event loop has stopped"))
+        }
+    }
+}
+
+struct EventLoop<T, P: PricingPolicy> {
+    poll: Poll,
+    /// Deregisters the waker when dropped, so must live as long as the loop
+    _registration: Registration,
+    set_readiness: SetReadiness,
+    commands: Receiver<Command<P>>,
+    sessions: HashMap<usize, Session<P>>,
+    /// Session ids by the subscription to their contract
+    subscriptions: HashMap<u64, usize>,
+    /// The latest account, and when it arrived, for each subscription whose
+    /// session hasn't been opened yet. Notifications can overtake the
+    /// `Open` command, as a session subscribes before it is sent
+    early_updates: HashMap<u64, (Account, Instant)>,
+    next_session_id: usize,
+    gatekeeper: Arc<Keypair>,
+    client: Arc<T>,
+    /// Latest slot fetched, or 0 before the first one
+    slot: Arc<AtomicU64>,
+    pubsub: Arc<PubSubClient>,
+    solana_sender: Sender<Submission>,
+    /// Asks for a session's contract to be fetched anew
+    refresh_sender: Sender<(u64, Pubkey)>,
+    fee_interval: Duration,
+}
+
+impl<T, P> EventLoop<T, P>
+where
+    T: 'static + Client + Send + Sync,
+    P: 'static + PricingPolicy + Send,
+{
+    fn run(mut self) {
+        let mut events = Events::with_capacity(1024);
+        let mut last_tick = Instant::now();
+        loop {
+            // Wake up at least once per fee interval so time is billed while idle
+            let timeout = self
+                .fee_interval
+                .checked_sub(last_tick.elapsed())
+                .unwrap_or_default();
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                error!("Event loop stopped: {}", e);
+                return;
+            }
+            for event in &events {
+                if event.token() == WAKER {
+                    self.run_commands();
+                } else {
+                    self.handle_socket(event.token(), event.readiness());
+                }
+            }
+            if last_tick.elapsed() >= self.fee_interval {
+                self.tick();
+                last_tick = Instant::now();
+            }
+        }
+    }
+
+    fn run_commands(&mut self) {
+        // Clear readiness before draining, so a command queued meanwhile
+        // readies the waker again
+        let _ = self.set_readiness.set_readiness(Ready::empty());
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                Command::Open(session) => self.open(*session),
+                Command::Update(subscription, account) => self.update(subscription, &account),
+                Command::Resubscribed(subscription) => self.refresh(subscription),
+                Command::Lost(subscription) => self.lose(subscription),
+            }
+        }
+    }
+
+    fn open(&mut self, session: Session<P>) {
+        let session_id = self.next_session_id;
+        self.next_session_id += 1;
+        if let Err(e) = self.poll.register(
+            &session.listener,
+            token(session_id, LISTENER),
+            Ready::readable(),
+            PollOpt::edge(),
+        ) {
+            error!(
+                "Could not listen for {}: {}",
+                session.params.contract_pubkey, e
+            );
+            self.unsubscribe(session.subscription);
+            return;
+        }
+        let subscription = session.subscription;
+        self.subscriptions.insert(subscription, session_id);
+        self.sessions.insert(session_id, session);
+        if let Some((account, _)) = self.early_updates.remove(&subscription) {
+            self.update(subscription, &account);
+        }
+    }
+
+    fn update(&mut self, subscription: u64, account: &Account) {
+        let session_id = match self.subscriptions.get(&subscription) {
+            Some(&session_id) => session_id,
+            None => {
+                self.early_updates
+                    .insert(subscription, (account.clone(), Instant::now()));
+                return;
+            }
+        };
+        let session = self.sessions.get_mut(&session_id).unwrap();
+        if update_contract(
+            &session.params,
+            &self.gatekeeper,
+            &mut session.contract_state,
+            &mut session.accumulator,
+            account,
+        ) {
+            self.close(session_id);
+        }
+    }
+
+    fn refresh(&mut self, subscription: u64) {
+        if let Some(session_id) = self.subscriptions.get(&subscription) {
+            let contract_pubkey = self.sessions[session_id].params.contract_pubkey;
+            let _ = self.refresh_sender.send((subscription, contract_pubkey));
+        }
+    }
+
+    /// End a session whose contract can no longer be watched, since its
+    /// balance and handovers would go unnoticed
+    fn lose(&mut self, subscription: u64) {
+        if let Some(&session_id) = self.subscriptions.get(&subscription) {
+            warn!(
+                "Lost the subscription to {}",
+                self.sessions[&session_id].params.contract_pubkey
+            );
+            self.close(session_id);
+        }
+    }
+
+    fn unsubscribe(&mut self, subscription: u64) {
+        self.subscriptions.remove(&subscription);
+        if let Err(e) = self.pubsub.unsubscribe(subscription) {
+            warn!("Could not unsubscribe from {}: {:?}", subscription, e);
+        }
+    }
+
+    fn latest_slot(&self) -> Option<u64> {
+        Some(self.slot.load(Ordering::Relaxed)).filter(|&slot| slot > 0)
+    }
+
+    fn handle_socket(&mut self, token: Token, readiness: Ready) {
+        let session_id = token.0 >> SOCKET_BITS;
+        let slot = self.latest_slot();
+        let session = match self.sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => return,
+        };
+        let Session {
+            params,
+            contract_state,
+            accumulator,
+            listener,
+            origin,
+            destination,
+            framed,
+            undecoded,
+            receipt: latest_receipt,
+            to_origin,
+            to_destination,
+            ..
+        } = session;
+        let framed = *framed;
+        let poll = &self.poll;
+        let solana_sender = &self.solana_sender;
+
+        let socket = token.0 & ((1 << SOCKET_BITS) - 1);
+        let finished = match origin {
+            None if socket == LISTENER => match listener.accept() {
+                Ok((stream, addr)) => {
+                    info!("Gatekeeper connected to {}", addr);
+                    let registered = poll
+                        .register(
+                            &stream,
+                            token(session_id, ORIGIN),
+                            Ready::readable() | Ready::writable() | UnixReady::hup(),
+                            PollOpt::edge(),
+                        )
+                        .and_then(|_| {
+                            poll.register(
+                                destination,
+                                token(session_id, DESTINATION),
+                                Ready::readable() | Ready::writable(),
+                                PollOpt::edge(),
+                            )
+                        })
+                        .and_then(|_| poll.deregister(listener));
+                    // Bill from when the initiator connects
+                    accumulator.started = Instant::now();
+                    *origin = Some(stream);
+                    registered.is_err()
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+                Err(e) => {
+                    error!("Could not accept initiator: {}", e);
+                    true
+                }
+            },
+            Some(origin) => {
+                let mut charge = |data_amount| {
+                    process_data(
+                        params,
+                        slot,
+                        contract_state,
+                        accumulator,
+                        data_amount,
+                        solana_sender,
+                    )
+                };
+                let mut relay_origin = |data: &[u8], pending: &mut Vec<u8>| -> io::Result<u64> {
+                    if !framed {
+                        pending.extend_from_slice(data);
+                        return Ok(data.len() as u64);
+                    }
+                    undecoded.extend_from_slice(data);
+                    let mut data_amount = 0;
+                    while let Some(frame) = Frame::decode(undecoded)? {
+                        match frame {
+                            Frame::Data(data) => {
+                                data_amount += data.len() as u64;
+                                pending.extend(data);
+                            }
+                            Frame::Receipt(receipt, signature) => keep_receipt(
+                                latest_receipt,
+                                params,
+                                contract_state,
+                                receipt,
+                                signature,
+                            ),
+                        }
+                    }
+                    Ok(data_amount)
+                };
+                let mut relay_destination =
+                    |data: &[u8], pending: &mut Vec<u8>| -> io::Result<u64> {
+                        if framed {
+                            pending.extend(Frame::Data(data.to_vec()).to_bytes()?);
+                        } else {
+                            pending.extend_from_slice(data);
+                        }
+                        Ok(data.len() as u64)
+                    };
+                // A socket turning writable lets its peer be read again, so
+                // both directions move on any event
+                match socket {
+                    ORIGIN | DESTINATION => {
+                        if socket == ORIGIN && UnixReady::from(readiness).is_hup() {
+                            true
+                        } else if forward(
+                            origin,
+                            destination,
+                            to_destination,
+                            &mut relay_origin,
+                            &mut charge,
+                        ) {
+                            true
+                        } else {
+                            forward(
+                                destination,
+                                origin,
+                                to_origin,
+                                &mut relay_destination,
+                                &mut charge,
+                            )
+                        }
+                    }
+                    _ => false,
+                }
+            }
+            None => false,
+        };
+        if finished {
+            self.close(session_id);
+        }
+    }
+
+    /// Charge idle sessions for the time they have been open, and drop those
+    /// whose initiator never connected
+    fn tick(&mut self) {
+        // Updates for sessions that never opened won't be claimed
+        self.early_updates
+            .retain(|_, (_, arrived)| arrived.elapsed() < ACCEPT_TIMEOUT);
+        let slot = self.latest_slot();
+        let solana_sender = &self.solana_sender;
+        let finished: Vec<_> = self
+            .sessions
+            .iter_mut()
+            .filter_map(|(&session_id, session)| {
+                if session.origin.is_none() {
+                    if session.opened.elapsed() > ACCEPT_TIMEOUT {
+                        info!(
+                            "Initiator never connected for {}",
+                            session.params.contract_pubkey
+                        );
+                        return Some(session_id);
+                    }
+                    return None;
+                }
+                if process_data(
+                    &session.params,
+                    slot,
+                    &session.contract_state,
+                    &mut session.accumulator,
+                    0,
+                    solana_sender,
+                ) {
+                    Some(session_id)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for session_id in finished {
+            self.close(session_id);
+        }
+    }
+
+    /// Drop the session's sockets and settle it on chain once its charges
+    /// have gone through. Settling waits for confirmations, so it gets its own
+    /// short-lived thread
+    fn close(&mut self, session_id: usize) {
+        let Session {
+            params,
+            accumulator,
+            origin,
+            receipt,
+            subscription,
+            ..
+        } = match self.sessions.remove(&session_id) {
+            Some(session) => session,
+            None => return,
+        };
+        self.unsubscribe(subscription);
+        // Nothing was forwarded, so there is nothing to settle
+        if origin.is_none() {
+            return;
+        }
+
+        let gatekeeper = self.gatekeeper.clone();
+        let client = self.client.clone();
+        let contract_pubkey = params.contract_pubkey;
+        let finish = Box::new(move |unpaid| {
+            thread::spawn(move || {
+                finish_session(&params, &gatekeeper, &client, receipt, &accumulator, unpaid);
+            });
+        });
+        let submission = Submission::Finish(contract_pubkey, finish);
+        if let Err(SendError(Submission::Finish(_, finish))) = self.solana_sender.send(submission) {
+            error!("Transaction submitter has stopped");
+            finish(0);
+        }
+    }
+}
+
+/// Forward everything that can be read from `from` to `to`. `relay` queues
+/// each chunk read in `pending`, framing or unframing it as needed, and
+/// returns how many bytes of traffic it carried for `charge`. What `to` can't
+/// take yet waits in `pending`, and `from` is left unread once that fills.
+/// Returns true once the session should end
+fn forward<R, F>(
+    from: &mut TcpStream,
+    to: &mut TcpStream,
+    pending: &mut Vec<u8>,
+    relay: &mut R,
+    charge: &mut F,
+) -> bool
+where
+    R: FnMut(&[u8], &mut Vec<u8>) -> io::Result<u64>,
+    F: FnMut(u64) -> bool,
+{
+    let mut data = [0 as u8; 1024];
+    loop {
+        if let Err(e) = flush(to, pending) {
+            warn!("Could not forward {} bytes: {}", pending.len(), e);
+            return true;
+        }
+        if pending.len() >= MAX_PENDING {
+            return false;
+        }
+        match from.read(&mut data) {
+            Ok(0) => return true,
+            Ok(data_amount) => match relay(&data[0..data_amount], pending) {
+                Ok(traffic) => {
+                    if charge(traffic) {
+                        return true;
+                    }
+                }
+                Err(e) => {
+                    warn!("Could not relay {} bytes: {}", data_amount, e);
+                    return true;
+                }
+            },
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return false,
+            Err(e) => {
+                info!("Connection closed: {}", e);
+                return true;
+            }
+        }
+    }
+}
+
+/// Keep a receipt a framed initiator sent for the session's contract
+fn keep_receipt(
+    latest: &mut Option<(UsageReceipt, Signature)>,
+    params: &NewConnParams,
+    contract_state: &BandwidthPrepayState,
+    receipt: UsageReceipt,
+    signature: Signature,
+) {
+    if receipt.contract_id != params.contract_pubkey {
+        warn!(
+            "Receipt for {} sent on a connection for {}",
+            receipt.contract_id, params.contract_pubkey
+        );
+        return;
+    }
+    let (total_bytes, total_lamports) = (receipt.total_bytes, receipt.total_lamports);
+    if record_receipt(latest, contract_state, receipt, signature) {
+        info!(
+            "Received receipt for {} lamports, {} bytes, contract: {:?}",
+            total_lamports, total_bytes, params.contract_pubkey
+        );
+    }
+}
+
+/// Write as much of `pending` to `to` as it will take without blocking
+fn flush(to: &mut TcpStream, pending: &mut Vec<u8>) -> io::Result<()> {
+    while !pending.is_empty() {
+        match to.write(pending) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+            Ok(written) => {
+                pending.drain(..written);
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Charge what the session still owes, including `unpaid` lamports of
+/// charges that didn't go through, preferring the initiator's latest receipt,
+/// and end it so the next one starts afresh
+fn finish_session<T: Client, P: PricingPolicy>(
+    params: &NewConnParams,
+    gatekeeper: &Keypair,
+    client: &Arc<T>,
+    receipt: Option<(UsageReceipt, Signature)>,
+    accumulator: &Accumulator<P>,
+    unpaid: u64,
+) {
+    let open_contract_state = check_contract(&params.contract_pubkey, client, &gatekeeper.pubkey())
+        .ok()
+        .map(|(_, contract_state)| contract_state)
+        .filter(|contract_state| contract_state.status != ContractStatus::Closed);
+    if let Some(contract_state) = open_contract_state {
+        let mut amount_outstanding = accumulator.amount_charged + unpaid;
+        if let Some((receipt, signature)) = receipt {
+            // Settling even a receipt that pays nothing backs the session's
+            // spends against a dispute
+            if receipt.total_lamports > contract_state.receipt_lamports {
+                info!(
+                    "Settling receipt for {} lamports, {} bytes",
+                    receipt.total_lamports, receipt.total_bytes
+                );
+                if settle_contract(
+                    params,
+                    client,
+                    &contract_state,
+                    gatekeeper,
+                    &receipt,
+                    &signature,
+                )
+                .is_ok()
+                {
+                    amount_outstanding = amount_outstanding.saturating_sub(
+                        receipt
+                            .total_lamports
+                            .saturating_sub(contract_state.total_spent),
+                    );
+                }
+            }
+        }
+        let ended = end_session(
+            params,
+            client,
+            &contract_state,
+            gatekeeper,
+            amount_outstanding,
+            accumulator.total_data_amount,
+        );
+        // The contract refuses to end sessions while it keeps too many open to
+        // disputes, so charge what is owed and leave the session running
+        if ended.is_err() && amount_outstanding > 0 {
+            let _ = charge_contract(
+                params,
+                client,
+                &contract_state,
+                gatekeeper,
+                amount_outstanding,
+                accumulator.total_data_amount,
+            );
+        }
+    } else if accumulator.amount_charged + unpaid > 0 {
+        // A contract handed over or closed can't be charged by this gatekeeper
+        error!(
+            "{} lamports owed by contract {} can no longer be charged",
+            accumulator.amount_charged + unpaid,
+            params.contract_pubkey
+        );
+    }
+
+    info!(
+        "Bytes transmitted for contract {}: {}",
+        params.contract_pubkey, accumulator.total_data_amount
+    );
+}
+
+/// Fetch a contract's account as a pubsub notification would carry it
+fn fetch_account<T: Client>(client: &T, contract_pubkey: &Pubkey) -> TransportResult<Account> {
+    let data = client
+        .get_account_data(contract_pubkey)?
+        .unwrap_or_default();
+    let lamports = client.get_balance(contract_pubkey)?;
+    Ok(Account {
+        lamports,
+        data,
+        ..Account::default()
+    })
+}
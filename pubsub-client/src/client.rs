@@ -1,9 +1,12 @@
 use crate::request::PubSubRequest;
 use log::*;
-use serde_json::json;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
-use std::{error, fmt, thread};
+use std::time::Duration;
+use std::{error, fmt, mem, thread};
 use ws::Error as WSError;
 use ws::ErrorKind as WSErrorKind;
 use ws::Sender as WSSender;
@@ -118,6 +121,350 @@ impl Handler for Client {
     }
 }
 
+/// Wait before the first attempt to reconnect a `PubSubClient`
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+/// Longest wait between attempts, which double until reaching it
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(10);
+/// How long `PubSubClient::subscribe` waits for the server to confirm
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A notification for one subscription of a `PubSubClient`
+#[derive(Debug)]
+pub struct Notification {
+    pub subscription: u64,
+    pub result: Value,
+}
+
+/// What a `PubSubClient` sends each subscriber
+#[derive(Debug)]
+pub enum SubscriptionEvent {
+    Notification(Notification),
+    /// The connection dropped and the subscription was made again on a new
+    /// one. Notifications sent in between were missed
+    Resubscribed(u64),
+    /// The server refused to make the subscription again after the
+    /// connection dropped, so it has ended
+    Failed(u64),
+}
+
+/// A subscription as the client knows it, which outlives any one connection
+struct Subscription {
+    method: PubSubRequest,
+    param: String,
+    subscriber: Sender<SubscriptionEvent>,
+    /// Number the server assigned on the current connection, once it has
+    server_subscription: Option<u64>,
+}
+
+/// Where a `PubSubClient` sends what arrives on its websocket
+#[derive(Default)]
+struct Routes {
+    /// Sends on the current connection, if there is one
+    ws_out: Option<WSSender>,
+    next_id: u64,
+    subscriptions: HashMap<u64, Subscription>,
+    /// Subscribe requests awaiting a server subscription number, by request id
+    pending: HashMap<u64, u64>,
+    /// Subscribe requests whose subscription ended before the server
+    /// answered, by request id. What they are given is unsubscribed
+    abandoned: HashMap<u64, PubSubRequest>,
+    /// Subscriptions by their number on the current connection
+    server_subscriptions: HashMap<u64, u64>,
+    /// `subscribe` calls waiting for their first confirmation
+    waiters: HashMap<u64, Sender<Result<(), PubSubError>>>,
+    /// Tells `PubSubClient::connect` the first connection opened
+    first_open: Option<Sender<()>>,
+    opened: bool,
+}
+
+impl Routes {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn send(&self, request_json: &Value) -> Result<(), PubSubError> {
+        let ws_out = self.ws_out.as_ref().ok_or_else(|| {
+            PubSubError::ConnectionDropped(None, "Not connected to pubsub".to_string())
+        })?;
+        let req = serde_json::to_string(request_json).unwrap();
+        info!("sending: '{}'", req);
+        ws_out
+            .send(req)
+            .map_err(|e| PubSubError::ConnectionDropped(None, e.to_string()))
+    }
+
+    /// Ask the server for `subscription` on the current connection
+    fn request_subscription(&mut self, subscription: u64) {
+        let id = self.next_id();
+        let request_json = {
+            let Subscription { method, param, .. } = &self.subscriptions[&subscription];
+            method.build_request_json(id, Some(json!([param])))
+        };
+        if self.send(&request_json).is_ok() {
+            self.pending.insert(id, subscription);
+        }
+    }
+
+    fn on_open(&mut self, ws_out: WSSender) {
+        self.ws_out = Some(ws_out);
+        self.opened = true;
+        if let Some(first_open) = self.first_open.take() {
+            let _ = first_open.send(());
+        }
+        let subscriptions: Vec<_> = self.subscriptions.keys().cloned().collect();
+        for subscription in subscriptions {
+            self.request_subscription(subscription);
+        }
+    }
+
+    fn on_close(&mut self) {
+        self.ws_out = None;
+        self.pending.clear();
+        self.abandoned.clear();
+        self.server_subscriptions.clear();
+        for subscription in self.subscriptions.values_mut() {
+            subscription.server_subscription = None;
+        }
+    }
+
+    fn route(&mut self, json: &Value) {
+        if let Some(id) = json["id"].as_u64() {
+            if let Some(subscription) = self.pending.remove(&id) {
+                self.confirm(subscription, json["result"].as_u64());
+            } else if let Some(method) = self.abandoned.remove(&id) {
+                if let Some(server_subscription) = json["result"].as_u64() {
+                    let id = self.next_id();
+                    let _ = self.send(&method.build_unsubscribe_json(id, server_subscription));
+                }
+            }
+        } else if let Some(server_subscription) = json["params"]["subscription"].as_u64() {
+            if let Some(&subscription) = self.server_subscriptions.get(&server_subscription) {
+                let notification = Notification {
+                    subscription,
+                    result: json["params"]["result"].clone(),
+                };
+                self.deliver(subscription, SubscriptionEvent::Notification(notification));
+            }
+        }
+    }
+
+    /// Record the server's answer to a subscribe request
+    fn confirm(&mut self, subscription: u64, server_subscription: Option<u64>) {
+        let waiter = self.waiters.remove(&subscription);
+        let server_subscription = match server_subscription {
+            Some(server_subscription) => server_subscription,
+            None => {
+                error!("PubSub subscription {} failed", subscription);
+                match waiter {
+                    Some(waiter) => {
+                        let _ = waiter.send(Err(PubSubError::SubscriptionFailed));
+                    }
+                    None => self.deliver(subscription, SubscriptionEvent::Failed(subscription)),
+                }
+                self.subscriptions.remove(&subscription);
+                return;
+            }
+        };
+        match self.subscriptions.get_mut(&subscription) {
+            Some(entry) => entry.server_subscription = Some(server_subscription),
+            None => return,
+        }
+        // Routed before the subscriber hears back, so no notification can
+        // slip past
+        self.server_subscriptions
+            .insert(server_subscription, subscription);
+        match waiter {
+            Some(waiter) => {
+                let _ = waiter.send(Ok(()));
+            }
+            None => self.deliver(subscription, SubscriptionEvent::Resubscribed(subscription)),
+        }
+    }
+
+    /// Pass `event` on, dropping the subscription if nobody listens anymore
+    fn deliver(&mut self, subscription: u64, event: SubscriptionEvent) {
+        let delivered = self
+            .subscriptions
+            .get(&subscription)
+            .map(|entry| entry.subscriber.send(event).is_ok());
+        if delivered == Some(false) {
+            self.subscriptions.remove(&subscription);
+        }
+    }
+}
+
+/// One websocket carrying any number of concurrent subscriptions, each
+/// routed to its own channel. A dropped connection is reopened with backoff
+/// and every subscription made again
+pub struct PubSubClient {
+    routes: Arc<Mutex<Routes>>,
+    pub handle: JoinHandle<()>,
+}
+
+impl PubSubClient {
+    pub fn connect(ws_addr: String) -> Result<Self, Box<dyn error::Error>> {
+        let (first_open, first_open_receiver) = channel();
+        let routes = Arc::new(Mutex::new(Routes {
+            first_open: Some(first_open),
+            ..Routes::default()
+        }));
+        let connection_routes = routes.clone();
+        let handle = thread::spawn(move || {
+            let mut backoff = RECONNECT_BACKOFF_MIN;
+            loop {
+                info!("Connecting to {}", ws_addr);
+                let router_routes = connection_routes.clone();
+                if let Err(e) = connect(ws_addr.clone(), move |ws_out| Router {
+                    ws_out,
+                    routes: router_routes.clone(),
+                }) {
+                    warn!("PubSub connection failed: {:?}", e);
+                }
+
+                let mut routes = connection_routes.lock().unwrap();
+                // Errors can end a connection without `on_close`
+                routes.on_close();
+                if routes.first_open.take().is_some() {
+                    // Never connected, so `connect` reports the failure
+                    return;
+                }
+                if mem::replace(&mut routes.opened, false) {
+                    backoff = RECONNECT_BACKOFF_MIN;
+                } else {
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                }
+                drop(routes);
+                info!("Reconnecting to PubSub in {:?}", backoff);
+                thread::sleep(backoff);
+            }
+        });
+
+        first_open_receiver
+            .recv()
+            .map_err(|_| PubSubError::ConnectionFailed)?;
+        info!("Connected to PubSub websocket");
+        Ok(PubSubClient { routes, handle })
+    }
+
+    /// Subscribe to `param` with `method`, sending its notifications to
+    /// `subscriber`. Blocks until the server confirms, for up to
+    /// `SUBSCRIBE_TIMEOUT`, then returns an id for the subscription that
+    /// stays the same across reconnects
+    pub fn subscribe<T>(
+        &self,
+        method: PubSubRequest,
+        param: &T,
+        subscriber: Sender<SubscriptionEvent>,
+    ) -> Result<u64, Box<dyn error::Error>>
+    where
+        T: fmt::Display,
+    {
+        let (waiter, confirmation) = channel();
+        let subscription = {
+            let mut routes = self.routes.lock().unwrap();
+            let subscription = routes.next_id();
+            routes.subscriptions.insert(
+                subscription,
+                Subscription {
+                    method,
+                    param: format!("{}", param),
+                    subscriber,
+                    server_subscription: None,
+                },
+            );
+            routes.waiters.insert(subscription, waiter);
+            // Made as soon as the connection reopens if it is down
+            if routes.ws_out.is_some() {
+                routes.request_subscription(subscription);
+            }
+            subscription
+        };
+
+        match confirmation.recv_timeout(SUBSCRIBE_TIMEOUT) {
+            Ok(confirmed) => confirmed?,
+            Err(RecvTimeoutError::Timeout) => {
+                self.unsubscribe(subscription)?;
+                return Err(PubSubError::ConnectionDropped(
+                    None,
+                    "Timed out subscribing to pubsub".to_string(),
+                ))?;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(PubSubError::ConnectionDropped(
+                    None,
+                    "Connection dropped while subscribing to pubsub".to_string(),
+                ))?;
+            }
+        }
+        info!("Subscribed to PubSub with subscription {}", subscription);
+        Ok(subscription)
+    }
+
+    /// End a subscription. Its notifications stop being routed straight
+    /// away, without waiting for the server to confirm. If the server hasn't
+    /// answered the subscribe request yet, the subscription it makes is ended
+    /// as soon as it does
+    pub fn unsubscribe(&self, subscription: u64) -> Result<(), PubSubError> {
+        let mut routes = self.routes.lock().unwrap();
+        let entry = match routes.subscriptions.remove(&subscription) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        routes.waiters.remove(&subscription);
+        match entry.server_subscription {
+            Some(server_subscription) => {
+                routes.server_subscriptions.remove(&server_subscription);
+                let id = routes.next_id();
+                routes.send(&entry.method.build_unsubscribe_json(id, server_subscription))
+            }
+            None => {
+                let ids: Vec<_> = routes
+                    .pending
+                    .iter()
+                    .filter(|(_, &pending)| pending == subscription)
+                    .map(|(&id, _)| id)
+                    .collect();
+                for id in ids {
+                    routes.pending.remove(&id);
+                    routes.abandoned.insert(id, entry.method);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Router {
+    ws_out: WSSender,
+    routes: Arc<Mutex<Routes>>,
+}
+
+impl Handler for Router {
+    fn on_open(&mut self, _: Handshake) -> Result<(), WSError> {
+        info!("PubSub connection opened");
+        self.routes.lock().unwrap().on_open(self.ws_out.clone());
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<(), WSError> {
+        match serde_json::from_str(&msg.into_text()?) {
+            Ok(json) => self.routes.lock().unwrap().route(&json),
+            Err(e) => warn!("Unparseable PubSub message: {:?}", e),
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        warn!("PubSub connection closed: {:?} {}", code, reason);
+        self.routes.lock().unwrap().on_close();
+    }
+
+    fn on_error(&mut self, e: ws::Error) {
+        error!("WS Error: {:?}", e)
+    }
+}
+
 pub enum Event {
     Connect(WSSender),
     Disconnect(CloseCode, String),
@@ -149,3 +496,227 @@ impl fmt::Display for PubSubError {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use ws::Builder;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Answers each subscribe with a fresh subscription number followed by
+    /// one notification carrying that number, and reports every request.
+    /// With a `gate`, each answer waits for a go-ahead sent on it. Subscribes
+    /// beyond the first `max_subscriptions` are refused
+    struct Server {
+        out: WSSender,
+        next_subscription: Arc<AtomicU64>,
+        max_subscriptions: u64,
+        requests: Sender<Value>,
+        gate: Option<Arc<Mutex<Receiver<()>>>>,
+    }
+
+    impl Handler for Server {
+        fn on_message(&mut self, msg: Message) -> Result<(), WSError> {
+            let request: Value = serde_json::from_str(&msg.into_text()?).unwrap();
+            let _ = self.requests.send(request.clone());
+            if request["method"] == "accountSubscribe" {
+                if let Some(gate) = &self.gate {
+                    gate.lock().unwrap().recv().unwrap();
+                }
+                let subscription = self.next_subscription.fetch_add(1, Ordering::SeqCst);
+                if subscription > self.max_subscriptions {
+                    let error = json!({"code": -32602, "message": "Invalid Request"});
+                    return self.out.send(
+                        json!({"jsonrpc": "2.0", "error": error, "id": request["id"]}).to_string(),
+                    );
+                }
+                self.out.send(
+                    json!({"jsonrpc": "2.0", "result": subscription, "id": request["id"]})
+                        .to_string(),
+                )?;
+                self.out.send(
+                    json!({
+                        "jsonrpc": "2.0",
+                        "method": "accountNotification",
+                        "params": {"result": subscription, "subscription": subscription},
+                    })
+                    .to_string(),
+                )?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Start a `Server`, returning its address, a handle to close its
+    /// connections and the requests it receives
+    fn start_server(
+        gate: Option<Receiver<()>>,
+        max_subscriptions: u64,
+    ) -> (String, WSSender, Receiver<Value>) {
+        let next_subscription = Arc::new(AtomicU64::new(1));
+        let gate = gate.map(|gate| Arc::new(Mutex::new(gate)));
+        let (requests_sender, requests) = channel();
+        let server = Builder::new()
+            .build(move |out| Server {
+                out,
+                next_subscription: next_subscription.clone(),
+                max_subscriptions,
+                requests: requests_sender.clone(),
+                gate: gate.clone(),
+            })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let ws_addr = format!("ws://{}", server.local_addr().unwrap());
+        let broadcaster = server.broadcaster();
+        thread::spawn(move || server.run());
+        (ws_addr, broadcaster, requests)
+    }
+
+    fn expect_notification(events: &Receiver<SubscriptionEvent>, subscription: u64) -> Value {
+        match events.recv_timeout(TIMEOUT).unwrap() {
+            SubscriptionEvent::Notification(notification) => {
+                assert_eq!(notification.subscription, subscription);
+                notification.result
+            }
+            event => panic!("expected a notification, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_pubsub_client_resubscribes_after_disconnect() {
+        let (ws_addr, broadcaster, requests) = start_server(None, u64::max_value());
+
+        let client = PubSubClient::connect(ws_addr).unwrap();
+        let (subscriber, events) = channel();
+        let subscription = client
+            .subscribe(PubSubRequest::Account, &"contract", subscriber)
+            .unwrap();
+        let request = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(request["method"], "accountSubscribe");
+        assert_eq!(request["params"], json!(["contract"]));
+        assert_eq!(expect_notification(&events, subscription), json!(1));
+
+        // Kill the connection; the client reconnects and subscribes again,
+        // getting a new number from the server under the same id
+        broadcaster.close(CloseCode::Away).unwrap();
+        match events.recv_timeout(TIMEOUT).unwrap() {
+            SubscriptionEvent::Resubscribed(resubscribed) => {
+                assert_eq!(resubscribed, subscription)
+            }
+            event => panic!("expected a resubscription, got {:?}", event),
+        }
+        let request = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(request["method"], "accountSubscribe");
+        assert_eq!(request["params"], json!(["contract"]));
+        assert_eq!(expect_notification(&events, subscription), json!(2));
+
+        // Unsubscribing uses the number from the current connection
+        client.unsubscribe(subscription).unwrap();
+        let request = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(request["method"], "accountUnsubscribe");
+        assert_eq!(request["params"], json!([2]));
+    }
+    #[test]
+    fn test_pubsub_client_concurrent_subscriptions() {
+        let (ws_addr, _broadcaster, requests) = start_server(None, u64::max_value());
+        let client = Arc::new(PubSubClient::connect(ws_addr).unwrap());
+
+        let subscribers: Vec<_> = (0..3)
+            .map(|i| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    let (subscriber, events) = channel();
+                    let subscription = client
+                        .subscribe(PubSubRequest::Account, &i, subscriber)
+                        .unwrap();
+                    (subscription, events)
+                })
+            })
+            .collect();
+        let subscribers: Vec<_> = subscribers
+            .into_iter()
+            .map(|subscriber| subscriber.join().unwrap())
+            .collect();
+
+        let mut ids: Vec<_> = (0..3)
+            .map(|_| {
+                requests.recv_timeout(TIMEOUT).unwrap()["id"]
+                    .as_u64()
+                    .unwrap()
+            })
+            .collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 3);
+
+        // Each subscriber only hears about the server subscription made for it
+        let server_subscriptions: Vec<_> = subscribers
+            .iter()
+            .map(|(subscription, events)| {
+                let result = expect_notification(events, *subscription);
+                assert!(events.try_recv().is_err());
+                result.as_u64().unwrap()
+            })
+            .collect();
+        let mut sorted = server_subscriptions.clone();
+        sorted.sort();
+        assert_eq!(sorted, vec![1, 2, 3]);
+
+        // Ending one ends its own server subscription
+        client.unsubscribe(subscribers[1].0).unwrap();
+        let request = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(request["method"], "accountUnsubscribe");
+        assert_eq!(request["params"], json!([server_subscriptions[1]]));
+    }
+
+    #[test]
+    fn test_pubsub_client_unsubscribe_while_resubscribing() {
+        let (go_ahead, gate) = channel();
+        let (ws_addr, broadcaster, requests) = start_server(Some(gate), u64::max_value());
+
+        let client = PubSubClient::connect(ws_addr).unwrap();
+        let (subscriber, events) = channel();
+        go_ahead.send(()).unwrap();
+        let subscription = client
+            .subscribe(PubSubRequest::Account, &"contract", subscriber)
+            .unwrap();
+        let request = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(request["method"], "accountSubscribe");
+        assert_eq!(expect_notification(&events, subscription), json!(1));
+
+        // Unsubscribe while the server is yet to answer the resubscription
+        broadcaster.close(CloseCode::Away).unwrap();
+        let request = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(request["method"], "accountSubscribe");
+        client.unsubscribe(subscription).unwrap();
+        go_ahead.send(()).unwrap();
+
+        // The subscription the server makes anyway is ended
+        let request = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(request["method"], "accountUnsubscribe");
+        assert_eq!(request["params"], json!([2]));
+        assert!(events.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+    #[test]
+    fn test_pubsub_client_reports_failed_resubscription() {
+        let (ws_addr, broadcaster, _requests) = start_server(None, 1);
+
+        let client = PubSubClient::connect(ws_addr).unwrap();
+        let (subscriber, events) = channel();
+        let subscription = client
+            .subscribe(PubSubRequest::Account, &"contract", subscriber)
+            .unwrap();
+        assert_eq!(expect_notification(&events, subscription), json!(1));
+
+        // The server refuses to subscribe again once the connection drops
+        broadcaster.close(CloseCode::Away).unwrap();
+        match events.recv_timeout(TIMEOUT).unwrap() {
+            SubscriptionEvent::Failed(failed) => assert_eq!(failed, subscription),
+            event => panic!("expected a failure, got {:?}", event),
+        }
+        assert!(events.recv_timeout(TIMEOUT).is_err());
+    }
+}
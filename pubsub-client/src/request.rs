@@ -1,5 +1,6 @@
 use serde_json::{json, Value};
 
+#[derive(Clone, Copy, Debug)]
 pub enum PubSubRequest {
     Account,
     Program,
@@ -24,4 +25,20 @@ impl PubSubRequest {
         }
         request
     }
+
+    /// Request ending subscription number `subscription`, made by a request
+    /// built with the same variant
+    pub fn build_unsubscribe_json(&self, id: u64, subscription: u64) -> Value {
+        let method = match self {
+            PubSubRequest::Account => "accountUnsubscribe",
+            PubSubRequest::Program => "programUnsubscribe",
+            PubSubRequest::Signature => "signatureUnsubscribe",
+        };
+        json!({
+           "jsonrpc": "2.0",
+           "id": id,
+           "method": method,
+           "params": [subscription],
+        })
+    }
 }
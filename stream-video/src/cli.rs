@@ -3,7 +3,7 @@ use client::bandwidth_client::BandwidthClient;
 use provider_drone::DEFAULT_DRONE_PORT;
 use solana_client::rpc_client::RpcClient;
 use solana_sdk::pubkey::read_pubkey;
-use solana_sdk::signature::{read_keypair, KeypairUtil};
+use solana_sdk::signature::read_keypair;
 use std::net::SocketAddr;
 use stream_video::stream_video::*;
 
@@ -123,7 +123,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let drone_addr = SocketAddr::new(host, DEFAULT_DRONE_PORT);
         client.request_airdrop(&drone_addr, lamports + 1)?;
         let prepay_account =
-            client.initialize_contract(lamports, &gatekeeper_pubkey, &provider_pubkey);
+            client.initialize_contract(lamports, &gatekeeper_pubkey, &provider_pubkey)?;
 
         // Start connection
         let gatekeeper_addr = matches.value_of("gatekeeper_addr").unwrap();
@@ -131,7 +131,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let destination: SocketAddr = destination.parse()?;
 
         let connection_addr =
-            client.request_connection(gatekeeper_addr, destination, &prepay_account.pubkey())?;
+            client.request_connection(gatekeeper_addr, destination, &prepay_account)?;
 
         let mut video_connecter = VideoManager::new_video_connecter(&connection_addr, None)?;
 
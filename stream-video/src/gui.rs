@@ -336,15 +336,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             'stopped: loop {
                 match connecter_recv.recv() {
                     Ok(ConnecterCommand::StartConnection(addr, lamports)) => {
-                        let prepay_account = client.initialize_contract(
-                            lamports,
-                            &gatekeeper_pubkey,
-                            &provider_pubkey,
-                        );
+                        let prepay_account = client
+                            .initialize_contract(lamports, &gatekeeper_pubkey, &provider_pubkey)
+                            .unwrap();
 
                         info!("Requesting connection to {:?}", addr);
                         let connection_addr = client
-                            .request_connection(&gatekeeper_addr, addr, &prepay_account.pubkey())
+                            .request_connection(&gatekeeper_addr, addr, &prepay_account)
                             .unwrap();
 
                         info!("Connecting to {:?}", connection_addr);